@@ -1,14 +1,15 @@
 use hyper::{
-    header::{HeaderMap, HeaderValue, HOST, LOCATION, REFERER},
+    client::HttpConnector,
+    header::{HeaderMap, HeaderValue, AUTHORIZATION, HOST, LOCATION, SET_COOKIE},
     server::accept::from_stream,
     service::{make_service_fn, service_fn},
-    {Body, Client, Method, Request, Response, Server, Uri},
+    {Body, Client, Method, Request, Response, Server, StatusCode, Uri},
 };
 use hyper_tls::HttpsConnector;
 use native_tls::TlsConnector;
 use tokio_tls::TlsConnector as TokioTlsConnector;
 
-use tokio::net::TcpListener;
+use tokio::net::{TcpListener, TcpStream};
 use tokio_stream::wrappers::TcpListenerStream;
 
 use std::{fs::File, io::Read, net::SocketAddr, str::FromStr, sync::Arc};
@@ -16,43 +17,163 @@ use std::{fs::File, io::Read, net::SocketAddr, str::FromStr, sync::Arc};
 use toml::Value;
 use url::Url;
 
+mod access;
+mod admin;
+mod audit;
 mod basic;
 mod body;
+mod buffer;
 mod config;
 mod cookie;
 mod cookie_replacement;
 mod errors;
 mod forms;
+mod injection;
+mod jar;
+mod json_login;
+#[cfg(test)]
+mod mock;
+mod ntlm;
+mod oauth2;
+mod policy;
+mod portal;
+mod scope;
+mod secrets;
 mod secure_support;
 mod sessions;
 mod status;
+mod totp;
 mod utils;
-use crate::reverse_proxy::{errors::ProxyError, sessions::process_session, status::bad_gateway};
+use crate::reverse_proxy::{
+    audit::AuditEvent,
+    buffer::ReplayableRequest,
+    errors::ProxyError,
+    forms::{FormRoute, Requester},
+    injection::{InjectedCredential, RouteCredential},
+    jar::JarSession,
+    policy::Decision,
+    portal::{Gate, ProxyUser},
+    sessions::process_session,
+    status::bad_gateway,
+};
 
 async fn handle_request(
-    req: Request<Body>,
+    replay: &ReplayableRequest,
     req_uri: &str,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
     config: Value,
+    user: Option<&ProxyUser>,
 ) -> Result<Response<Body>, ProxyError> {
+    let mut req = replay.to_request().await?;
     let cloned_headers = req.headers().clone();
     let req_method = req.method().clone();
     println!("Request Path: {}", req_uri);
 
-    let req_for_auth =
-        match create_new_req(req_uri, req_method, cloned_headers.clone(), Body::empty()).await {
-            Some(new_req) => new_req,
-            None => return status::unauthorized_response(),
-        };
+    // A second copy, sent when answering a challenge
+    let mut req_for_auth = match create_new_req(
+        req_uri,
+        req_method,
+        cloned_headers.clone(),
+        replay.to_request().await?.into_body(),
+    )
+    .await
+    {
+        Some(new_req) => new_req,
+        None => return status::unauthorized_response(),
+    };
     sessions::detect_cookies(&cloned_headers);
 
+    let route_credential = injection::find_credential(req_uri, config.clone());
+    let mut injected_credential = None;
+    if let Some(route_credential) = &route_credential {
+        let credential = match route_credential.resolve(&client).await {
+            Ok(credential) => credential,
+            Err(_) => return bad_gateway("credentials"),
+        };
+        injection::inject(&mut req, &credential);
+        injection::inject(&mut req_for_auth, &credential);
+        injected_credential = Some(credential);
+    }
+    let json_session = match json_login::find_login(req_uri, config.clone()) {
+        Some(login) => {
+            let token = match json_login::session_token(&login, user, &client).await {
+                Ok(token) => token,
+                Err(_) => return bad_gateway("credentials"),
+            };
+            json_login::inject(&mut req, &login, &token);
+            json_login::inject(&mut req_for_auth, &login, &token);
+            Some((login, token))
+        }
+        None => None,
+    };
+
+    let ntlm_credentials = ntlm::find_credentials(req_uri, config.clone());
+    if let Some(credentials) = ntlm_credentials
+        .as_ref()
+        .filter(|credentials| ntlm::is_known_upstream(req_uri, credentials, user))
+    {
+        println!("Known NTLM upstream, sending over an authenticated connection");
+        return ntlm::send(req, req_for_auth, credentials, user).await;
+    }
+
+    let cached_realm = basic::cached_realm(req_uri);
+    if let Some(realm) = &cached_realm {
+        println!("Known protection space, sending credentials preemptively");
+        req = basic::auth_middleware(req, realm, config.clone(), user).await?;
+    }
+
     let response = basic::authenticate(req, client.clone()).await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        return Ok(response);
+    }
+    if let (Some(RouteCredential::OAuth2(oauth)), Some(InjectedCredential::Bearer(rejected))) =
+        (&route_credential, &injected_credential)
+    {
+        println!("Access token rejected, retrying with a fresh one");
+        let token = match oauth2::refresh_token(oauth, rejected, &client).await {
+            Ok(token) => token,
+            Err(_) => return bad_gateway("credentials"),
+        };
+        injection::inject(&mut req_for_auth, &InjectedCredential::Bearer(token));
+        return Ok(client.request(req_for_auth).await?);
+    }
+    if let Some((login, rejected)) = &json_session {
+        println!("Session token rejected, logging in again");
+        let token = match json_login::refresh_token(login, user, rejected, &client).await {
+            Ok(token) => token,
+            Err(_) => return bad_gateway("credentials"),
+        };
+        json_login::inject(&mut req_for_auth, login, &token);
+        return Ok(client.request(req_for_auth).await?);
+    }
+    if let Some(realm) = cached_realm {
+        println!("Preemptive credentials rejected, protection space dropped");
+        basic::forget_protection_space(req_uri, &realm);
+    }
     let resp_headers = response.headers();
 
-    let modified_resp = match basic::intercept_auth(resp_headers, req_for_auth, config).await {
-        Ok(res) => client.request(res).await?,
-        Err(_) => return Ok(response),
-    };
+    if ntlm::is_requested(resp_headers) {
+        println!("Identified NTLM Authentication needed!");
+        return match ntlm_credentials {
+            Some(credentials) => ntlm::handshake(req_for_auth, &credentials, user).await,
+            None => Ok(response),
+        };
+    }
+
+    let sent_auth = req_for_auth.headers().get(AUTHORIZATION).cloned();
+    let authenticated_req =
+        match basic::intercept_auth(resp_headers, req_for_auth, config, user).await {
+            Ok(res) => res,
+            Err(_) => return Ok(response),
+        };
+    let injected = authenticated_req.headers().get(AUTHORIZATION) != sent_auth.as_ref();
+    let modified_resp = client.request(authenticated_req).await?;
+
+    if injected && modified_resp.status() != StatusCode::UNAUTHORIZED {
+        if let Some(realm) = basic::challenge_realm(resp_headers) {
+            basic::remember_protection_space(req_uri, &realm);
+        }
+    }
     Ok(modified_resp)
 }
 
@@ -79,22 +200,104 @@ pub async fn handle_redirection(
     Ok(response)
 }
 
+// Logs in again through `relogin` when `response` shows the upstream
+// session expired, and replays the request in the new session
+async fn replay_after_relogin(
+    response: Response<Body>,
+    replay: &mut ReplayableRequest,
+    relogin: &(String, FormRoute),
+    target_url: &str,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    config: Value,
+    requester: Requester<'_>,
+) -> Result<Response<Body>, ProxyError> {
+    let (key, route) = relogin;
+    let (expired, response) = forms::is_expired(route, target_url, response).await?;
+    if !expired {
+        return Ok(response);
+    }
+    let sent_at = replay.received_at();
+    let session =
+        match forms::relogin(key, route, client.clone(), &config, requester, sent_at).await {
+            Ok(session) => session,
+            Err(_) => return Ok(response),
+        };
+
+    forms::attach_session(replay.headers_mut(), &session);
+    println!("Replaying {} in the new upstream session", target_url);
+    let mut replayed = handle_request(replay, target_url, client, config, requester.user).await?;
+    // The browser keeps the new session from now on
+    for set_cookie in session.get_all(SET_COOKIE) {
+        replayed
+            .headers_mut()
+            .append(SET_COOKIE, set_cookie.clone());
+    }
+    Ok(replayed)
+}
+
 async fn handle_response(
     req: Request<Body>,
     target_url: &str,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
     config: Value,
+    user: Option<&ProxyUser>,
+    jar: Option<&JarSession>,
 ) -> Result<Response<Body>, ProxyError> {
+    let requester = Requester { user, jar };
+    // Kept aside so the request can be replayed, when answering a challenge
+    // or once logged in again
+    let limits = config::setup_buffering(config.clone());
+    let mut replay = match ReplayableRequest::keep(req, &limits).await {
+        Ok(replay) => replay,
+        Err(ProxyError::BodyTooLarge(max)) => return status::payload_too_large(max),
+        Err(err) => return Err(err),
+    };
     let mut target_response =
-        handle_request(req, target_url, client.clone(), config.clone()).await?;
+        handle_request(&replay, target_url, client.clone(), config.clone(), user).await?;
+    if let Some(relogin) = forms::find_relogin(target_url, config.clone()) {
+        target_response = replay_after_relogin(
+            target_response,
+            &mut replay,
+            &relogin,
+            target_url,
+            client.clone(),
+            config.clone(),
+            requester,
+        )
+        .await?;
+    }
+    if let Some(jar) = jar {
+        jar::store(jar, target_url, target_response.headers());
+    }
 
     if target_response.status().is_redirection() {
-        target_response = handle_redirection(target_response, client.clone(), "").await?;
+        let location = utils::absolute_location(target_url, target_response.headers());
+        let location = location
+            .as_ref()
+            .and_then(|location| location.to_str().ok());
+        let cookies = match (jar, location) {
+            (Some(jar), Some(location)) => jar::cookie_header(jar, location).unwrap_or_default(),
+            _ => String::new(),
+        };
+        target_response = handle_redirection(target_response, client.clone(), &cookies).await?;
+        if let (Some(jar), Some(location)) = (jar, location) {
+            jar::store(jar, location, target_response.headers());
+        }
+    }
+    if let Some(credential) = injection::find_credential(target_url, config.clone()) {
+        injection::strip_response(target_response.headers_mut(), &credential);
     }
     let resp_header = target_response.headers();
     let session_cookie = process_session(resp_header);
-    target_response =
-        body::read_body(target_response, client, target_url, session_cookie, config).await?;
+    target_response = body::read_body(
+        target_response,
+        client,
+        target_url,
+        session_cookie,
+        config,
+        requester,
+    )
+    .await?;
 
     sessions::handle_cookies(target_response.headers());
 
@@ -146,48 +349,106 @@ async fn reverse_proxy(
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
     path: String,
     config: Value,
+    user: Option<&ProxyUser>,
 ) -> Result<Response<Body>, ProxyError> {
     let headers = req.headers().clone();
     let method = req.method().clone();
     let (parts, body) = req.into_parts();
+    let secure = utils::is_https(&parts.uri, &headers);
 
     let target_url = match utils::determine_target(&path, &parts.uri, config.clone()) {
         Ok(url) => url,
         Err(_) => return status::not_found(),
     };
+    if let Decision::Deny(reason) =
+        policy::authorize(user, method.as_str(), &target_url, config.clone())
+    {
+        let event = AuditEvent::new("access_denied", user, method.as_str(), &target_url, &reason);
+        audit::record(event, &config);
+        return status::forbidden();
+    }
+    let deadline = match access::check(user, &target_url, config.clone()) {
+        Ok(deadline) => deadline,
+        Err(reason) => {
+            let event =
+                AuditEvent::new("access_denied", user, method.as_str(), &target_url, &reason);
+            audit::record(event, &config);
+            return status::forbidden();
+        }
+    };
 
-    let target_request = match create_new_req(&target_url, method, headers, body).await {
+    let mut target_request = match create_new_req(&target_url, method, headers, body).await {
         Some(new_req) => new_req,
         None => return status::bad_request(),
     };
+    let cookie_settings = config::setup_cookies(config.clone());
+    let jar = match cookie_settings.jar || cookie_settings.replace {
+        true => Some(jar::open(
+            target_request.headers_mut(),
+            &cookie_settings,
+            user,
+        )),
+        false => None,
+    };
+    if let Some(jar) = &jar {
+        jar::reveal_cookies(jar, target_request.headers_mut());
+        if cookie_settings.jar {
+            jar::attach(jar, &target_url, target_request.headers_mut());
+        }
+    }
 
-    let target_response = handle_response(target_request, &target_url, client, config).await?;
-    Ok(target_response)
+    let mut target_response = handle_response(
+        target_request,
+        &target_url,
+        client,
+        config.clone(),
+        user,
+        jar.as_ref().filter(|_| cookie_settings.jar),
+    )
+    .await?;
+    if let Some(jar) = jar.as_ref().filter(|_| cookie_settings.replace) {
+        jar::hide_cookies(jar, parts.uri.path(), target_response.headers_mut());
+    }
+    if let Some(jar) = jar.filter(|jar| jar.is_new && jar::is_open(jar)) {
+        if let Ok(jar_cookie) = HeaderValue::from_str(&jar::jar_cookie(&jar.id, secure)) {
+            target_response.headers_mut().append(SET_COOKIE, jar_cookie);
+        }
+    }
+    Ok(match deadline {
+        Some(deadline) => {
+            let user = user.cloned();
+            access::cut_off(target_response, deadline, move || {
+                access::check(user.as_ref(), &target_url, config.clone())
+            })
+        }
+        None => target_response,
+    })
 }
 
 async fn handle(
     req: Request<Body>,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
-    mut path: String,
+    path: String,
 ) -> Result<Response<Body>, ProxyError> {
     let config = match config::define_conf("./config.toml") {
         Ok(parsed_toml) => parsed_toml,
         Err(_) => return bad_gateway("config.toml"),
     };
-    let req_headers = req.headers();
-
-    if let Some(ref_header) = req_headers.get(REFERER) {
-        path = match ref_header.to_str() {
-            Ok(res) => res.to_string(),
-            Err(_) => path,
-        }
+    let (req, user) = match portal::gate(req, &client, config.clone()).await? {
+        Gate::Forward(req, user) => (req, user),
+        Gate::Respond(response) => return Ok(response),
+    };
+    if req.uri().path().starts_with(admin::ADMIN_PREFIX) {
+        return admin::handle(req, user.as_ref(), config).await;
     }
+    let req_headers = req.headers();
+    let path = utils::route_ref(&path, req_headers);
     println!("REQ VERSION\t {:?}", req.version());
     println!("REQ EXTENSIONS\t {:?}", req.extensions());
     println!("\nProxy Request Headers:");
     utils::print_formatted_headers(req_headers);
 
-    let target_response = reverse_proxy(req, client, path, config).await?;
+    let target_response = reverse_proxy(req, client, path, config, user.as_ref()).await?;
     Ok(target_response)
 }
 
@@ -218,8 +479,9 @@ pub async fn create_serv() {
 
     let tls_connector = TlsConnector::builder().build().unwrap();
     let tokio_tls_connector = TokioTlsConnector::from(tls_connector);
-    let hyper_tls_connector = hyper_tls::TlsConnector::from(tokio_tls_connector);
-    let https_connector = HttpsConnector::new_with_connector(hyper_tls_connector);
+    let mut http_connector = HttpConnector::new();
+    http_connector.enforce_http(false);
+    let https_connector = HttpsConnector::from((http_connector, tokio_tls_connector));
 
     let listener = TcpListener::bind(&addr).await.unwrap();
     let incoming = TcpListenerStream::new(listener);
@@ -228,15 +490,21 @@ pub async fn create_serv() {
     let client_for_service = client.clone();
     // -----------------------
 
+    tokio::spawn(jar::sweep());
+
     // let https = HttpsConnector::new();
     // let client = Arc::new(Client::builder().build(https));
     // let client_for_service = client.clone();
 
-    let make_proxy_svc = make_service_fn(move |_conn| {
+    let make_proxy_svc = make_service_fn(move |conn: &TcpStream| {
         let client = client_for_service.clone();
-        async {
-            Ok::<_, ProxyError>(service_fn(move |req| {
+        let remote = conn.peer_addr().ok();
+        async move {
+            Ok::<_, ProxyError>(service_fn(move |mut req: Request<Body>| {
                 let client = client.clone();
+                if let Some(remote) = remote {
+                    req.extensions_mut().insert(remote);
+                }
                 let path = req.uri().path().to_owned();
 
                 println!("Path: {}", path);
@@ -0,0 +1,509 @@
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use hyper::{body::HttpBody, Body, Response};
+
+use once_cell::sync::Lazy;
+use std::sync::{Mutex, PoisonError};
+use tokio::sync::Notify;
+use toml::Value;
+use url::Url;
+
+use super::{config::setup_restricted, portal::ProxyUser, scope::CredentialScope};
+
+const DAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+const DAY_MINUTES: u32 = 24 * 60;
+
+/// Weekdays and a time span, such as `Mon-Fri 08:00-18:00`. A span ending
+/// before it starts runs over midnight into the next day.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TimeWindow {
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+/// Route from a [[restricted]] entry, only usable inside its time windows
+/// and, with `approval`, under a grant issued through the admin API.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RestrictedRoute {
+    pub key: String,
+    pub route: CredentialScope,
+    pub timezone: Tz,
+    pub windows: Vec<TimeWindow>,
+    pub approval: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Grant {
+    pub id: String,
+    pub user: String,
+    pub route: String,
+    pub issued_by: String,
+    pub reason: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+static GRANTS: Lazy<Mutex<Vec<Grant>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+// Wakes the responses being streamed under a grant when one is revoked
+static REVOKED: Lazy<Notify> = Lazy::new(Notify::new);
+
+fn parse_day(day: &str) -> Option<usize> {
+    let day = day.to_lowercase();
+
+    DAYS.iter().position(|known| day.starts_with(known))
+}
+
+fn parse_minutes(time: &str) -> Option<u32> {
+    let (hours, minutes) = time.split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+
+    match hours * 60 + minutes {
+        total if minutes < 60 && total <= DAY_MINUTES => Some(total),
+        _ => None,
+    }
+}
+
+impl TimeWindow {
+    /// Parses `Mon-Fri 08:00-18:00`, `Sat,Sun 10:00-12:00` or `* 22:00-06:00`.
+    pub fn parse(window: &str) -> Option<Self> {
+        let (days_spec, span) = window.trim().split_once(' ')?;
+        let (start, end) = span.trim().split_once('-')?;
+        let mut days = [false; 7];
+
+        for part in days_spec.split(',') {
+            match part.split_once('-') {
+                _ if part == "*" => days = [true; 7],
+                Some((first, last)) => {
+                    let (first, last) = (parse_day(first)?, parse_day(last)?);
+                    let mut day = first;
+                    loop {
+                        days[day] = true;
+                        if day == last {
+                            break;
+                        }
+                        day = (day + 1) % 7;
+                    }
+                }
+                None => days[parse_day(part)?] = true,
+            }
+        }
+        let (start, end) = (parse_minutes(start)?, parse_minutes(end)?);
+        if start == end {
+            return None;
+        }
+
+        Some(TimeWindow { days, start, end })
+    }
+
+    /// Window that never opens, standing for one that couldn't be parsed.
+    pub fn closed() -> Self {
+        TimeWindow {
+            days: [false; 7],
+            start: 0,
+            end: DAY_MINUTES,
+        }
+    }
+
+    // Local end of the window holding `now`, if any
+    fn open_until(&self, now: NaiveDateTime) -> Option<NaiveDateTime> {
+        let today = now.weekday().num_days_from_monday() as usize;
+        let yesterday = (today + 6) % 7;
+        let minute = now.hour() * 60 + now.minute();
+        let midnight = now.date().and_hms_opt(0, 0, 0)?;
+        let at = |days: i64, minutes: u32| {
+            midnight + Duration::days(days) + Duration::minutes(minutes as i64)
+        };
+
+        if self.start < self.end {
+            return (self.days[today] && self.start <= minute && minute < self.end)
+                .then(|| at(0, self.end));
+        }
+        if self.days[today] && minute >= self.start {
+            return Some(at(1, self.end));
+        }
+        (self.days[yesterday] && minute < self.end).then(|| at(0, self.end))
+    }
+}
+
+impl RestrictedRoute {
+    /// When the current window closes, or `None` outside every window.
+    pub fn window_deadline(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let local = now.with_timezone(&self.timezone).naive_local();
+
+        self.windows
+            .iter()
+            .filter_map(|window| window.open_until(local))
+            .max()
+            .and_then(|end| self.timezone.from_local_datetime(&end).earliest())
+            .map(|end| end.with_timezone(&Utc))
+    }
+}
+
+fn new_grant_id() -> String {
+    let bytes: [u8; 8] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn issue_grant(
+    user: &str,
+    route: &str,
+    issued_by: &str,
+    reason: &str,
+    lifetime: Duration,
+) -> Grant {
+    let grant = Grant {
+        id: new_grant_id(),
+        user: user.to_string(),
+        route: route.to_string(),
+        issued_by: issued_by.to_string(),
+        reason: reason.to_string(),
+        expires_at: Utc::now() + lifetime,
+    };
+    let mut grants = GRANTS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    grants.retain(|grant| grant.expires_at > Utc::now());
+    grants.push(grant.clone());
+    grant
+}
+
+pub fn active_grants() -> Vec<Grant> {
+    let grants = GRANTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Utc::now();
+
+    grants
+        .iter()
+        .filter(|grant| grant.expires_at > now)
+        .cloned()
+        .collect()
+}
+
+pub fn revoke_grant(id: &str) -> Option<Grant> {
+    let mut grants = GRANTS.lock().unwrap_or_else(PoisonError::into_inner);
+    let position = grants.iter().position(|grant| grant.id == id)?;
+    let grant = grants.remove(position);
+
+    REVOKED.notify_waiters();
+    Some(grant)
+}
+
+fn grant_deadline(user: &str, route: &str, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let grants = GRANTS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    grants
+        .iter()
+        .filter(|grant| grant.user == user && grant.route == route && grant.expires_at > now)
+        .map(|grant| grant.expires_at)
+        .max()
+}
+
+/// Checks the time windows and grants of the restricted routes covering
+/// `target`. Returns when access ends, or why it is refused.
+pub fn check(
+    user: Option<&ProxyUser>,
+    target: &str,
+    config: Value,
+) -> Result<Option<DateTime<Utc>>, String> {
+    let url = match Url::parse(target) {
+        Ok(url) => url,
+        Err(_) => return Err(format!("unparsable target {}", target)),
+    };
+    let now = Utc::now();
+    let mut deadline: Option<DateTime<Utc>> = None;
+    let restricted_routes = match setup_restricted(config) {
+        Ok(restricted_routes) => restricted_routes,
+        Err(_) => return Err("the [[restricted]] configuration is broken".to_string()),
+    };
+
+    for restricted in restricted_routes {
+        if !restricted.route.matches(&url, None) {
+            continue;
+        }
+        if !restricted.windows.is_empty() {
+            match restricted.window_deadline(now) {
+                Some(end) => deadline = Some(deadline.map_or(end, |current| current.min(end))),
+                None => return Err(format!("{} is outside its access windows", restricted.key)),
+            }
+        }
+        if restricted.approval {
+            let granted = user.and_then(|user| grant_deadline(&user.name, &restricted.key, now));
+            match granted {
+                Some(end) => deadline = Some(deadline.map_or(end, |current| current.min(end))),
+                None => return Err(format!("no active grant for {}", restricted.key)),
+            }
+        }
+    }
+
+    Ok(deadline)
+}
+
+// Resolves once access has ended: at the deadline, or when a revoked
+// grant makes `recheck` refuse it
+async fn access_end<F>(mut deadline: DateTime<Utc>, recheck: F) -> &'static str
+where
+    F: Fn() -> Result<Option<DateTime<Utc>>, String>,
+{
+    let mut revoked = Box::pin(REVOKED.notified());
+
+    loop {
+        let remaining = (deadline - Utc::now()).to_std().unwrap_or_default();
+        tokio::select! {
+            _ = tokio::time::sleep(remaining) => return "Access expired",
+            _ = &mut revoked => {}
+        }
+        revoked = Box::pin(REVOKED.notified());
+        match recheck() {
+            Ok(Some(end)) => deadline = end,
+            Ok(None) => {}
+            Err(_) => return "Access revoked",
+        }
+    }
+}
+
+/// Stops streaming the response body once `deadline` has passed, or once
+/// `recheck`, run again whenever a grant is revoked, refuses access.
+pub fn cut_off<F>(response: Response<Body>, deadline: DateTime<Utc>, recheck: F) -> Response<Body>
+where
+    F: Fn() -> Result<Option<DateTime<Utc>>, String> + Send + 'static,
+{
+    let (parts, mut body) = response.into_parts();
+    let (mut sender, limited) = Body::channel();
+
+    tokio::spawn(async move {
+        let ended = access_end(deadline, recheck);
+        tokio::pin!(ended);
+
+        let reason = loop {
+            let chunk = tokio::select! {
+                reason = &mut ended => break reason,
+                chunk = body.data() => chunk,
+            };
+            let sent = match chunk {
+                Some(Ok(chunk)) => tokio::select! {
+                    reason = &mut ended => break reason,
+                    sent = sender.send_data(chunk) => sent,
+                },
+                Some(Err(err)) => {
+                    eprintln!("Error reading the response body: {}", err);
+                    sender.abort();
+                    return;
+                }
+                None => return,
+            };
+            if sent.is_err() {
+                return;
+            }
+        };
+        println!("{}, response cut off", reason);
+        sender.abort();
+    });
+
+    Response::from_parts(parts, limited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration as StdDuration;
+    use tokio::runtime::Runtime;
+
+    fn restricted(windows: &[&str], approval: bool) -> RestrictedRoute {
+        RestrictedRoute {
+            key: "https://db.example/".to_string(),
+            route: CredentialScope::parse("https://db.example/", None).unwrap(),
+            timezone: "Europe/Paris".parse().unwrap(),
+            windows: windows
+                .iter()
+                .map(|window| TimeWindow::parse(window).unwrap())
+                .collect(),
+            approval,
+        }
+    }
+
+    fn utc(time: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(time)
+            .unwrap()
+            .with_timezone(&Utc)
+    }
+
+    #[test]
+    fn test_parse_window() {
+        let window = TimeWindow::parse("Mon-Fri 08:00-18:30").unwrap();
+        assert_eq!(window.days, [true, true, true, true, true, false, false]);
+        assert_eq!((window.start, window.end), (480, 1110));
+
+        let window = TimeWindow::parse("Sat,sunday 22:00-24:00").unwrap();
+        assert_eq!(window.days, [false, false, false, false, false, true, true]);
+        assert!(TimeWindow::parse("Fri-Mon 00:00-01:00").unwrap().days[6]);
+        assert_eq!(TimeWindow::parse("* 22:00-06:00").unwrap().days, [true; 7]);
+
+        assert_eq!(TimeWindow::parse("Mon 08:00-08:00"), None);
+        assert_eq!(TimeWindow::parse("Mon 08:00-25:00"), None);
+        assert_eq!(TimeWindow::parse("Funday 08:00-09:00"), None);
+        assert_eq!(TimeWindow::parse("08:00-09:00"), None);
+    }
+
+    #[test]
+    fn test_window_deadline() {
+        let route = restricted(&["Mon-Fri 08:00-18:00"], false);
+
+        // Wednesday 2024-01-10, Paris is UTC+1 in winter
+        assert_eq!(
+            route.window_deadline(utc("2024-01-10T09:30:00Z")),
+            Some(utc("2024-01-10T17:00:00Z"))
+        );
+        assert_eq!(route.window_deadline(utc("2024-01-10T06:59:00Z")), None);
+        assert_eq!(route.window_deadline(utc("2024-01-10T17:00:00Z")), None);
+        // Saturday
+        assert_eq!(route.window_deadline(utc("2024-01-13T10:00:00Z")), None);
+        // summer time, UTC+2
+        assert_eq!(
+            route.window_deadline(utc("2024-07-10T06:30:00Z")),
+            Some(utc("2024-07-10T16:00:00Z"))
+        );
+    }
+
+    #[test]
+    fn test_overnight_window() {
+        let route = restricted(&["Fri 22:00-06:00"], false);
+
+        // Friday 23:00 and Saturday 05:00 in Paris
+        assert_eq!(
+            route.window_deadline(utc("2024-01-12T22:00:00Z")),
+            Some(utc("2024-01-13T05:00:00Z"))
+        );
+        assert_eq!(
+            route.window_deadline(utc("2024-01-13T04:00:00Z")),
+            Some(utc("2024-01-13T05:00:00Z"))
+        );
+        // Saturday 23:00
+        assert_eq!(route.window_deadline(utc("2024-01-13T22:00:00Z")), None);
+    }
+
+    #[test]
+    fn test_check_grants() {
+        let config: Value = toml::from_str(
+            r#"
+            [[restricted]]
+            route = "https://vault.example/"
+            approval = true
+            "#,
+        )
+        .unwrap();
+        let alice = ProxyUser::new("alice", Vec::new());
+        let target = "https://vault.example/secrets";
+
+        assert!(check(Some(&alice), target, config.clone()).is_err());
+        assert!(check(None, target, config.clone()).is_err());
+        assert_eq!(
+            check(Some(&alice), "https://other.example/", config.clone()),
+            Ok(None)
+        );
+
+        let grant = issue_grant(
+            "alice",
+            "https://vault.example/",
+            "admin",
+            "incident 42",
+            Duration::minutes(30),
+        );
+        assert_eq!(
+            check(Some(&alice), target, config.clone()),
+            Ok(Some(grant.expires_at))
+        );
+        assert!(active_grants().contains(&grant));
+
+        assert_eq!(revoke_grant(&grant.id), Some(grant.clone()));
+        assert!(check(Some(&alice), target, config).is_err());
+        assert!(!active_grants().contains(&grant));
+    }
+
+    #[test]
+    fn test_broken_entry_refuses_everything() {
+        let config: Value = toml::from_str(
+            r#"
+            [[restricted]]
+            route = "https://vault.example/"
+            approval = true
+
+            [[restricted]]
+            route = "not a url"
+            "#,
+        )
+        .unwrap();
+
+        assert!(check(None, "https://other.example/", config.clone()).is_err());
+        let config: Value = toml::from_str(
+            "[[restricted]]
+approval = true
+",
+        )
+        .unwrap();
+        assert!(check(None, "https://other.example/", config).is_err());
+    }
+
+    #[test]
+    fn test_revocation_cuts_off() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let config: Value = toml::from_str(
+                r#"
+                [[restricted]]
+                route = "https://safe.example/"
+                approval = true
+                "#,
+            )
+            .unwrap();
+            let carol = ProxyUser::new("carol", Vec::new());
+            let target = "https://safe.example/dump";
+            let grant = issue_grant(
+                "carol",
+                "https://safe.example/",
+                "admin",
+                "",
+                Duration::minutes(30),
+            );
+            let deadline = check(Some(&carol), target, config.clone())
+                .unwrap()
+                .unwrap();
+
+            let (mut sender, body) = Body::channel();
+            let response = cut_off(Response::new(body), deadline, move || {
+                check(Some(&carol), target, config.clone())
+            });
+            tokio::spawn(async move {
+                sender.send_data("first".into()).await.unwrap();
+                tokio::time::sleep(StdDuration::from_millis(200)).await;
+                let _ = sender.send_data("after the revocation".into()).await;
+            });
+
+            let mut body = response.into_body();
+            assert_eq!(body.data().await.unwrap().unwrap(), "first");
+            revoke_grant(&grant.id);
+            assert!(body.data().await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn test_cut_off() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut sender, body) = Body::channel();
+            let response = cut_off(
+                Response::new(body),
+                Utc::now() + Duration::milliseconds(100),
+                || Ok(None),
+            );
+
+            tokio::spawn(async move {
+                sender.send_data("first".into()).await.unwrap();
+                tokio::time::sleep(StdDuration::from_millis(500)).await;
+                let _ = sender.send_data("too late".into()).await;
+            });
+
+            let mut body = response.into_body();
+            assert_eq!(body.data().await.unwrap().unwrap(), "first");
+            assert!(body.data().await.unwrap().is_err());
+        });
+    }
+}
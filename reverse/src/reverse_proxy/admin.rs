@@ -0,0 +1,313 @@
+use chrono::Duration;
+use hyper::{header::CONTENT_TYPE, Body, Method, Request, Response, StatusCode};
+
+use toml::Value;
+
+use super::{
+    access::{self, Grant},
+    audit::{self, AuditEvent},
+    config::{setup_admin, setup_restricted},
+    forms::{active_manual_logins, ManualLogin},
+    portal::ProxyUser,
+    status, ProxyError,
+};
+
+pub const ADMIN_PREFIX: &str = "/__proxy/admin/";
+
+const GRANTS_PATH: &str = "/__proxy/admin/grants";
+const FALLBACKS_PATH: &str = "/__proxy/admin/fallbacks";
+const AUDIT_PATH: &str = "/__proxy/admin/audit";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AdminConfig {
+    pub groups: Vec<String>,
+    pub max_grant_minutes: i64,
+}
+
+fn grant_json(grant: &Grant) -> serde_json::Value {
+    serde_json::json!({
+        "id": grant.id,
+        "user": grant.user,
+        "route": grant.route,
+        "issued_by": grant.issued_by,
+        "reason": grant.reason,
+        "expires_at": grant.expires_at.to_rfc3339(),
+    })
+}
+
+fn manual_login_json(manual: &ManualLogin) -> serde_json::Value {
+    serde_json::json!({
+        "route": manual.route,
+        "user": manual.user,
+        "reason": manual.reason,
+        "since": manual.since.to_rfc3339(),
+        "until": manual.until.to_rfc3339(),
+    })
+}
+
+fn json_response(
+    status: StatusCode,
+    json: serde_json::Value,
+) -> Result<Response<Body>, ProxyError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(json.to_string()))?)
+}
+
+fn error_response(status: StatusCode, error: &str) -> Result<Response<Body>, ProxyError> {
+    json_response(status, serde_json::json!({ "error": error }))
+}
+
+async fn issue(
+    req: Request<Body>,
+    admin: &AdminConfig,
+    issuer: &ProxyUser,
+    config: &Value,
+) -> Result<Response<Body>, ProxyError> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let request: serde_json::Value = match serde_json::from_slice(&body) {
+        Ok(request) => request,
+        Err(_) => return error_response(StatusCode::BAD_REQUEST, "expected a JSON body"),
+    };
+    let (user, route, minutes) = match (
+        request["user"].as_str(),
+        request["route"].as_str(),
+        request["minutes"].as_i64(),
+    ) {
+        (Some(user), Some(route), Some(minutes)) => (user, route, minutes),
+        _ => {
+            return error_response(
+                StatusCode::BAD_REQUEST,
+                "user, route and minutes are required",
+            )
+        }
+    };
+    if minutes <= 0 || minutes > admin.max_grant_minutes {
+        return error_response(StatusCode::BAD_REQUEST, "minutes out of range");
+    }
+    let approval_route = setup_restricted(config.clone())
+        .unwrap_or_default()
+        .iter()
+        .any(|restricted| restricted.approval && restricted.key == route);
+    if !approval_route {
+        return error_response(StatusCode::BAD_REQUEST, "route doesn't require approval");
+    }
+
+    let reason = request["reason"].as_str().unwrap_or("");
+    let grant = access::issue_grant(
+        user,
+        route,
+        &issuer.name,
+        reason,
+        Duration::minutes(minutes),
+    );
+    let detail = format!(
+        "grant {} for {} until {}",
+        grant.id,
+        user,
+        grant.expires_at.to_rfc3339()
+    );
+    audit::record(
+        AuditEvent::new("grant_issued", Some(issuer), "POST", route, &detail),
+        config,
+    );
+
+    json_response(StatusCode::CREATED, grant_json(&grant))
+}
+
+fn revoke(id: &str, issuer: &ProxyUser, config: &Value) -> Result<Response<Body>, ProxyError> {
+    match access::revoke_grant(id) {
+        Some(grant) => {
+            let detail = format!("grant {} for {}", grant.id, grant.user);
+            audit::record(
+                AuditEvent::new(
+                    "grant_revoked",
+                    Some(issuer),
+                    "DELETE",
+                    &grant.route,
+                    &detail,
+                ),
+                config,
+            );
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())?)
+        }
+        None => error_response(StatusCode::NOT_FOUND, "unknown grant"),
+    }
+}
+
+/// Admin API, open to the proxy users of the [admin] groups.
+///
+/// - `GET /__proxy/admin/grants` lists the active grants
+/// - `POST /__proxy/admin/grants` issues one from `{"user", "route", "minutes", "reason"}`
+/// - `DELETE /__proxy/admin/grants/<id>` revokes one
+/// - `GET /__proxy/admin/fallbacks` lists the form logins left to their users
+/// - `GET /__proxy/admin/audit` lists the recent audit events, oldest first
+pub async fn handle(
+    req: Request<Body>,
+    user: Option<&ProxyUser>,
+    config: Value,
+) -> Result<Response<Body>, ProxyError> {
+    let admin = match setup_admin(config.clone()) {
+        Some(admin) => admin,
+        None => return status::not_found(),
+    };
+    let user = match user {
+        Some(user) => user,
+        None => return status::login_required(),
+    };
+    let path = req.uri().path().to_string();
+    if !user.groups.iter().any(|group| admin.groups.contains(group)) {
+        let event = AuditEvent::new(
+            "access_denied",
+            Some(user),
+            req.method().as_str(),
+            &path,
+            "not an admin",
+        );
+        audit::record(event, &config);
+        return status::forbidden();
+    }
+
+    match (req.method(), path.as_str()) {
+        (&Method::GET, GRANTS_PATH) => {
+            let grants: Vec<serde_json::Value> =
+                access::active_grants().iter().map(grant_json).collect();
+            json_response(StatusCode::OK, serde_json::Value::from(grants))
+        }
+        (&Method::POST, GRANTS_PATH) => issue(req, &admin, user, &config).await,
+        (&Method::GET, FALLBACKS_PATH) => {
+            let manual_logins: Vec<serde_json::Value> = active_manual_logins()
+                .iter()
+                .map(manual_login_json)
+                .collect();
+            json_response(StatusCode::OK, serde_json::Value::from(manual_logins))
+        }
+        (&Method::GET, AUDIT_PATH) => {
+            let events: Vec<serde_json::Value> = audit::recent_events()
+                .iter()
+                .map(AuditEvent::to_json)
+                .collect();
+            json_response(StatusCode::OK, serde_json::Value::from(events))
+        }
+        (&Method::DELETE, path) => match path
+            .strip_prefix(GRANTS_PATH)
+            .and_then(|id| id.strip_prefix('/'))
+        {
+            Some(id) if !id.is_empty() => revoke(id, user, &config),
+            _ => status::not_found(),
+        },
+        _ => status::not_found(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn config() -> Value {
+        toml::from_str(
+            r#"
+            [admin]
+            groups = ["admins"]
+
+            [[restricted]]
+            route = "https://vault.example/"
+            approval = true
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn request(method: Method, path: &str, body: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    async fn json_body(res: Response<Body>) -> serde_json::Value {
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_grant_lifecycle() {
+        let rt = Runtime::new().unwrap();
+        let admin = ProxyUser::new("root", vec!["admins".to_string()]);
+
+        rt.block_on(async {
+            let issue = request(
+                Method::POST,
+                GRANTS_PATH,
+                r#"{"user":"bob","route":"https://vault.example/","minutes":15,"reason":"backup"}"#,
+            );
+            let res = handle(issue, Some(&admin), config()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::CREATED);
+            let grant = json_body(res).await;
+            assert_eq!(grant["user"], "bob");
+            assert_eq!(grant["issued_by"], "root");
+            let id = grant["id"].as_str().unwrap().to_string();
+
+            let list = request(Method::GET, GRANTS_PATH, "");
+            let res = handle(list, Some(&admin), config()).await.unwrap();
+            let grants = json_body(res).await;
+            assert!(grants
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|grant| grant["id"] == id));
+
+            let revoke = request(Method::DELETE, &format!("{}/{}", GRANTS_PATH, id), "");
+            let res = handle(revoke, Some(&admin), config()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NO_CONTENT);
+
+            let revoke = request(Method::DELETE, &format!("{}/{}", GRANTS_PATH, id), "");
+            let res = handle(revoke, Some(&admin), config()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            let audit = request(Method::GET, AUDIT_PATH, "");
+            let res = handle(audit, Some(&admin), config()).await.unwrap();
+            let events = json_body(res).await;
+            let revoked = format!("grant {} for bob", id);
+            assert!(events
+                .as_array()
+                .unwrap()
+                .iter()
+                .any(|event| event["kind"] == "grant_revoked" && event["detail"] == revoked));
+        });
+    }
+
+    #[test]
+    fn test_rejected_requests() {
+        let rt = Runtime::new().unwrap();
+        let admin = ProxyUser::new("root", vec!["admins".to_string()]);
+        let bob = ProxyUser::new("bob", vec!["staff".to_string()]);
+
+        rt.block_on(async {
+            let list = || request(Method::GET, GRANTS_PATH, "");
+            let res = handle(list(), Some(&bob), config()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::FORBIDDEN);
+            let res = handle(list(), None, config()).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let no_admin: Value = toml::from_str("").unwrap();
+            let res = handle(list(), Some(&admin), no_admin).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+
+            for body in [
+                r#"{"user":"bob","route":"https://other.example/","minutes":15}"#,
+                r#"{"user":"bob","route":"https://vault.example/","minutes":100000}"#,
+                r#"{"user":"bob","route":"https://vault.example/"}"#,
+                "not json",
+            ] {
+                let issue = request(Method::POST, GRANTS_PATH, body);
+                let res = handle(issue, Some(&admin), config()).await.unwrap();
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", body);
+            }
+        });
+    }
+}
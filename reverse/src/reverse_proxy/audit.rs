@@ -0,0 +1,130 @@
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::Write,
+    sync::{Mutex, PoisonError},
+};
+use toml::Value;
+
+use super::{config::setup_audit, portal::ProxyUser};
+
+// Events kept in memory for the admin API
+const RECENT_EVENTS: usize = 1000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub kind: String,
+    pub user: Option<String>,
+    pub method: String,
+    pub target: String,
+    pub detail: String,
+}
+
+static EVENTS: Lazy<Mutex<VecDeque<AuditEvent>>> = Lazy::new(|| Mutex::new(VecDeque::new()));
+
+impl AuditEvent {
+    pub fn new(
+        kind: &str,
+        user: Option<&ProxyUser>,
+        method: &str,
+        target: &str,
+        detail: &str,
+    ) -> Self {
+        AuditEvent {
+            timestamp: Utc::now().to_rfc3339(),
+            kind: kind.to_string(),
+            user: user.map(|user| user.name.clone()),
+            method: method.to_string(),
+            target: target.to_string(),
+            detail: detail.to_string(),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "timestamp": self.timestamp,
+            "kind": self.kind,
+            "user": self.user,
+            "method": self.method,
+            "target": self.target,
+            "detail": self.detail,
+        })
+    }
+}
+
+/// Records `event` in memory and, with an [audit] file, as a JSON line.
+pub fn record(event: AuditEvent, config: &Value) {
+    println!(
+        "AUDIT {} {} {} {} ({})",
+        event.kind,
+        event.user.as_deref().unwrap_or("-"),
+        event.method,
+        event.target,
+        event.detail
+    );
+
+    if let Some(path) = setup_audit(config) {
+        let written = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| writeln!(file, "{}", event.to_json()));
+        if let Err(err) = written {
+            eprintln!("Error writing the {} audit file : {}", path, err);
+        }
+    }
+
+    let mut events = EVENTS.lock().unwrap_or_else(PoisonError::into_inner);
+    if events.len() == RECENT_EVENTS {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+/// Most recent events, oldest first.
+pub fn recent_events() -> Vec<AuditEvent> {
+    let events = EVENTS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    events.iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn test_record() {
+        let file = env::temp_dir().join("reverse-test-audit.log");
+        let _ = fs::remove_file(&file);
+        let config: Value =
+            toml::from_str(&format!("[audit]\nfile = \"{}\"\n", file.display())).unwrap();
+        let user = ProxyUser::new("alice", Vec::new());
+
+        record(
+            AuditEvent::new("test", Some(&user), "GET", "http://host/a", "first"),
+            &config,
+        );
+        record(
+            AuditEvent::new("test", None, "POST", "http://host/b", "second"),
+            &config,
+        );
+
+        let lines: Vec<serde_json::Value> = fs::read_to_string(&file)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["user"], "alice");
+        assert_eq!(lines[1]["user"], serde_json::Value::Null);
+        assert_eq!(lines[1]["detail"], "second");
+
+        let recent = recent_events();
+        assert!(recent.iter().any(|event| event.detail == "first"));
+        fs::remove_file(file).unwrap();
+    }
+}
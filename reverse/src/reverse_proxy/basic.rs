@@ -7,23 +7,21 @@ use hyper_tls::HttpsConnector;
 
 use base64::{engine::general_purpose, Engine as _};
 use once_cell::sync::Lazy;
-use std::{collections::HashMap, sync::Arc};
+use std::sync::{Arc, Mutex, PoisonError};
 use toml::Value;
+use url::Url;
 
-use super::{config::setup_basic, ProxyError};
-use crate::reverse_proxy::utils::{clean_url, common_prefix};
+use super::{config::setup_basic, portal::ProxyUser, scope::match_scope, ProxyError};
 
 #[derive(Debug)]
 pub struct ServerCredentials {
-    pub realm: String,
     pub username: String,
     pub password: String,
 }
 
 impl ServerCredentials {
-    pub fn new(realm: &str, username: &str, password: &str) -> Self {
+    pub fn new(username: &str, password: &str) -> Self {
         ServerCredentials {
-            realm: realm.to_string(),
             username: username.to_string(),
             password: password.to_string(),
         }
@@ -31,9 +29,119 @@ impl ServerCredentials {
 
     fn _print_credentials(&self) {
         println!(
-            "|**\n|Realm: {}\n|Username: {}\n|Password: {}\n|_",
-            self.realm, self.username, self.password
+            "|**\n|Username: {}\n|Password: {}\n|_",
+            self.username, self.password
+        );
+    }
+}
+
+// Scheme, host, path prefix and realm known to require credentials
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProtectionSpace {
+    scheme: String,
+    authority: String,
+    path_prefix: String,
+    realm: String,
+}
+
+static PROTECTION_SPACES: Lazy<Mutex<Vec<ProtectionSpace>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+impl ProtectionSpace {
+    // RFC 7617: paths at or deeper than the last segment of the URI share its protection space
+    fn new(uri: &str, realm: &str) -> Option<Self> {
+        let url = Url::parse(uri).ok()?;
+        let path = url.path();
+        let path_prefix = &path[..path.rfind('/').map_or(0, |index| index + 1)];
+
+        Some(ProtectionSpace {
+            scheme: url.scheme().to_string(),
+            authority: format!("{}:{}", url.host_str()?, url.port_or_known_default()?),
+            path_prefix: path_prefix.to_string(),
+            realm: realm.to_string(),
+        })
+    }
+
+    fn contains(&self, url: &Url) -> bool {
+        let authority = match (url.host_str(), url.port_or_known_default()) {
+            (Some(host), Some(port)) => format!("{}:{}", host, port),
+            _ => return false,
+        };
+
+        url.scheme() == self.scheme
+            && authority == self.authority
+            && url.path().starts_with(&self.path_prefix)
+    }
+}
+
+fn find_space<'a>(spaces: &'a [ProtectionSpace], uri: &str) -> Option<&'a ProtectionSpace> {
+    let url = Url::parse(uri).ok()?;
+
+    spaces
+        .iter()
+        .filter(|space| space.contains(&url))
+        .max_by_key(|space| space.path_prefix.len())
+}
+
+fn insert_space(spaces: &mut Vec<ProtectionSpace>, space: ProtectionSpace) {
+    spaces.retain(|known| {
+        known.scheme != space.scheme
+            || known.authority != space.authority
+            || known.path_prefix != space.path_prefix
+    });
+    spaces.push(space);
+}
+
+fn remove_space(spaces: &mut Vec<ProtectionSpace>, uri: &str, realm: &str) {
+    if let Ok(url) = Url::parse(uri) {
+        spaces.retain(|space| !(space.realm == realm && space.contains(&url)));
+    }
+}
+
+/// Realm of the cached protection space covering `uri`, if any.
+pub fn cached_realm(uri: &str) -> Option<String> {
+    let spaces = PROTECTION_SPACES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    find_space(&spaces, uri).map(|space| space.realm.clone())
+}
+
+pub fn remember_protection_space(uri: &str, realm: &str) {
+    if let Some(space) = ProtectionSpace::new(uri, realm) {
+        println!(
+            "Protection space cached: {}{}",
+            uri,
+            format_args!(" ({})", realm)
         );
+        let mut spaces = PROTECTION_SPACES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        insert_space(&mut spaces, space);
+    }
+}
+
+pub fn forget_protection_space(uri: &str, realm: &str) {
+    let mut spaces = PROTECTION_SPACES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    remove_space(&mut spaces, uri, realm);
+}
+
+/// Realm requested by a Basic `WWW-Authenticate` challenge.
+pub fn challenge_realm(resp_headers: &HeaderMap) -> Option<String> {
+    let auth_str = resp_headers
+        .get(WWW_AUTHENTICATE)
+        .and_then(|header| header.to_str().ok())
+        .unwrap_or_default();
+    let basic = auth_str.strip_prefix("Basic realm=\"")?;
+
+    match basic.find('\"') {
+        Some(end_quote_index) => Some(basic[..end_quote_index].to_string()),
+        None => {
+            eprintln!("Realm Parsing Error: Closing double quote not found!");
+            None
+        }
     }
 }
 
@@ -51,7 +159,7 @@ fn _decode_auth(auth_val: &str) -> Result<(String, String), &'static str> {
     }
 }
 
-fn encode_auth(user: String, password: String) -> String {
+pub fn encode_auth(user: String, password: String) -> String {
     let combined_auth = format!("{}:{}", user, password);
     let encoded_credentials = general_purpose::STANDARD.encode(combined_auth);
 
@@ -78,68 +186,36 @@ async fn reconstruct_req(
     Ok(authenticated_req)
 }
 
-fn match_credentials<'a>(
-    uri: &str,
-    auth_map: &'a HashMap<String, ServerCredentials>,
-) -> Option<&'a ServerCredentials> {
-    match auth_map.get(uri) {
-        Some(_) => return auth_map.get(uri),
-        None => {
-            for (key, _) in auth_map.iter() {
-                if common_prefix(uri, key) == *key {
-                    return auth_map.get(key);
-                }
-            }
-        }
-    }
-
-    None
-}
-
-async fn auth_middleware(
+pub async fn auth_middleware(
     req: Request<Body>,
     realm: &str,
     config: Value,
+    user: Option<&ProxyUser>,
 ) -> Result<Request<Body>, ProxyError> {
     let basic_auth = match setup_basic(config) {
         Some(map) => map,
         None => return Ok(req),
     };
-    let target = clean_url(&(req.uri().to_string()));
+    let target = req.uri().to_string();
 
-    let credential_info = match match_credentials(&target, &basic_auth) {
-        Some(res) => res,
-        None => return Ok(req),
-    };
-
-    if realm == credential_info.realm {
-        let authenticated = reconstruct_req(req, credential_info).await?;
-        return Ok(authenticated);
+    match match_scope(&basic_auth, &target, Some(realm)).and_then(|found| found.resolve(user)) {
+        Some(credential_info) => reconstruct_req(req, credential_info).await,
+        None => Ok(req),
     }
-    Ok(req)
 }
 
 pub async fn intercept_auth(
     resp_headers: &HeaderMap,
     cloned_req: Request<Body>,
     config: Value,
+    user: Option<&ProxyUser>,
 ) -> Result<Request<Body>, ProxyError> {
-    let auth_header = resp_headers.get(WWW_AUTHENTICATE);
-    let auth_str = auth_header
-        .and_then(|header| header.to_str().ok())
-        .unwrap_or_default();
-
-    if let Some(basic) = auth_str.strip_prefix("Basic realm=\"") {
-        if let Some(end_quote_index) = basic.find('\"') {
-            let extracted_auth = &basic[..end_quote_index];
-            println!("Identified Basic Authentication needed!");
-            println!(" |__ {}\n", extracted_auth);
+    if let Some(extracted_auth) = challenge_realm(resp_headers) {
+        println!("Identified Basic Authentication needed!");
+        println!(" |__ {}\n", extracted_auth);
 
-            let authenticated_req = auth_middleware(cloned_req, extracted_auth, config).await?;
-            return Ok(authenticated_req);
-        } else {
-            eprintln!("Realm Parsing Error: Closing double quote not found!");
-        }
+        let authenticated_req = auth_middleware(cloned_req, &extracted_auth, config, user).await?;
+        return Ok(authenticated_req);
     }
     Ok(cloned_req)
 }
@@ -218,6 +294,61 @@ pub mod tests {
         }
     }
 
+    #[test]
+    fn test_protection_space_prefix() {
+        let space = ProtectionSpace::new("http://localhost:8080/docs/index.html", "Docs").unwrap();
+        assert_eq!(space.authority, "localhost:8080");
+        assert_eq!(space.path_prefix, "/docs/");
+
+        let contained = |uri: &str| space.contains(&Url::parse(uri).unwrap());
+        assert!(contained("http://localhost:8080/docs/"));
+        assert!(contained("http://localhost:8080/docs/sub/page.html"));
+        assert!(!contained("http://localhost:8080/documents"));
+        assert!(!contained("http://localhost:8081/docs/"));
+        assert!(!contained("https://localhost:8080/docs/"));
+    }
+
+    #[test]
+    fn test_protection_space_cache() {
+        let mut spaces = Vec::new();
+        insert_space(
+            &mut spaces,
+            ProtectionSpace::new("http://host/index.html", "Root").unwrap(),
+        );
+        insert_space(
+            &mut spaces,
+            ProtectionSpace::new("http://host/admin/index.html", "Admin").unwrap(),
+        );
+
+        let realm =
+            |spaces: &[ProtectionSpace], uri| find_space(spaces, uri).map(|s| s.realm.clone());
+        assert_eq!(
+            realm(&spaces, "http://host/admin/users"),
+            Some("Admin".to_string())
+        );
+        assert_eq!(
+            realm(&spaces, "http://host/public"),
+            Some("Root".to_string())
+        );
+        assert_eq!(realm(&spaces, "http://other/admin/users"), None);
+
+        remove_space(&mut spaces, "http://host/admin/users", "Admin");
+        assert_eq!(
+            realm(&spaces, "http://host/admin/users"),
+            Some("Root".to_string())
+        );
+
+        insert_space(
+            &mut spaces,
+            ProtectionSpace::new("http://host/home", "Renamed").unwrap(),
+        );
+        assert_eq!(spaces.len(), 1);
+        assert_eq!(
+            realm(&spaces, "http://host/admin/users"),
+            Some("Renamed".to_string())
+        );
+    }
+
     #[test]
     fn test_decode_invalid_format() {
         assert_eq!(
@@ -1,40 +1,20 @@
-use hyper::{Body, Client, Response};
+use hyper::{header::HeaderMap, Body, Client, Response};
 use hyper_tls::HttpsConnector;
 
-use scraper::{Html, Selector};
 use std::sync::Arc;
 use toml::Value;
 
-use super::forms::handle_forms;
+use super::forms::{handle_forms, Requester};
 use super::ProxyError;
 
-// Modified to generalise based on inputs from forms
-pub fn _extract_token(html: &str) -> Option<String> {
-    let document = Html::parse_document(html);
-
-    match Selector::parse("meta[name=csrf-token]") {
-        Ok(csrf_token_selector) => {
-            let csrf_token = document
-                .select(&csrf_token_selector)
-                .next()
-                .and_then(|meta| meta.value().attr("content"))
-                .map(|s| s.to_string());
-
-            csrf_token
-        }
-        Err(err) => {
-            eprintln!("{}", err);
-            None
-        }
-    }
-}
-
 async fn process_body(
     body: &[u8],
+    headers: &HeaderMap,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
     target_url: &str,
     session_cookie: String,
     config: Value,
+    requester: Requester<'_>,
 ) -> Result<Response<Body>, ()> {
     let body_str = match String::from_utf8(body.to_owned()) {
         Ok(body) => body,
@@ -44,11 +24,20 @@ async fn process_body(
         }
     };
 
-    if body_str.is_empty() || body_str == "" {
+    if body_str.is_empty() {
         println!("Body not identified/ empty");
     }
 
-    handle_forms(body_str, target_url, client, &session_cookie, config).await
+    handle_forms(
+        body_str,
+        headers,
+        target_url,
+        client,
+        &session_cookie,
+        config,
+        requester,
+    )
+    .await
 }
 
 pub async fn read_body(
@@ -57,11 +46,22 @@ pub async fn read_body(
     target_url: &str,
     session_cookie: String,
     config: Value,
+    requester: Requester<'_>,
 ) -> Result<hyper::Response<Body>, ProxyError> {
     let (parts, body) = resp.into_parts();
     let body_bytes = hyper::body::to_bytes(body).await?.to_vec();
 
-    match process_body(&body_bytes, client, target_url, session_cookie, config).await {
+    match process_body(
+        &body_bytes,
+        &parts.headers,
+        client,
+        target_url,
+        session_cookie,
+        config,
+        requester,
+    )
+    .await
+    {
         Ok(res) => Ok(res),
         Err(_) => {
             let target_response = Response::from_parts(parts, Body::from(body_bytes));
@@ -0,0 +1,322 @@
+use hyper::{
+    body::{Bytes, HttpBody},
+    header::{HeaderMap, CONTENT_LENGTH},
+    Body, Method, Request, Uri, Version,
+};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncReadExt, AsyncWriteExt},
+};
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use super::ProxyError;
+
+const DEFAULT_MEMORY_LIMIT: u64 = 1024 * 1024;
+const DEFAULT_MAX_BODY_SIZE: u64 = 16 * 1024 * 1024;
+const CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub struct BufferLimits {
+    pub memory_limit: u64,
+    pub max_body_size: u64,
+}
+
+/// Request body kept by the proxy so it can be sent more than once.
+#[derive(Debug)]
+pub enum BufferedBody {
+    Memory(Bytes),
+    File(PathBuf),
+}
+
+/// Request kept by the proxy so it can be replayed, body included.
+#[derive(Debug)]
+pub struct ReplayableRequest {
+    method: Method,
+    uri: Uri,
+    version: Version,
+    headers: HeaderMap,
+    body: BufferedBody,
+    received_at: Instant,
+}
+
+impl BufferLimits {
+    pub fn new(memory_limit: u64, max_body_size: u64) -> Self {
+        BufferLimits {
+            memory_limit,
+            max_body_size,
+        }
+    }
+}
+
+impl Default for BufferLimits {
+    fn default() -> Self {
+        BufferLimits::new(DEFAULT_MEMORY_LIMIT, DEFAULT_MAX_BODY_SIZE)
+    }
+}
+
+impl BufferedBody {
+    /// Builds a new `Body` streaming the buffered content.
+    pub async fn to_body(&self) -> Result<Body, ProxyError> {
+        let path = match self {
+            BufferedBody::Memory(bytes) => return Ok(Body::from(bytes.clone())),
+            BufferedBody::File(path) => path,
+        };
+        let mut file = File::open(path).await?;
+        let (mut sender, body) = Body::channel();
+
+        tokio::spawn(async move {
+            let mut chunk = vec![0u8; CHUNK_SIZE];
+            loop {
+                match file.read(&mut chunk).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if sender
+                            .send_data(Bytes::copy_from_slice(&chunk[..n]))
+                            .await
+                            .is_err()
+                        {
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        eprintln!("Error reading buffered body: {}", err);
+                        sender.abort();
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(body)
+    }
+}
+
+impl ReplayableRequest {
+    pub async fn keep(req: Request<Body>, limits: &BufferLimits) -> Result<Self, ProxyError> {
+        let received_at = Instant::now();
+        let (parts, body) = req.into_parts();
+        let body = buffer_body(body, &parts.headers, limits).await?;
+
+        Ok(ReplayableRequest {
+            method: parts.method,
+            uri: parts.uri,
+            version: parts.version,
+            headers: parts.headers,
+            body,
+            received_at,
+        })
+    }
+
+    /// When the proxy got the request, and with it the cookies it carries.
+    pub fn received_at(&self) -> Instant {
+        self.received_at
+    }
+
+    /// Headers sent with every copy of the request.
+    pub fn headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    /// Builds a new copy of the request.
+    pub async fn to_request(&self) -> Result<Request<Body>, ProxyError> {
+        let mut request = Request::new(self.body.to_body().await?);
+        *request.method_mut() = self.method.clone();
+        *request.uri_mut() = self.uri.clone();
+        *request.version_mut() = self.version;
+        *request.headers_mut() = self.headers.clone();
+        Ok(request)
+    }
+}
+
+impl Drop for BufferedBody {
+    fn drop(&mut self) {
+        if let BufferedBody::File(path) = &self {
+            if let Err(err) = fs::remove_file(path) {
+                eprintln!("Error removing {}: {}", path.display(), err);
+            }
+        }
+    }
+}
+
+fn spill_path() -> PathBuf {
+    let id: u64 = rand::random();
+    env::temp_dir().join(format!("reverse-body-{:016x}", id))
+}
+
+// A new file only the proxy can read, request bodies holding credentials
+async fn create_private(path: &Path) -> Result<File, ProxyError> {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    Ok(options.open(path).await?)
+}
+
+async fn write_file(
+    mut file: File,
+    memory: &[u8],
+    mut body: Body,
+    limits: &BufferLimits,
+) -> Result<u64, ProxyError> {
+    let mut len = memory.len() as u64;
+
+    file.write_all(memory).await?;
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        len += chunk.len() as u64;
+        if len > limits.max_body_size {
+            return Err(ProxyError::BodyTooLarge(limits.max_body_size));
+        }
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(len)
+}
+
+async fn spill_to_file(
+    memory: &[u8],
+    body: Body,
+    limits: &BufferLimits,
+) -> Result<BufferedBody, ProxyError> {
+    let path = spill_path();
+    let file = create_private(&path).await?;
+
+    match write_file(file, memory, body, limits).await {
+        Ok(len) => {
+            println!("Request body spilled to {} ({} bytes)", path.display(), len);
+            Ok(BufferedBody::File(path))
+        }
+        Err(err) => {
+            let _ = fs::remove_file(path);
+            Err(err)
+        }
+    }
+}
+
+/// Reads a whole request body, in memory up to `memory_limit` bytes and in a
+/// temporary file above, refusing bodies over `max_body_size` bytes.
+pub async fn buffer_body(
+    mut body: Body,
+    headers: &HeaderMap,
+    limits: &BufferLimits,
+) -> Result<BufferedBody, ProxyError> {
+    let announced_len = headers
+        .get(CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok());
+    if announced_len.is_some_and(|len| len > limits.max_body_size) {
+        return Err(ProxyError::BodyTooLarge(limits.max_body_size));
+    }
+
+    let mut memory = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if (memory.len() + chunk.len()) as u64 > limits.max_body_size {
+            return Err(ProxyError::BodyTooLarge(limits.max_body_size));
+        }
+        memory.extend_from_slice(&chunk);
+        if memory.len() as u64 > limits.memory_limit {
+            return spill_to_file(&memory, body, limits).await;
+        }
+    }
+
+    Ok(BufferedBody::Memory(Bytes::from(memory)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    async fn read_all(buffered: &BufferedBody) -> Vec<u8> {
+        let body = buffered.to_body().await.unwrap();
+        hyper::body::to_bytes(body).await.unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_buffer_body_in_memory() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limits = BufferLimits::new(16, 64);
+            let buffered = buffer_body(Body::from("name=value"), &HeaderMap::new(), &limits)
+                .await
+                .unwrap();
+
+            assert!(matches!(buffered, BufferedBody::Memory(_)));
+            assert_eq!(read_all(&buffered).await, b"name=value");
+            assert_eq!(read_all(&buffered).await, b"name=value");
+        });
+    }
+
+    #[test]
+    fn test_replayable_request() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let request = Request::post("http://upstream.example/form")
+                .header("x-test", "1")
+                .body(Body::from("a=1"))
+                .unwrap();
+            let kept = ReplayableRequest::keep(request, &BufferLimits::default())
+                .await
+                .unwrap();
+
+            for _ in 0..2 {
+                let replayed = kept.to_request().await.unwrap();
+                assert_eq!(replayed.method(), Method::POST);
+                assert_eq!(replayed.uri(), "http://upstream.example/form");
+                assert_eq!(replayed.headers()["x-test"], "1");
+                let body = hyper::body::to_bytes(replayed.into_body()).await.unwrap();
+                assert_eq!(&body[..], b"a=1");
+            }
+        });
+    }
+
+    #[test]
+    fn test_buffer_body_spills_to_file() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limits = BufferLimits::new(16, 64);
+            let content = b"0123456789".repeat(4);
+            let buffered = buffer_body(Body::from(content.clone()), &HeaderMap::new(), &limits)
+                .await
+                .unwrap();
+
+            let path = match &buffered {
+                BufferedBody::File(path) => path.clone(),
+                BufferedBody::Memory(_) => panic!("body should have been spilled"),
+            };
+            assert_eq!(fs::metadata(&path).unwrap().len(), 40);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                let mode = fs::metadata(&path).unwrap().permissions().mode();
+                assert_eq!(mode & 0o777, 0o600);
+            }
+            assert_eq!(read_all(&buffered).await, content);
+            assert_eq!(read_all(&buffered).await, content);
+
+            drop(buffered);
+            assert!(!path.exists());
+        });
+    }
+
+    #[test]
+    fn test_buffer_body_over_limit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let limits = BufferLimits::new(16, 32);
+            let result = buffer_body(Body::from(vec![b'a'; 40]), &HeaderMap::new(), &limits).await;
+            assert!(matches!(result, Err(ProxyError::BodyTooLarge(32))));
+
+            let mut headers = HeaderMap::new();
+            headers.insert(CONTENT_LENGTH, "1000".parse().unwrap());
+            let result = buffer_body(Body::empty(), &headers, &limits).await;
+            assert!(matches!(result, Err(ProxyError::BodyTooLarge(32))));
+        });
+    }
+}
@@ -3,16 +3,96 @@ use std::io::{prelude::Read, BufReader};
 
 use std::collections::HashMap;
 use std::string::String;
+use std::time::Duration;
 use toml::Value;
 
+use hyper::{header::HeaderName, Method};
+use regex::Regex;
+use scraper::Selector;
 use std::error::Error;
 
+use super::access::{RestrictedRoute, TimeWindow};
+use super::admin::AdminConfig;
 use super::basic::ServerCredentials;
+use super::buffer::BufferLimits;
+use super::forms::{
+    CsrfStrategy, Extraction, Fallback, FormFields, FormRoute, FormRules, LoginStep, LoginSuccess,
+    SessionExpiry, StepSuccess, TokenSource, TokenTarget, DEFAULT_MANUAL_TTL, DEFAULT_RETRY_AFTER,
+    DEFAULT_STEP_TIMEOUT,
+};
+use super::injection::{InjectedCredential, RouteCredential};
+use super::json_login::{JsonLogin, TokenInjection, TokenLocation};
+use super::ntlm::NtlmCredentials;
+use super::oauth2::OAuth2Client;
+use super::policy::{normalize_url, Effect, Policy, PolicyRule};
+use super::portal::{
+    LdapConfig, OidcConfig, PortalConfig, UserCredentials, DEFAULT_SESSION_TTL, DEFAULT_USER_PREFIX,
+};
+use super::scope::{CredentialScope, ScopedCredentials};
+use super::secrets::resolve_secret;
+use super::sessions::CookieSettings;
+use super::totp::{decode_base32, Algorithm, Totp, DEFAULT_FIELD};
 use super::utils::clean_url;
 
-pub fn setup_basic(config: Value) -> Option<HashMap<String, ServerCredentials>> {
-    let mut map = HashMap::new();
-    let basic = match config["basic"].as_table() {
+// Shared credentials of a path, plus the `users` and `groups` subtables
+// holding per-identity ones. TOML tables don't keep their order, so group
+// entries are tried by their `priority` (lowest first, entries without one
+// last), then by name.
+fn user_credentials<T>(
+    path: &str,
+    info: &Value,
+    parse: impl Fn(&Value) -> Option<T>,
+) -> Option<UserCredentials<T>> {
+    let subtable = |key: &str| {
+        info.get(key)
+            .and_then(|table| table.as_table())
+            .map(|table| {
+                table
+                    .iter()
+                    .filter_map(|(name, info)| match parse(info) {
+                        Some(credential) => Some((name.clone(), credential)),
+                        None => {
+                            eprintln!("Error parsing {}.{} for path: {}", key, name, path);
+                            None
+                        }
+                    })
+                    .collect::<Vec<(String, T)>>()
+            })
+            .unwrap_or_default()
+    };
+
+    let priority = |group: &str| {
+        info.get("groups")
+            .and_then(|groups| groups.get(group))
+            .and_then(|info| info.get("priority"))
+            .and_then(|priority| priority.as_integer())
+            .unwrap_or(i64::MAX)
+    };
+    let mut groups = subtable("groups");
+    groups.sort_by_cached_key(|(group, _)| (priority(group), group.clone()));
+
+    let credentials = UserCredentials {
+        users: subtable("users").into_iter().collect(),
+        groups,
+        shared: parse(info),
+    };
+    if credentials.is_empty() {
+        eprintln!("No credentials configured for path: {}", path);
+        return None;
+    }
+    Some(credentials)
+}
+
+fn basic_credential(auth_info: &Value) -> Option<ServerCredentials> {
+    let username = auth_info.get("username")?.as_str()?;
+    let password = auth_info.get("password")?.as_str()?;
+
+    Some(ServerCredentials::new(username, password))
+}
+
+pub fn setup_basic(config: Value) -> Option<ScopedCredentials<UserCredentials<ServerCredentials>>> {
+    let mut scoped = Vec::new();
+    let basic = match config.get("basic").and_then(|basic| basic.as_table()) {
         Some(basic) => basic,
         None => {
             eprintln!("Error parsing the [basic] structure");
@@ -21,36 +101,624 @@ pub fn setup_basic(config: Value) -> Option<HashMap<String, ServerCredentials>>
     };
 
     for (path, auth_info) in basic {
-        let realm = auth_info["realm"].as_str()?;
-        let username = auth_info["username"].as_str()?;
-        let password = auth_info["password"].as_str()?;
+        let realm = auth_info.get("realm").and_then(|realm| realm.as_str());
 
-        let credential = ServerCredentials::new(realm, username, password);
-        map.insert(path.to_string(), credential);
+        if let Some(scope) = CredentialScope::parse(path, realm) {
+            if let Some(credentials) = user_credentials(path, auth_info, basic_credential) {
+                scoped.push((scope, credentials));
+            }
+        }
     }
 
-    Some(map)
+    Some(scoped)
+}
+
+pub fn setup_ntlm(config: Value) -> Option<ScopedCredentials<NtlmCredentials>> {
+    let mut scoped = Vec::new();
+    let ntlm = config.get("ntlm").and_then(|ntlm| ntlm.as_table())?;
+
+    for (path, auth_info) in ntlm {
+        let field = |key: &str| match auth_info.get(key).and_then(Value::as_str) {
+            Some(value) => Some(value),
+            None => {
+                eprintln!("Error parsing {} for path: {}", key, path);
+                None
+            }
+        };
+        let (domain, username, password) =
+            match (field("domain"), field("username"), field("password")) {
+                (Some(domain), Some(username), Some(password)) => (domain, username, password),
+                _ => continue,
+            };
+        let workstation = auth_info
+            .get("workstation")
+            .and_then(Value::as_str)
+            .unwrap_or("");
+
+        if let Some(scope) = CredentialScope::parse(path, None) {
+            let credential = NtlmCredentials::new(domain, username, password, workstation);
+            scoped.push((scope, credential));
+        }
+    }
+
+    Some(scoped)
+}
+
+fn route_credential(path: &str, credential_info: &Value) -> Option<RouteCredential> {
+    let field = |key: &str| credential_info.get(key).and_then(|value| value.as_str());
+    let secret = |key: &str| match field(key).and_then(resolve_secret) {
+        Some(secret) => Some(secret),
+        None => {
+            eprintln!("Error parsing {} for path: {}", key, path);
+            None
+        }
+    };
+
+    match field("type").unwrap_or("bearer") {
+        "oauth2" => Some(RouteCredential::OAuth2(OAuth2Client {
+            token_url: field("token_url")?.to_string(),
+            client_id: field("client_id")?.to_string(),
+            client_secret: secret("client_secret")?,
+            scope: field("scope").map(|scope| scope.to_string()),
+            audience: field("audience").map(|audience| audience.to_string()),
+        })),
+        kind => InjectedCredential::new(kind, field("name"), &secret("secret")?)
+            .map(RouteCredential::Static),
+    }
+}
+
+pub fn setup_credentials(config: Value) -> Option<ScopedCredentials<RouteCredential>> {
+    let mut scoped = Vec::new();
+    let credentials = config
+        .get("credentials")
+        .and_then(|credentials| credentials.as_table())?;
+
+    for (path, credential_info) in credentials {
+        if let Some(scope) = CredentialScope::parse(path, None) {
+            if let Some(credential) = route_credential(path, credential_info) {
+                scoped.push((scope, credential));
+            }
+        }
+    }
+
+    Some(scoped)
+}
+
+// Route settings of [json_login."url"], the other strings are credential fields
+const JSON_LOGIN_KEYS: [&str; 8] = [
+    "endpoint",
+    "method",
+    "body",
+    "token_pointer",
+    "token_header",
+    "header",
+    "header_format",
+    "cookie",
+];
+
+fn json_login_fields(path: &str, login_info: &Value) -> Option<FormFields> {
+    let mut fields = Vec::new();
+
+    for (key, value) in login_info.as_table()? {
+        if JSON_LOGIN_KEYS.contains(&key.as_str()) {
+            continue;
+        }
+        match value.as_str().map(resolve_secret) {
+            Some(Some(value)) => fields.push((key.to_string(), value)),
+            Some(None) => eprintln!("Error parsing {} for path: {}", key, path),
+            None => {}
+        }
+    }
+    match fields.is_empty() {
+        true => None,
+        false => Some(fields),
+    }
+}
+
+fn json_login(path: &str, login_info: &Value) -> Option<JsonLogin> {
+    let field = |key: &str| login_info.get(key).and_then(|value| value.as_str());
+    let invalid = |key: &str| eprintln!("Error parsing {} for path: {}", key, path);
+    let header = |key: &str, default: &str| {
+        let name = field(key).unwrap_or(default);
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| invalid(key))
+            .ok()
+    };
+
+    let endpoint = match field("endpoint") {
+        Some(endpoint) => endpoint.to_string(),
+        None => {
+            invalid("endpoint");
+            return None;
+        }
+    };
+    let method = match Method::from_bytes(field("method").unwrap_or("POST").as_bytes()) {
+        Ok(method) => method,
+        Err(_) => {
+            invalid("method");
+            return None;
+        }
+    };
+    // The body template is JSON text or a TOML table
+    let body = match login_info.get("body") {
+        Some(Value::String(body)) => serde_json::from_str(body).ok(),
+        Some(body @ Value::Table(_)) => serde_json::to_value(body).ok(),
+        _ => None,
+    };
+    let body = match body {
+        Some(body) => body,
+        None => {
+            invalid("body");
+            return None;
+        }
+    };
+    let token = match (field("token_pointer"), field("token_header")) {
+        (_, Some(_)) => TokenLocation::Header(header("token_header", "")?),
+        (pointer, None) => TokenLocation::Pointer(pointer.unwrap_or("/token").to_string()),
+    };
+    let injection = match field("cookie") {
+        Some(cookie) => TokenInjection::Cookie(cookie.to_string()),
+        None => TokenInjection::Header {
+            name: header("header", "Authorization")?,
+            format: field("header_format")
+                .unwrap_or("Bearer {token}")
+                .to_string(),
+        },
+    };
+    let ttl = login_info
+        .get("ttl")
+        .and_then(|ttl| ttl.as_integer())
+        .and_then(|ttl| u64::try_from(ttl).ok())
+        .unwrap_or(3600);
+
+    Some(JsonLogin {
+        endpoint,
+        method,
+        body,
+        token,
+        injection,
+        ttl: Duration::from_secs(ttl),
+        credentials: user_credentials(path, login_info, |info| json_login_fields(path, info))?,
+    })
+}
+
+pub fn setup_json_login(config: Value) -> Option<ScopedCredentials<JsonLogin>> {
+    let mut scoped = Vec::new();
+    let logins = config
+        .get("json_login")
+        .and_then(|logins| logins.as_table())?;
+
+    for (path, login_info) in logins {
+        if let Some(scope) = CredentialScope::parse(path, None) {
+            if let Some(login) = json_login(path, login_info) {
+                scoped.push((scope, login));
+            }
+        }
+    }
+
+    Some(scoped)
+}
+
+fn form_fields(path: &str, form_info: &Value) -> Option<FormFields> {
+    let mut fields = Vec::new();
+
+    for (key, value) in form_info.as_table()? {
+        if let Some(inner_value) = value.as_str() {
+            fields.push((key.to_string(), inner_value.to_string()));
+        } else if !value.is_table() && !value.is_array() {
+            eprintln!("Error parsing {} for path: {}", key, path);
+        }
+    }
+    match fields.is_empty() {
+        true => None,
+        false => Some(fields),
+    }
+}
+
+fn form_rules(path: &str, form_info: &Value) -> Option<FormRules> {
+    let rules = match form_info.get("rules") {
+        Some(rules) => rules,
+        None => return Some(FormRules::default()),
+    };
+    let field = |key: &str| rules.get(key).and_then(|value| value.as_str());
+    let invalid = |key: &str| eprintln!("Error parsing rules.{} for path: {}", key, path);
+    let selector = |key: &str| match field(key) {
+        Some(selector) => match Selector::parse(selector) {
+            Ok(selector) => Ok(Some(selector)),
+            Err(_) => {
+                invalid(key);
+                Err(())
+            }
+        },
+        None => Ok(None),
+    };
+
+    let login_urls = rules.get("login_urls").map(string_list).unwrap_or_default();
+    let detect = match (selector("detect").ok()?, login_urls.is_empty()) {
+        (Some(detect), _) => Some(detect),
+        (None, true) => FormRules::default().detect,
+        (None, false) => None,
+    };
+    let action_pattern = match field("action_pattern").map(Regex::new) {
+        Some(Ok(pattern)) => Some(pattern),
+        Some(Err(_)) => {
+            invalid("action_pattern");
+            return None;
+        }
+        None => None,
+    };
+
+    Some(FormRules {
+        login_urls,
+        detect,
+        selector: selector("selector").ok()?,
+        form_id: field("form_id").map(|form_id| form_id.to_string()),
+        action_pattern,
+        required_fields: rules
+            .get("required_fields")
+            .map(string_list)
+            .unwrap_or_default(),
+    })
+}
+
+// Absent is Ok(None), broken is Err
+fn form_csrf(path: &str, form_info: &Value) -> Result<Option<CsrfStrategy>, ()> {
+    let csrf = match form_info.get("csrf") {
+        Some(csrf) => csrf,
+        None => return Ok(None),
+    };
+    let field = |key: &str| csrf.get(key).and_then(|value| value.as_str());
+    let invalid = |key: &str| eprintln!("Error parsing csrf.{} for path: {}", key, path);
+    let header = |key: &str| {
+        let name = field(key).unwrap_or("X-CSRF-Token");
+        HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid(key))
+    };
+    let name = |default: &str| field("name").unwrap_or(default).to_string();
+
+    let source = match field("source").unwrap_or("meta") {
+        "meta" => TokenSource::Meta(name("csrf-token")),
+        "input" => TokenSource::Input(name("csrf_token")),
+        "cookie" => TokenSource::Cookie(name("XSRF-TOKEN")),
+        "header" => TokenSource::Header(header("name")?),
+        _ => {
+            invalid("source");
+            return Err(());
+        }
+    };
+    let send_as = match source {
+        TokenSource::Meta(_) | TokenSource::Input(_) => "field",
+        TokenSource::Cookie(_) | TokenSource::Header(_) => "header",
+    };
+    let target = match field("send_as").unwrap_or(send_as) {
+        "field" => TokenTarget::Field(field("field").map(|field| field.to_string())),
+        "header" => TokenTarget::Header(header("header")?),
+        _ => {
+            invalid("send_as");
+            return Err(());
+        }
+    };
+
+    Ok(Some(CsrfStrategy { source, target }))
+}
+
+fn string_table(value: Option<&Value>) -> Vec<(String, String)> {
+    value
+        .and_then(|table| table.as_table())
+        .map(|table| {
+            table
+                .iter()
+                .filter_map(|(key, value)| Some((key.clone(), value.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// A status code or a list of them
+fn status_list(value: Option<&Value>) -> Vec<u16> {
+    let status = match value {
+        Some(Value::Integer(status)) => vec![*status],
+        Some(Value::Array(statuses)) => statuses.iter().filter_map(Value::as_integer).collect(),
+        _ => Vec::new(),
+    };
+
+    status
+        .into_iter()
+        .filter_map(|status| u16::try_from(status).ok())
+        .collect()
+}
+
+fn step_success(path: &str, index: usize, success: Option<&Value>) -> Option<StepSuccess> {
+    let success = match success {
+        Some(success) => success,
+        None => return Some(StepSuccess::default()),
+    };
+    let field = |key: &str| success.get(key).and_then(|value| value.as_str());
+    let selector = |key: &str| match field(key).map(Selector::parse) {
+        Some(Ok(selector)) => Ok(Some(selector)),
+        Some(Err(_)) => {
+            eprintln!(
+                "Error parsing steps.{}.success.{} for path: {}",
+                index, key, path
+            );
+            Err(())
+        }
+        None => Ok(None),
+    };
+
+    Some(StepSuccess {
+        status: status_list(success.get("status")),
+        contains: field("contains").map(|text| text.to_string()),
+        present: selector("present").ok()?,
+        absent: selector("absent").ok()?,
+    })
+}
+
+// Absent is an empty list, broken is None
+fn form_steps(path: &str, form_info: &Value) -> Option<Vec<LoginStep>> {
+    let steps = match form_info.get("steps") {
+        Some(steps) => steps.as_array()?,
+        None => return Some(Vec::new()),
+    };
+    let mut parsed = Vec::new();
+
+    for (index, step) in steps.iter().enumerate() {
+        let invalid =
+            |key: &str| eprintln!("Error parsing steps.{}.{} for path: {}", index, key, path);
+        let field = |key: &str| step.get(key).and_then(|value| value.as_str());
+
+        let selector = match field("selector").map(Selector::parse) {
+            Some(Ok(selector)) => Some(selector),
+            Some(Err(_)) => {
+                invalid("selector");
+                return None;
+            }
+            None => None,
+        };
+        let mut extract = Vec::new();
+        for (name, spec) in string_table(step.get("extract")) {
+            match Extraction::parse(&spec) {
+                Some(extraction) => extract.push((name, extraction)),
+                None => {
+                    invalid(&format!("extract.{}", name));
+                    return None;
+                }
+            }
+        }
+        let timeout = step
+            .get("timeout")
+            .and_then(|timeout| timeout.as_integer())
+            .and_then(|timeout| u64::try_from(timeout).ok())
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_STEP_TIMEOUT);
+
+        parsed.push(LoginStep {
+            url: field("url").map(|url| url.to_string()),
+            selector,
+            fields: string_table(step.get("fields")),
+            extract,
+            success: step_success(path, index, step.get("success"))?,
+            timeout,
+        });
+    }
+    Some(parsed)
+}
+
+pub fn totp(path: &str, totp_info: &Value) -> Result<Totp, ()> {
+    let field = |key: &str| totp_info.get(key);
+    let invalid = |key: &str| eprintln!("Error parsing totp.{} for path: {}", key, path);
+
+    let seed = field("secret")
+        .and_then(|secret| secret.as_str())
+        .and_then(resolve_secret)
+        .and_then(|secret| decode_base32(&secret))
+        .filter(|seed| !seed.is_empty());
+    let digits = match field("digits").map(|digits| digits.as_integer()) {
+        None => Some(6),
+        Some(Some(digits @ 6..=10)) => Some(digits as u32),
+        Some(_) => None,
+    };
+    let period = match field("period").map(|period| period.as_integer()) {
+        None => Some(30),
+        Some(Some(period)) if period > 0 => Some(period as u64),
+        Some(_) => None,
+    };
+    let algorithm = match field("algorithm").map(|algorithm| algorithm.as_str()) {
+        None => Some(Algorithm::Sha1),
+        Some(algorithm) => algorithm.and_then(Algorithm::parse),
+    };
+    let skew = match field("skew") {
+        None => Some(0),
+        Some(skew) => skew.as_integer(),
+    };
+    let name = match field("field") {
+        None => Some(DEFAULT_FIELD),
+        Some(name) => name.as_str(),
+    };
+
+    let parsed = [
+        ("secret", seed.is_some()),
+        ("digits", digits.is_some()),
+        ("period", period.is_some()),
+        ("algorithm", algorithm.is_some()),
+        ("skew", skew.is_some()),
+        ("field", name.is_some()),
+    ];
+    if let Some((key, _)) = parsed.iter().find(|(_, ok)| !ok) {
+        invalid(key);
+        return Err(());
+    }
+    Ok(Totp {
+        seed: seed.unwrap_or_default(),
+        digits: digits.unwrap_or(6),
+        period: period.unwrap_or(30),
+        algorithm: algorithm.unwrap_or(Algorithm::Sha1),
+        skew: skew.unwrap_or(0),
+        field: name.unwrap_or(DEFAULT_FIELD).to_string(),
+    })
+}
+
+// The `totp` tables of a form route, its `users` and its `groups`
+fn form_totp(path: &str, form_info: &Value) -> Result<Option<UserCredentials<Totp>>, ()> {
+    let parse = |info: &Value| {
+        info.get("totp")
+            .map(|totp_info| totp(path, totp_info))
+            .transpose()
+    };
+    let subtable = |key: &str| -> Result<Vec<(String, Totp)>, ()> {
+        let mut seeds = Vec::new();
+        let table = form_info.get(key).and_then(|table| table.as_table());
+        for (name, info) in table.into_iter().flatten() {
+            if let Some(seed) = parse(info)? {
+                seeds.push((name.clone(), seed));
+            }
+        }
+        Ok(seeds)
+    };
+
+    let seeds = UserCredentials {
+        users: subtable("users")?.into_iter().collect(),
+        groups: subtable("groups")?,
+        shared: parse(form_info)?,
+    };
+    match seeds.is_empty() {
+        true => Ok(None),
+        false => Ok(Some(seeds)),
+    }
 }
 
-pub fn setup_form(config: Value) -> Option<HashMap<String, Vec<(String, String)>>> {
+fn form_success(path: &str, form_info: &Value) -> Option<LoginSuccess> {
+    let success = match form_info.get("success") {
+        Some(success) => success,
+        None => return Some(LoginSuccess::default()),
+    };
+    let invalid = |key: &str| eprintln!("Error parsing success.{} for path: {}", key, path);
+    let field = |key: &str| success.get(key).and_then(|value| value.as_str());
+    let pattern = |key: &str| match field(key).map(Regex::new) {
+        Some(Ok(pattern)) => Ok(Some(pattern)),
+        Some(Err(_)) => {
+            invalid(key);
+            Err(())
+        }
+        None => Ok(None),
+    };
+    let retry_after = match success.get("retry_after").map(|value| value.as_integer()) {
+        None => DEFAULT_RETRY_AFTER,
+        Some(Some(seconds)) if seconds >= 0 => Duration::from_secs(seconds as u64),
+        Some(_) => {
+            invalid("retry_after");
+            return None;
+        }
+    };
+
+    Some(LoginSuccess {
+        status: status_list(success.get("status")),
+        redirect: pattern("redirect").ok()?,
+        cookie: field("cookie").map(|cookie| cookie.to_string()),
+        present: pattern("present").ok()?,
+        absent: pattern("absent").ok()?,
+        retry_after,
+    })
+}
+
+// Absent is None, broken is Err
+fn form_expiry(path: &str, form_info: &Value) -> Result<Option<SessionExpiry>, ()> {
+    let expiry = match form_info.get("expiry") {
+        Some(expiry) => expiry,
+        None => return Ok(None),
+    };
+    let invalid = |key: &str| eprintln!("Error parsing expiry.{} for path: {}", key, path);
+    let redirect = match expiry.get("redirect").map(|redirect| redirect.as_str()) {
+        None => None,
+        Some(Some(redirect)) => match Regex::new(redirect) {
+            Ok(redirect) => Some(redirect),
+            Err(_) => {
+                invalid("redirect");
+                return Err(());
+            }
+        },
+        Some(None) => {
+            invalid("redirect");
+            return Err(());
+        }
+    };
+    let login_page = match expiry.get("login_page") {
+        None => false,
+        Some(Value::Boolean(login_page)) => *login_page,
+        Some(_) => {
+            invalid("login_page");
+            return Err(());
+        }
+    };
+
+    Ok(Some(SessionExpiry {
+        status: status_list(expiry.get("status")),
+        redirect,
+        login_page,
+    }))
+}
+
+fn form_fallback(path: &str, form_info: &Value) -> Option<Fallback> {
+    let fallback = match form_info.get("fallback") {
+        Some(fallback) => fallback,
+        None => return Some(Fallback::default()),
+    };
+    let invalid = |key: &str| eprintln!("Error parsing fallback.{} for path: {}", key, path);
+    let ttl = match fallback.get("ttl").map(|ttl| ttl.as_integer()) {
+        None => DEFAULT_MANUAL_TTL,
+        Some(Some(seconds)) if seconds > 0 => Duration::from_secs(seconds as u64),
+        Some(_) => {
+            invalid("ttl");
+            return None;
+        }
+    };
+
+    match fallback.get("mode").and_then(|mode| mode.as_str()) {
+        None | Some("error") => Some(Fallback::Error),
+        Some("manual") => Some(Fallback::Manual { ttl }),
+        Some(_) => {
+            invalid("mode");
+            None
+        }
+    }
+}
+
+pub fn setup_form(config: Value) -> Option<HashMap<String, FormRoute>> {
     let mut map = HashMap::new();
 
-    match config["form"].as_table() {
+    match config.get("form").and_then(|form| form.as_table()) {
         Some(form_table) => {
             for (path, form_info) in form_table {
-                if let Some(form_info_table) = form_info.as_table() {
-                    let mut inner_vec = Vec::new();
-
-                    for (key, value) in form_info_table {
-                        if let Some(inner_value) = value.as_str() {
-                            inner_vec.push((key.to_string(), inner_value.to_string()));
-                        } else {
-                            eprintln!("Error parsing {} for path: {}", key, path);
-                            continue;
-                        }
-                    }
-                    let path = &clean_url(path);
-                    map.insert(path.clone(), inner_vec);
+                let fields = user_credentials(path, form_info, |info| form_fields(path, info));
+                let rules = form_rules(path, form_info);
+                let csrf = form_csrf(path, form_info);
+                let steps = form_steps(path, form_info);
+                let totp = form_totp(path, form_info);
+                let success = form_success(path, form_info);
+                let expiry = form_expiry(path, form_info);
+                let fallback = form_fallback(path, form_info);
+                // A broken rule disables the route rather than widening it
+                if let (
+                    Some(credentials),
+                    Some(rules),
+                    Ok(csrf),
+                    Some(steps),
+                    Ok(totp),
+                    Some(success),
+                    Ok(expiry),
+                    Some(fallback),
+                ) = (fields, rules, csrf, steps, totp, success, expiry, fallback)
+                {
+                    let route = FormRoute {
+                        credentials,
+                        rules,
+                        csrf,
+                        steps,
+                        totp,
+                        success,
+                        expiry,
+                        fallback,
+                    };
+                    map.insert(clean_url(path), route);
                 }
             }
         }
@@ -70,7 +738,10 @@ pub fn setup_form(config: Value) -> Option<HashMap<String, Vec<(String, String)>
 }
 
 pub fn setup_servers(config: Value) -> Option<HashMap<String, String>> {
-    let redirections = match config["redirections"].as_table() {
+    let redirections = match config
+        .get("redirections")
+        .and_then(|redirections| redirections.as_table())
+    {
         Some(servers) => servers,
         None => {
             eprintln!("Error parsing the [redirections] structure");
@@ -95,6 +766,358 @@ pub fn setup_servers(config: Value) -> Option<HashMap<String, String>> {
     Some(map)
 }
 
+pub fn setup_buffering(config: Value) -> BufferLimits {
+    let mut limits = BufferLimits::default();
+    let buffering = match config
+        .get("buffering")
+        .and_then(|buffering| buffering.as_table())
+    {
+        Some(buffering) => buffering,
+        None => return limits,
+    };
+
+    for (key, value) in buffering {
+        let size = match value.as_integer() {
+            Some(size) if size >= 0 => size as u64,
+            _ => {
+                eprintln!("Error parsing {} in the [buffering] structure", key);
+                continue;
+            }
+        };
+        match key.as_str() {
+            "memory_limit" => limits.memory_limit = size,
+            "max_body_size" => limits.max_body_size = size,
+            _ => eprintln!("Unknown key {} in the [buffering] structure", key),
+        }
+    }
+
+    limits
+}
+
+pub fn setup_cookies(config: Value) -> CookieSettings {
+    let mut settings = CookieSettings::default();
+    let cookies = match config.get("cookies").and_then(|cookies| cookies.as_table()) {
+        Some(cookies) => cookies,
+        None => return settings,
+    };
+
+    for (key, value) in cookies {
+        match (key.as_str(), value) {
+            ("jar", Value::Boolean(jar)) => settings.jar = *jar,
+            ("jar_idle", Value::Integer(idle)) if *idle > 0 => {
+                settings.jar_idle = Duration::from_secs(*idle as u64)
+            }
+            ("jar_max", Value::Integer(max)) if *max > 0 => settings.jar_max = *max as usize,
+            ("replace", Value::Boolean(replace)) => settings.replace = *replace,
+            ("replace_all", Value::Boolean(all)) => settings.replace_all = *all,
+            ("jar" | "jar_idle" | "jar_max" | "replace" | "replace_all", _) => {
+                eprintln!("Error parsing {} in the [cookies] structure", key)
+            }
+            _ => eprintln!("Unknown key {} in the [cookies] structure", key),
+        }
+    }
+
+    settings
+}
+
+fn string_list(value: &Value) -> Vec<String> {
+    value
+        .as_array()
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str())
+                .map(|value| value.to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn setup_oidc(oidc: &Value) -> Option<OidcConfig> {
+    let field = |key: &str| oidc.get(key).and_then(|value| value.as_str());
+    let required = |key: &str| {
+        let value = field(key);
+        if value.is_none() {
+            eprintln!("Error parsing {} in the [portal.oidc] structure", key);
+        }
+        value.map(|value| value.to_string())
+    };
+
+    let issuer = required("issuer");
+    let client_id = required("client_id");
+    let redirect_uri = required("redirect_uri");
+    let client_secret = match field("client_secret") {
+        Some(reference) => Some(resolve_secret(reference)?),
+        None => None,
+    };
+
+    Some(OidcConfig {
+        issuer: issuer?,
+        client_id: client_id?,
+        client_secret,
+        redirect_uri: redirect_uri?,
+        scopes: field("scopes").unwrap_or("openid profile").to_string(),
+        username_claim: field("username_claim")
+            .unwrap_or("preferred_username")
+            .to_string(),
+        groups_claim: field("groups_claim").unwrap_or("groups").to_string(),
+        user_prefix: field("user_prefix")
+            .unwrap_or(DEFAULT_USER_PREFIX)
+            .to_string(),
+    })
+}
+
+fn setup_ldap(ldap: &Value) -> Option<LdapConfig> {
+    let field = |key: &str| ldap.get(key).and_then(|value| value.as_str());
+    let integer = |key: &str, default: u64| match ldap.get(key).map(|value| value.as_integer()) {
+        Some(Some(value)) if value > 0 => value as u64,
+        Some(_) => {
+            eprintln!("Error parsing {} in the [portal.ldap] structure", key);
+            default
+        }
+        None => default,
+    };
+
+    let (url, base_dn) = match (field("url"), field("base_dn")) {
+        (Some(url), Some(base_dn)) => (url.to_string(), base_dn.to_string()),
+        _ => {
+            eprintln!("url and base_dn are required in the [portal.ldap] structure");
+            return None;
+        }
+    };
+    let bind_password = match field("bind_password") {
+        Some(reference) => Some(resolve_secret(reference)?),
+        None => None,
+    };
+
+    Some(LdapConfig {
+        group_base_dn: field("group_base_dn").unwrap_or(&base_dn).to_string(),
+        url,
+        bind_dn: field("bind_dn").map(|bind_dn| bind_dn.to_string()),
+        bind_password,
+        base_dn,
+        user_filter: field("user_filter")
+            .unwrap_or("(uid={username})")
+            .to_string(),
+        group_filter: field("group_filter").unwrap_or("(member={dn})").to_string(),
+        group_attribute: field("group_attribute").unwrap_or("cn").to_string(),
+        starttls: ldap
+            .get("starttls")
+            .and_then(|starttls| starttls.as_bool())
+            .unwrap_or(false),
+        pool_size: integer("pool_size", 4) as usize,
+        timeout: Duration::from_secs(integer("timeout", 5)),
+    })
+}
+
+pub fn setup_portal(config: Value) -> Option<PortalConfig> {
+    let portal = config.get("portal")?.as_table()?;
+
+    let users_file = portal
+        .get("users_file")
+        .and_then(|users_file| users_file.as_str())
+        .map(|users_file| users_file.to_string());
+    let oidc = portal.get("oidc").and_then(setup_oidc);
+    let ldap = portal.get("ldap").and_then(setup_ldap);
+    if users_file.is_none() && oidc.is_none() && ldap.is_none() {
+        eprintln!(
+            "No users_file, oidc nor ldap in the [portal] structure, every login will be refused"
+        );
+    }
+    let session_ttl = match portal.get("session_ttl").map(|ttl| ttl.as_integer()) {
+        Some(Some(ttl)) if ttl > 0 => ttl as u64,
+        Some(_) => {
+            eprintln!("Error parsing session_ttl in the [portal] structure");
+            DEFAULT_SESSION_TTL
+        }
+        None => DEFAULT_SESSION_TTL,
+    };
+    let public = portal.get("public").map(string_list).unwrap_or_default();
+    let groups = portal
+        .get("groups")
+        .and_then(|groups| groups.as_table())
+        .map(|groups| {
+            groups
+                .iter()
+                .map(|(group, members)| (group.clone(), string_list(members)))
+                .collect()
+        })
+        .unwrap_or_default();
+
+    Some(PortalConfig {
+        users_file,
+        session_ttl,
+        public,
+        groups,
+        oidc,
+        ldap,
+    })
+}
+
+fn policy_rule(index: usize, rule: &Value) -> Option<PolicyRule> {
+    let list = |key: &str| rule.get(key).map(string_list).unwrap_or_default();
+    let route = match rule.get("route").and_then(|route| route.as_str()) {
+        Some(route) => match normalize_url(route) {
+            Some(route) => CredentialScope::parse(route.as_str(), None)?,
+            None => CredentialScope::parse(route, None)?,
+        },
+        None => {
+            eprintln!("No route in the rule {} of the [policy] structure", index);
+            return None;
+        }
+    };
+    let effect = rule
+        .get("effect")
+        .and_then(|effect| effect.as_str())
+        .unwrap_or("allow");
+    let effect = match Effect::parse(effect) {
+        Some(effect) => effect,
+        None => {
+            eprintln!(
+                "Unknown effect {} in the rule {} of the [policy] structure",
+                effect, index
+            );
+            return None;
+        }
+    };
+
+    Some(PolicyRule {
+        route,
+        users: list("users"),
+        groups: list("groups"),
+        methods: list("methods"),
+        paths: list("paths"),
+        effect,
+    })
+}
+
+pub fn setup_policy(config: Value) -> Option<Policy> {
+    let policy = config.get("policy")?.as_table()?;
+    let mut rules = Vec::new();
+
+    if let Some(configured) = policy.get("rules").and_then(|rules| rules.as_array()) {
+        for (index, rule) in configured.iter().enumerate() {
+            match policy_rule(index, rule) {
+                Some(rule) => rules.push(rule),
+                // A broken rule could be a deny rule, refuse everything rather than skip it
+                None => {
+                    return Some(Policy {
+                        rules: Vec::new(),
+                        default: Effect::Deny,
+                    })
+                }
+            }
+        }
+    }
+    let default = match policy.get("default").and_then(|default| default.as_str()) {
+        Some(default) => match Effect::parse(default) {
+            Some(default) => default,
+            None => {
+                eprintln!("Unknown default {} in the [policy] structure", default);
+                Effect::Deny
+            }
+        },
+        None => Effect::Deny,
+    };
+
+    Some(Policy { rules, default })
+}
+
+/// A broken entry could be the one guarding a route, so it fails the whole
+/// [[restricted]] set rather than being skipped.
+pub fn setup_restricted(config: Value) -> Result<Vec<RestrictedRoute>, ()> {
+    let mut restricted = Vec::new();
+    let entries = match config
+        .get("restricted")
+        .and_then(|entries| entries.as_array())
+    {
+        Some(entries) => entries,
+        None => return Ok(restricted),
+    };
+
+    for (index, entry) in entries.iter().enumerate() {
+        let key = match entry.get("route").and_then(|route| route.as_str()) {
+            Some(key) => key,
+            None => {
+                eprintln!("No route in the [[restricted]] entry {}", index);
+                return Err(());
+            }
+        };
+        let route = match CredentialScope::parse(key, None) {
+            Some(route) => route,
+            None => {
+                eprintln!(
+                    "Error parsing the route of the [[restricted]] entry {}",
+                    index
+                );
+                return Err(());
+            }
+        };
+        let timezone = entry
+            .get("timezone")
+            .and_then(|timezone| timezone.as_str())
+            .unwrap_or("UTC");
+        // Misconfigured windows keep the route closed rather than open
+        let mut windows: Vec<TimeWindow> = entry
+            .get("windows")
+            .map(string_list)
+            .unwrap_or_default()
+            .iter()
+            .map(|window| {
+                TimeWindow::parse(window).unwrap_or_else(|| {
+                    eprintln!("Invalid window {} for route: {}", window, key);
+                    TimeWindow::closed()
+                })
+            })
+            .collect();
+        let timezone = match timezone.parse() {
+            Ok(timezone) => timezone,
+            Err(_) => {
+                eprintln!("Unknown timezone {} for route: {}", timezone, key);
+                windows = vec![TimeWindow::closed()];
+                chrono_tz::UTC
+            }
+        };
+
+        restricted.push(RestrictedRoute {
+            key: key.to_string(),
+            route,
+            timezone,
+            windows,
+            approval: entry
+                .get("approval")
+                .and_then(|approval| approval.as_bool())
+                .unwrap_or(false),
+        });
+    }
+
+    Ok(restricted)
+}
+
+pub fn setup_admin(config: Value) -> Option<AdminConfig> {
+    let admin = config.get("admin")?.as_table()?;
+    let groups = admin.get("groups").map(string_list).unwrap_or_default();
+    if groups.is_empty() {
+        eprintln!("No groups in the [admin] structure, the admin API is closed to everyone");
+    }
+
+    Some(AdminConfig {
+        groups,
+        max_grant_minutes: admin
+            .get("max_grant_minutes")
+            .and_then(|max| max.as_integer())
+            .unwrap_or(8 * 60),
+    })
+}
+
+pub fn setup_audit(config: &Value) -> Option<String> {
+    config
+        .get("audit")?
+        .get("file")?
+        .as_str()
+        .map(|file| file.to_string())
+}
+
 pub fn define_conf(filename: &str) -> Result<Value, Box<dyn Error>> {
     let toml_script = match File::open(filename) {
         Ok(file) => {
@@ -6,20 +6,20 @@ use std::str;
 
 #[derive(Debug, PartialEq, Hash)]
 pub struct SetCookie<'a> {
-    name: &'a [u8],
-    value: &'a [u8],
-    attrs: SetCookieAttributes<'a>,
+    pub name: &'a [u8],
+    pub value: &'a [u8],
+    pub attrs: SetCookieAttributes<'a>,
 }
 
 #[derive(Debug, PartialEq, Hash)]
 pub struct SetCookieAttributes<'a> {
-    max_age: Option<u32>,
-    domain: &'a [u8],
-    path: &'a [u8],
-    same_site: SameSite,
-    secure: bool,
-    http_only: bool,
-    partitioned: bool,
+    pub max_age: Option<u32>,
+    pub domain: &'a [u8],
+    pub path: &'a [u8],
+    pub same_site: SameSite,
+    pub secure: bool,
+    pub http_only: bool,
+    pub partitioned: bool,
 }
 
 #[derive(Debug, PartialEq, Hash)]
@@ -60,7 +60,7 @@ pub fn read_cookies<'a>(data: &'a [u8]) -> CookieIterator<'a> {
 ///     println!("name={} value={} max-age={}", name, value, max_age);
 /// }
 /// ```
-pub fn parse_set_cookie<'a>(data: &'a [u8], timestamp: u64) -> Option<SetCookie> {
+pub fn parse_set_cookie(data: &[u8], timestamp: u64) -> Option<SetCookie<'_>> {
     if let Some((name, rest)) = SetCookie::parse_name(data) {
         if let Some((value, attrs)) = SetCookie::parse_value(rest) {
             let attrs = parse_set_cookie_attribute(attrs, timestamp);
@@ -86,7 +86,7 @@ impl<'a> SetCookie<'a> {
                 return Some((name, rest));
             }
         }
-        return None;
+        None
     }
 
     fn parse_value(data: &'a [u8]) -> Option<(&'a [u8], &'a [u8])> {
@@ -96,7 +96,7 @@ impl<'a> SetCookie<'a> {
             return Some((value, attrs));
         }
         let value = rtrim(data);
-        return Some((value, to_end_slice(data)));
+        Some((value, to_end_slice(data)))
     }
 }
 
@@ -149,7 +149,7 @@ impl<'a> Default for SetCookieAttributes<'a> {
     }
 }
 
-fn parse_set_cookie_attribute<'a>(data: &'a [u8], timestamp: u64) -> SetCookieAttributes {
+fn parse_set_cookie_attribute(data: &[u8], timestamp: u64) -> SetCookieAttributes<'_> {
     let mut attrs: SetCookieAttributes = Default::default();
     let mut buffer = [0u8; 12];
     let mut has_max_age = false;
@@ -162,7 +162,7 @@ fn parse_set_cookie_attribute<'a>(data: &'a [u8], timestamp: u64) -> SetCookieAt
             match ltrim(value) {
                 [b'"', rest @ .., b'"'] => (rtrim(name), ltrim(rest)),
                 [b'"', ..] => break,
-                [rest @ ..] => (rtrim(name), ltrim(rest)),
+                rest => (rtrim(name), ltrim(rest)),
             }
         } else {
             (part, to_end_slice(part))
@@ -185,10 +185,11 @@ fn parse_set_cookie_attribute<'a>(data: &'a [u8], timestamp: u64) -> SetCookieAt
                 [b'.', rest @ ..] => attrs.domain = rest,
                 _ => attrs.domain = value,
             },
-            b"PATH" => match value {
-                [b'/', ..] => attrs.path = value,
-                _ => {}
-            },
+            b"PATH" => {
+                if let [b'/', ..] = value {
+                    attrs.path = value;
+                }
+            }
             b"SECURE" => attrs.secure = true,
             b"HTTPONLY" => attrs.http_only = true,
             b"PARTITIONED" => attrs.partitioned = true,
@@ -287,14 +288,14 @@ fn parse_max_age(max_age: &[u8]) -> Option<u32> {
     let (max_age, is_neg) = match max_age {
         [] | [b'-'] => return None,
         [b'-', rest @ ..] => (rest, true),
-        [rest @ ..] => (rest, false),
+        rest => (rest, false),
     };
 
     let mut delta = 0u32;
 
     for c in max_age {
         // invalid number
-        if c < &b'0' || &b'9' < c {
+        if !c.is_ascii_digit() {
             return None;
         }
 
@@ -312,7 +313,7 @@ fn parse_max_age(max_age: &[u8]) -> Option<u32> {
 }
 
 fn parse_expires(expires: &[u8], timestamp: u64) -> Option<u32> {
-    if let Ok(date) = str::from_utf8(&expires) {
+    if let Ok(date) = str::from_utf8(expires) {
         // Wed, 21 Oct 2015 07:28:00 GMT
         if let Ok(gmt_time) = DateTime::parse_from_rfc2822(date) {
             let utc_time = gmt_time.with_timezone(&Utc);
@@ -7,12 +7,15 @@ use crate::reverse_proxy::cookie::read_cookies;
 
 #[derive(Debug, PartialEq, Hash)]
 pub struct CookieReplacement {
-    // layout: name... '=' replacement... value...
-    //                  ^ isep            ^ ivalue
+    // layout: name... '=' value... replacement...
+    //                  ^ isep      ^ ireplacement
     // TODO replace with Allocator + allocation error checking
     buf: Vec<u8>,
     isep: usize,
-    ivalue: usize,
+    ireplacement: usize,
+    // Domain and path of the cookie the browser holds under `name`
+    domain: Vec<u8>,
+    path: Vec<u8>,
 }
 
 #[derive(Debug)]
@@ -22,39 +25,69 @@ pub struct CookieReplacementKey {
 
 pub type CookieReplacements = HashSet<CookieReplacementKey>;
 
+impl From<CookieReplacement> for CookieReplacementKey {
+    fn from(k: CookieReplacement) -> Self {
+        Self { k }
+    }
+}
+
+// Keys are the cookies as the browser sends them, several cookies of a
+// name each having their own value
 impl PartialEq for CookieReplacementKey {
     fn eq(&self, other: &Self) -> bool {
-        self.k.name() == other.k.name()
+        self.k.as_key_value_cookie() == other.k.as_key_value_cookie()
     }
 }
 impl Eq for CookieReplacementKey {}
 
 impl Hash for CookieReplacementKey {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.k.name().hash(state);
+        self.k.as_key_value_cookie().hash(state);
     }
 }
 
 impl Borrow<[u8]> for CookieReplacementKey {
     fn borrow(&self) -> &[u8] {
-        self.k.name()
+        self.k.as_key_value_cookie()
+    }
+}
+
+impl CookieReplacementKey {
+    /// Whether this replaces the cookie `name` the browser holds for
+    /// `domain` and `path`.
+    pub fn is_cookie(&self, name: &[u8], domain: &[u8], path: &[u8]) -> bool {
+        self.k.name() == name && self.k.domain == domain && self.k.path == path
     }
 }
 
 impl CookieReplacement {
-    fn new(name: &[u8], value: &[u8], replacement: &[u8]) -> Self {
+    pub fn new(name: &[u8], value: &[u8], replacement: &[u8]) -> Self {
         let isep = name.len();
-        let ivalue = isep + 1 + replacement.len();
-        let mut buf = Vec::with_capacity(ivalue + value.len());
+        let ireplacement = isep + 1 + value.len();
+        let mut buf = Vec::with_capacity(ireplacement + replacement.len());
         buf.extend_from_slice(name);
         buf.push(b'=');
-        buf.extend_from_slice(replacement);
         buf.extend_from_slice(value);
-        Self { buf, isep, ivalue }
+        buf.extend_from_slice(replacement);
+        Self {
+            buf,
+            isep,
+            ireplacement,
+            domain: Vec::new(),
+            path: Vec::new(),
+        }
+    }
+
+    /// Ties the replacement to the cookie the browser holds for `domain`
+    /// and `path`.
+    pub fn scoped(mut self, domain: &[u8], path: &[u8]) -> Self {
+        self.domain = domain.to_vec();
+        self.path = path.to_vec();
+        self
     }
 
     fn as_key_value_cookie(&self) -> &[u8] {
-        &self.buf.as_slice()[0..self.ivalue]
+        &self.buf.as_slice()[0..self.ireplacement]
     }
 
     fn name(&self) -> &[u8] {
@@ -62,11 +95,12 @@ impl CookieReplacement {
     }
 
     fn replacement(&self) -> &[u8] {
-        &self.buf.as_slice()[self.isep + 1..self.ivalue]
+        &self.buf.as_slice()[self.ireplacement..]
     }
 
+    #[cfg(test)]
     fn value(&self) -> &[u8] {
-        &self.buf.as_slice()[self.ivalue..]
+        &self.buf.as_slice()[self.isep + 1..self.ireplacement]
     }
 }
 
@@ -79,18 +113,29 @@ pub fn cookie_replacements<'a>(
     buffer.clear();
 
     let mut last_index = 0;
+    let mut cookie = Vec::new();
     let mut iter = read_cookies(cookies);
     while let Some((name, value)) = iter.next() {
-        if let Some(rep) = replacements.get(name) {
-            let start_index = ptr_offset(cookies, name);
-            buffer.extend_from_slice(&cookies[last_index..start_index]);
-            // skip cookie when value don't match
-            if value == rep.k.value() {
-                buffer.extend_from_slice(rep.k.as_key_value_cookie());
+        cookie.clear();
+        cookie.extend_from_slice(name);
+        cookie.push(b'=');
+        cookie.extend_from_slice(value);
+        let rep = replacements.get(cookie.as_slice());
+        if rep.is_none() && !replacements.iter().any(|rep| rep.k.name() == name) {
+            continue;
+        }
+
+        let start_index = ptr_offset(cookies, name);
+        buffer.extend_from_slice(&cookies[last_index..start_index]);
+        match rep {
+            Some(rep) => {
+                buffer.extend_from_slice(rep.k.name());
+                buffer.push(b'=');
+                buffer.extend_from_slice(rep.k.replacement());
                 last_index = ptr_offset(cookies, value) + value.len();
-            } else {
-                last_index = ptr_offset(cookies, iter.remaining());
             }
+            // skip cookie when value don't match
+            None => last_index = ptr_offset(cookies, iter.remaining()),
         }
     }
 
@@ -125,7 +170,7 @@ mod tests {
         });
 
         {
-            let ref rep = replacements.get(b"name2" as &[u8]).unwrap().k;
+            let rep = &replacements.get(b"name2=value2" as &[u8]).unwrap().k;
             assert_eq!(rep.name(), b"name2");
             assert_eq!(rep.value(), b"value2");
             assert_eq!(rep.replacement(), b"newvalue2!");
@@ -244,4 +289,33 @@ mod tests {
             "name1=value1; name2=newvalue2!; name2bis=value2; name3=value3;"
         );
     }
+
+    #[test]
+    fn test_cookie_replacements_per_path() {
+        let mut buffer = Vec::new();
+        let mut replacements = HashSet::new();
+        replacements.insert(CookieReplacementKey::from(
+            CookieReplacement::new(b"sid", b"token1", b"root").scoped(b"", b"/"),
+        ));
+        replacements.insert(CookieReplacementKey::from(
+            CookieReplacement::new(b"sid", b"token2", b"admin").scoped(b"", b"/admin"),
+        ));
+        assert_eq!(replacements.len(), 2);
+        assert!(replacements
+            .iter()
+            .any(|rep| rep.is_cookie(b"sid", b"", b"/admin")));
+        assert!(!replacements
+            .iter()
+            .any(|rep| rep.is_cookie(b"sid", b"app.example", b"/")));
+
+        assert_eq!(
+            std::str::from_utf8(cookie_replacements(
+                &mut buffer,
+                b"sid=token2; sid=token1",
+                &replacements
+            ))
+            .unwrap(),
+            "sid=admin; sid=root"
+        );
+    }
 }
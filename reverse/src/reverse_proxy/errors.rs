@@ -4,6 +4,8 @@ use std::error::Error;
 pub enum ProxyError {
     Hyper(hyper::Error),
     HyperHttp(hyper::http::Error),
+    Io(std::io::Error),
+    BodyTooLarge(u64),
 }
 
 impl From<hyper::Error> for ProxyError {
@@ -18,11 +20,19 @@ impl From<hyper::http::Error> for ProxyError {
     }
 }
 
+impl From<std::io::Error> for ProxyError {
+    fn from(e: std::io::Error) -> Self {
+        ProxyError::Io(e)
+    }
+}
+
 impl std::fmt::Display for ProxyError {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             ProxyError::Hyper(_) => write!(fmt, "Hyper: {:?}", self),
             ProxyError::HyperHttp(_) => write!(fmt, "HyperHttp: {:?}", self),
+            ProxyError::Io(_) => write!(fmt, "Io: {:?}", self),
+            ProxyError::BodyTooLarge(max) => write!(fmt, "Body larger than {} bytes", max),
         }
     }
 }
@@ -32,6 +42,8 @@ impl std::error::Error for ProxyError {
         match self {
             ProxyError::Hyper(e) => Some(e),
             ProxyError::HyperHttp(e) => Some(e),
+            ProxyError::Io(e) => Some(e),
+            ProxyError::BodyTooLarge(_) => None,
         }
     }
 }
@@ -1,228 +1,858 @@
-use hyper::{Body, Client, Response};
+use hyper::{
+    header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE},
+    Body, Client, Request, Response,
+};
 use hyper_tls::HttpsConnector;
 
-use scraper::{Html, Selector};
-use std::sync::Arc;
+use encoding_rs::Encoding;
+use once_cell::sync::Lazy;
+use scraper::Html;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::Instant,
+};
+use tokio::sync::Mutex as AsyncMutex;
 use toml::Value;
-
-use super::config::setup_form;
-
+use url::Url;
+
+use super::{
+    audit::{self, AuditEvent},
+    config::setup_form,
+    jar::{self, JarSession},
+    portal::{ProxyUser, UserCredentials},
+    sessions::process_session,
+    status::bad_gateway,
+    totp::Totp,
+    utils::clean_url,
+};
+
+mod action;
+mod csrf;
+mod encode;
+mod expiry;
+mod extract;
+mod fallback;
 mod post;
+mod rules;
+mod steps;
+mod success;
+
+pub use csrf::{CsrfStrategy, TokenSource, TokenTarget};
+pub use encode::Enctype;
+pub use expiry::{is_expired, SessionExpiry};
+pub use extract::{Form, ServerFormElements};
+pub use fallback::{active_manual_logins, Fallback, ManualLogin, DEFAULT_MANUAL_TTL};
+pub use rules::FormRules;
+pub use steps::{Extraction, LoginStep, StepSuccess, DEFAULT_STEP_TIMEOUT};
+pub use success::{LoginSuccess, DEFAULT_RETRY_AFTER};
+
+use encode::FormBody;
+use extract::form_base_elements;
+use post::LoginCookies;
+use steps::FetchedPage;
+
+/// Field names and values filled in a login form.
+pub type FormFields = Vec<(String, String)>;
+
+// Route and owner a relogin is for, anonymous browsers being told apart
+// by their jar
+type ReloginKey = (String, Option<LoginOwner>);
+
+// Set-Cookie headers of the last relogin, and when it happened
+type ReloginSlot = Arc<AsyncMutex<Option<(HeaderMap, Instant)>>>;
+
+// One slot per key, its lock makes concurrent relogins single-flight
+static RELOGINS: Lazy<Mutex<HashMap<ReloginKey, ReloginSlot>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Who a form login is for: the portal user, if any, and the jar of the
+/// browser, which is the proxy session telling anonymous ones apart.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Requester<'a> {
+    pub user: Option<&'a ProxyUser>,
+    pub jar: Option<&'a JarSession>,
+}
 
-#[derive(Debug, PartialEq)]
-pub struct Input {
-    id: String,
-    types: String,
-    pub name: String,
-    value: String,
+// Whose failed or handed over login it is
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum LoginOwner {
+    User(String),
+    Session(String),
 }
 
-#[derive(Debug, PartialEq)]
-pub struct Form {
-    id: String,
-    pub action: String,
-    method: String,
-    pub inputs: Vec<Input>,
+impl Requester<'_> {
+    // None for an anonymous browser without a proxy session
+    fn owner(&self) -> Option<LoginOwner> {
+        match (self.user, self.jar) {
+            (Some(user), _) => Some(LoginOwner::User(user.name.clone())),
+            (None, Some(jar)) => Some(LoginOwner::Session(jar.id.clone())),
+            (None, None) => None,
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct ServerFormElements {
-    pub forms: Vec<Form>,
+/// A [form."url"] route: the credentials to fill in, the rules deciding
+/// which page and form they go to, how its CSRF token travels, the TOTP
+/// seed of its second factor, what tells the login worked, what to do
+/// when it doesn't, how its upstream session expires and, for logins
+/// spread over several pages, its steps.
+#[derive(Debug, Clone)]
+pub struct FormRoute {
+    pub credentials: UserCredentials<FormFields>,
+    pub rules: FormRules,
+    pub csrf: Option<CsrfStrategy>,
+    pub steps: Vec<LoginStep>,
+    pub totp: Option<UserCredentials<Totp>>,
+    pub success: LoginSuccess,
+    pub expiry: Option<SessionExpiry>,
+    pub fallback: Fallback,
 }
 
-impl Input {
-    fn new(id: &str, types: &str, name: &str, value: &str) -> Self {
-        let mut type_def = String::from("text");
-        if types.is_empty() {
-            type_def = types.to_string();
-        }
+pub fn extract_form_elements(document: &Html) -> Option<ServerFormElements> {
+    let forms = form_base_elements(document);
 
-        Input {
-            id: id.to_string(),
-            types: type_def,
-            name: name.to_string(),
-            value: value.to_string(),
-        }
+    if !forms.is_empty() {
+        return Some(forms);
     }
+    None
 }
 
-impl Form {
-    fn new(id: &str, action: &str, method: &str) -> Self {
-        Form {
-            id: id.to_string(),
-            action: action.to_string(),
-            method: method.to_string(),
-            inputs: Vec::new(),
-        }
+impl FormRoute {
+    fn totp(&self, user: Option<&ProxyUser>) -> Option<&Totp> {
+        self.totp.as_ref().and_then(|totp| totp.resolve(user))
     }
 
-    fn add_input(&mut self, input: Input) {
-        self.inputs.push(input);
+    // The URLs of the route `key`, the only origins its login may go to
+    fn upstreams<'a>(&'a self, key: &'a str) -> Vec<&'a str> {
+        let mut upstreams = vec![key];
+        upstreams.extend(self.rules.login_urls.iter().map(String::as_str));
+        upstreams
     }
+}
 
-    fn _remove_input(&mut self, input: Input) {
-        if let Some(pos) = self.inputs.iter().position(|x| *x == input) {
-            self.inputs.remove(pos);
-        }
+/// Form route of `target_url`, with its key: the one configured for it or
+/// listing it in its `login_urls`.
+fn find_route(target_url: &str, config: Value) -> Option<(String, FormRoute)> {
+    let target = clean_url(target_url);
+
+    setup_form(config)?
+        .into_iter()
+        .find(|(key, route)| *key == target || route.rules.is_login_url(&target))
+}
+
+/// A page of the upstream the login goes through.
+struct LoginPage<'a> {
+    url: &'a str,
+    document: &'a Html,
+    headers: &'a HeaderMap,
+}
+
+/// A login form ready to be posted. Its fields stay apart until then, so
+/// a TOTP code goes in only once the form is known to be submittable.
+struct Submission {
+    action: Url,
+    fields: FormFields,
+    files: Vec<String>,
+    enctype: Enctype,
+    encoding: &'static Encoding,
+    headers: HeaderMap,
+}
+
+impl Submission {
+    fn has_field(&self, name: &str) -> bool {
+        self.fields.iter().any(|(field, _)| field == name)
+    }
+
+    fn body(&self) -> FormBody {
+        encode::encode(&self.fields, &self.files, self.enctype, self.encoding)
     }
 }
 
-impl ServerFormElements {
-    fn new() -> Self {
-        ServerFormElements { forms: Vec::new() }
+// Fills `form` of `page` with `fields` and the route's CSRF token, once
+// sure it posts to the upstream of the route `key`
+fn submission(
+    key: &str,
+    route: &FormRoute,
+    page: &LoginPage,
+    form: &Form,
+    mut fields: FormFields,
+    config: &Value,
+    user: Option<&ProxyUser>,
+) -> Result<Submission, ()> {
+    let action = action::resolve(page.document, page.url, &form.action).ok_or(())?;
+    if !action::is_upstream(&action, &route.upstreams(key)) {
+        let detail = format!("login form of {} posts outside its upstream", page.url);
+        let event = AuditEvent::new(
+            "form_action_refused",
+            user,
+            "POST",
+            action.as_str(),
+            &detail,
+        );
+        audit::record(event, config);
+        return Err(());
+    }
+    let mut headers = HeaderMap::new();
+    if let Some(csrf) = &route.csrf {
+        let token = match csrf.extract(page.document, page.headers) {
+            Some(token) => token,
+            None => {
+                eprintln!("No CSRF token found on {}", page.url);
+                return Err(());
+            }
+        };
+        csrf.send(&token, page.document, &mut fields, &mut headers)?;
     }
 
-    fn add_form(&mut self, form: Form) {
-        self.forms.push(form);
+    Ok(Submission {
+        action,
+        fields: form.fill(&fields),
+        files: form.file_fields(),
+        enctype: Enctype::parse(&form.enctype),
+        encoding: encode::charset(&form.accept_charset),
+        headers,
+    })
+}
+
+// Fills the login form of `page`
+async fn form_submission(
+    key: &str,
+    route: &FormRoute,
+    page: &FetchedPage,
+    config: &Value,
+    user: Option<&ProxyUser>,
+) -> Result<Submission, ()> {
+    let credentials = route.credentials.resolve(user).cloned().unwrap_or_default();
+    let mut submission = {
+        let document = Html::parse_document(&page.body);
+        let forms = extract_form_elements(&document).ok_or(())?;
+        let form = match route.rules.pick(&document, &forms.forms) {
+            Some(form) => form,
+            None => {
+                println!("No form of {} matches the login form rules", page.url);
+                return Err(());
+            }
+        };
+        println!("Login form identified!");
+
+        let login_page = LoginPage {
+            url: &page.url,
+            document: &document,
+            headers: &page.headers,
+        };
+        submission(key, route, &login_page, form, credentials, config, user)?
+    };
+
+    // A code is good once only, it's spent on a form about to be posted
+    if let Some(totp) = route
+        .totp(user)
+        .filter(|totp| submission.has_field(&totp.field))
+    {
+        let code = totp.fresh_code().await;
+        submission.fields.retain(|(name, _)| *name != totp.field);
+        submission.fields.push((totp.field.clone(), code));
     }
+    Ok(submission)
+}
 
-    fn _remove_form(&mut self, form: Form) {
-        if let Some(pos) = self.forms.iter().position(|x| *x == form) {
-            self.forms.remove(pos);
-        }
+/// Outcome of logging in through a form route.
+enum Attempt {
+    LoggedIn(Response<Body>),
+    // No form to fill on the page
+    Skipped,
+    Failed,
+    // Failed recently, not submitted again
+    Held,
+    // Left to the user, see `Fallback::Manual`
+    HandedOver,
+}
+
+// Logs in through the login page `page` of the route `key`, recording
+// failures so they aren't submitted again for a while and, with a manual
+// fallback, handing the login over to the user
+async fn attempt(
+    key: &str,
+    route: &FormRoute,
+    page: FetchedPage,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    session: &str,
+    config: &Value,
+    requester: Requester<'_>,
+) -> Attempt {
+    let user = requester.user;
+    if fallback::is_handed_over(key, requester) {
+        println!("Login to {} is left to the user", key);
+        return Attempt::HandedOver;
+    }
+    if success::recently_failed(key, requester, route.success.retry_after) {
+        println!("Login to {} failed recently, not submitting it again", key);
+        return Attempt::Held;
     }
+    let url = page.url.clone();
+    let mut cookies = match requester.jar {
+        Some(jar) => LoginCookies::Jar(jar),
+        None => LoginCookies::Session(steps::merge_cookies("", session)),
+    };
 
-    fn is_empty(&mut self) -> bool {
-        if self.forms.is_empty() {
-            return true;
+    let login = match route.steps.is_empty() {
+        false => steps::run(key, route, page, client, cookies, config, user).await,
+        true => match form_submission(key, route, &page, config, user).await {
+            Ok(submission) => {
+                post::handle_post(
+                    submission.action.to_string(),
+                    submission.body(),
+                    submission.headers,
+                    client,
+                    &mut cookies,
+                    &route.success,
+                    &route.rules,
+                )
+                .await
+            }
+            Err(_) if route.fallback == Fallback::Error => return Attempt::Skipped,
+            Err(_) => Err("no login form could be filled".to_string()),
+        },
+    };
+    let reason = match login {
+        Ok(response) => {
+            success::clear_failure(key, requester);
+            return Attempt::LoggedIn(response);
         }
-        false
-    }
-
-    fn _print(&self) {
-        for (i, form) in self.forms.iter().enumerate() {
-            println!("Form {}:", i + 1);
-            println!("  ID: {}", form.id);
-            println!("  Action: {}", form.action);
-            if !form.inputs.is_empty() {
-                println!("  Elements:");
-                for (i, input) in form.inputs.iter().enumerate() {
-                    println!("    Input #{}:", i + 1);
-                    println!("      ID: {}", input.id);
-                    println!("      Type: {}", input.types);
-                    println!("      Name: {} + Value: {}", input.name, input.value);
-                }
+        Err(reason) => reason,
+    };
+    eprintln!("Login to {} failed: {}", url, reason);
+    success::record_failure(key, requester);
+    let event = AuditEvent::new("form_login_failed", user, "POST", &url, &reason);
+    audit::record(event, config);
+
+    match route.fallback {
+        // Without a proxy session, the login would go to every anonymous browser
+        Fallback::Manual { ttl } if requester.owner().is_some() => {
+            // The browser needs its jar to be told apart next time
+            if let Some(jar) = requester.jar {
+                jar::keep(jar);
             }
-            println!();
+            let manual = fallback::hand_over(key, requester, &reason, ttl);
+            let detail = format!("manual login until {}", manual.until.to_rfc3339());
+            let event = AuditEvent::new("form_login_fallback", user, "GET", &url, &detail);
+            audit::record(event, config);
+            Attempt::HandedOver
         }
+        _ => Attempt::Failed,
     }
 }
 
-fn input_elements(document: Html, input_selector: Selector, form: &mut Form) {
-    for element in document.select(&input_selector) {
-        let id = element.value().attr("id").unwrap_or("").to_string();
-        let type_elem = element.value().attr("type").unwrap_or("text").to_string();
-        let name = element.value().attr("name").unwrap_or("").to_string();
-        let value = element.value().attr("value").unwrap_or("").to_string();
-
-        if filter_inputs(type_elem.clone(), name.clone()).is_some() {
-            let input = Input::new(&id, &type_elem, &name, &value);
+/// Logs in through the login form of the page, when `target_url` has a
+/// form route and the page passes its rules. `Err` leaves the page as is,
+/// as when the login is handed over to the user; a failed login is
+/// otherwise answered with an error page.
+pub async fn handle_forms(
+    body: String,
+    headers: &HeaderMap,
+    target_url: &str,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    session: &str,
+    config: Value,
+    requester: Requester<'_>,
+) -> Result<Response<Body>, ()> {
+    let (key, route) = find_route(target_url, config.clone()).ok_or(())?;
+    let is_login_page = route
+        .rules
+        .is_login_page(target_url, &Html::parse_document(&body));
+    if !is_login_page {
+        return Err(());
+    }
 
-            form.add_input(input);
-        }
+    let page = FetchedPage::new(target_url, headers.clone(), body);
+    match attempt(&key, &route, page, client, session, &config, requester).await {
+        Attempt::LoggedIn(response) => Ok(response),
+        Attempt::Skipped | Attempt::HandedOver => Err(()),
+        Attempt::Failed | Attempt::Held => bad_gateway("login").map_err(|_| ()),
     }
 }
 
-fn form_base_elements(
-    html: &str,
-    form_select: Selector,
-    input_select: Selector,
-) -> ServerFormElements {
-    let document = Html::parse_document(html);
-    let mut form_elems = ServerFormElements::new();
+/// Form route whose upstream session `target_url` relies on: one with an
+/// `expiry` table on the same origin, `target_url` not being its login page.
+pub fn find_relogin(target_url: &str, config: Value) -> Option<(String, FormRoute)> {
+    let target = Url::parse(target_url).ok()?;
+    let clean_target = clean_url(target_url);
+
+    setup_form(config)?.into_iter().find(|(key, route)| {
+        route.expiry.is_some()
+            && *key != clean_target
+            && !route.rules.is_login_url(&clean_target)
+            && Url::parse(key).is_ok_and(|login| login.origin() == target.origin())
+    })
+}
 
-    for element in document.select(&form_select) {
-        let id = element.value().attr("id").unwrap_or("").to_string();
-        let action = element.value().attr("action").unwrap_or("").to_string();
-        let method = element.value().attr("method").unwrap_or("get").to_string();
+fn relogin_slot(key: &str, requester: Requester) -> ReloginSlot {
+    let mut relogins = RELOGINS.lock().unwrap_or_else(PoisonError::into_inner);
 
-        if method.to_lowercase() != "get" {
-            let mut form = Form::new(&id, &action, &method);
+    relogins
+        .entry((key.to_string(), requester.owner()))
+        .or_default()
+        .clone()
+}
 
-            input_elements(document.clone(), input_select.clone(), &mut form);
-            ServerFormElements::add_form(&mut form_elems, form)
+/// Logs in again through the route `key` once its upstream session
+/// expired, answering with the `Set-Cookie` headers of the new session.
+///
+/// Concurrent callers wait for a single login, and a request `sent_at`
+/// before the last one ended gets its session rather than logging in again.
+pub async fn relogin(
+    key: &str,
+    route: &FormRoute,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    config: &Value,
+    requester: Requester<'_>,
+    sent_at: Instant,
+) -> Result<HeaderMap, ()> {
+    let slot = relogin_slot(key, requester);
+    let mut last = slot.lock().await;
+
+    if let Some((cookies, logged_in_at)) = last.as_ref() {
+        if *logged_in_at > sent_at {
+            println!("Upstream session of {} renewed meanwhile, reusing it", key);
+            return Ok(cookies.clone());
         }
     }
-    form_elems
+    let cookies = login_again(key, route, client, config, requester).await?;
+    *last = Some((cookies.clone(), Instant::now()));
+    Ok(cookies)
 }
 
-fn filter_inputs(input: String, name: String) -> Option<String> {
-    if input == "text"
-        || input == "email"
-        || input == "password"
-        || input == "submit"
-        || input == "hidden"
-    {
-        return Some(input.clone());
+async fn login_again(
+    key: &str,
+    route: &FormRoute,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    config: &Value,
+    requester: Requester<'_>,
+) -> Result<HeaderMap, ()> {
+    if fallback::is_handed_over(key, requester) {
+        return Err(());
     }
-    if name.is_empty() {
-        return None;
+    println!("Upstream session expired, logging in again through {}", key);
+    let mut request = Request::get(key).body(Body::empty()).map_err(|_| ())?;
+    if let Some(jar) = requester.jar {
+        jar::attach(jar, key, request.headers_mut());
+    }
+    let response = client.request(request).await.map_err(|err| {
+        eprintln!("Error fetching the login page {}: {}", key, err);
+    })?;
+    let page = FetchedPage::read(key, response).await.map_err(|err| {
+        eprintln!("Error reading the login page {}: {}", key, err);
+    })?;
+    if let Some(jar) = requester.jar {
+        jar::store(jar, key, &page.headers);
+    }
+    let session = process_session(&page.headers);
+    let mut cookies = HeaderMap::new();
+    for set_cookie in page.headers.get_all(SET_COOKIE) {
+        cookies.append(SET_COOKIE, set_cookie.clone());
+    }
+
+    match attempt(key, route, page, client, &session, config, requester).await {
+        Attempt::LoggedIn(response) => {
+            for set_cookie in response.headers().get_all(SET_COOKIE) {
+                cookies.append(SET_COOKIE, set_cookie.clone());
+            }
+            Ok(cookies)
+        }
+        _ => Err(()),
     }
-    None
 }
 
-pub fn extract_form_elements(html: &str) -> Option<ServerFormElements> {
-    let mut forms = ServerFormElements::new();
+/// Sends the cookies `session` sets along with those of `headers`.
+pub fn attach_session(headers: &mut HeaderMap, session: &HeaderMap) {
+    let cookies = headers
+        .get(COOKIE)
+        .and_then(|cookies| cookies.to_str().ok())
+        .unwrap_or("");
+    let merged = steps::merge_cookies(cookies, &process_session(session));
 
-    match Selector::parse("form") {
-        Ok(form_selector) => {
-            match Selector::parse("input") {
-                Ok(input_selector) => {
-                    forms = form_base_elements(html, form_selector, input_selector)
-                }
-                Err(err) => eprintln!("{}", err),
+    if let Ok(merged) = HeaderValue::from_str(&merged) {
+        headers.insert(COOKIE, merged);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::{mock, sessions::CookieSettings};
+    use hyper::{header::LOCATION, Request};
+    use tokio::runtime::Runtime;
+
+    // Login page setting a pre-session cookie, posting with it to a
+    // redirection that sets the session cookie
+    fn mock_upstream() -> String {
+        mock::serve(|req: Request<Body>| async move {
+            let path = req.uri().path().to_string();
+            let pre_session = req
+                .headers()
+                .get(COOKIE)
+                .is_some_and(|cookie| cookie == "pre=1");
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let posted = pre_session && &body[..] == b"user=alice&pass=s3cret";
+
+            let response = match path.as_str() {
+                "/login" if body.is_empty() => Response::builder()
+                    .header(SET_COOKIE, "pre=1; Path=/")
+                    .body(Body::from(
+                        r#"<form method="post"><input name="user">
+                        <input type="password" name="pass"></form>"#,
+                    )),
+                "/login" if posted => Response::builder()
+                    .status(302)
+                    .header(SET_COOKIE, "sid=fresh; Path=/")
+                    .header(LOCATION, "/home")
+                    .body(Body::empty()),
+                _ => Response::builder()
+                    .status(401)
+                    .body(Body::from("Invalid password")),
             };
-        }
-        Err(err) => eprintln!("{}", err),
-    };
+            response.unwrap()
+        })
+    }
 
-    if !ServerFormElements::is_empty(&mut forms) {
-        return Some(forms);
+    fn upstream_config(upstream: &str, password: &str) -> Value {
+        toml::from_str(&format!(
+            r#"
+            [redirections]
+            "/" = "{upstream}/"
+
+            [form."{upstream}/login"]
+            user = "alice"
+            pass = "{password}"
+
+            [form."{upstream}/login".expiry]
+            redirect = "/login"
+            "#,
+        ))
+        .unwrap()
     }
-    None
-}
 
-fn match_credential(target_url: String, config: Value, form: &Form) -> Vec<(String, String)> {
-    let mut post_data: Vec<(String, String)> = Vec::new();
+    #[test]
+    fn test_relogin() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream();
+            let client = Arc::new(mock::http_client());
+            let config = upstream_config(&upstream, "s3cret");
+
+            assert!(find_relogin(&format!("{}/login", upstream), config.clone()).is_none());
+            assert!(find_relogin("http://other.example/app", config.clone()).is_none());
+            let (key, route) = find_relogin(&format!("{}/app", upstream), config.clone()).unwrap();
+
+            let sent_at = Instant::now();
+            let anonymous = Requester::default();
+            let session = relogin(&key, &route, client.clone(), &config, anonymous, sent_at)
+                .await
+                .unwrap();
+            let set_cookies: Vec<&str> = session
+                .get_all(SET_COOKIE)
+                .iter()
+                .map(|set_cookie| set_cookie.to_str().unwrap())
+                .collect();
+            assert_eq!(set_cookies, ["pre=1; Path=/", "sid=fresh; Path=/"]);
+
+            let mut headers = HeaderMap::new();
+            headers.insert(COOKIE, HeaderValue::from_static("theme=dark; sid=stale"));
+            attach_session(&mut headers, &session);
+            assert_eq!(headers[COOKIE], "theme=dark; pre=1; sid=fresh");
+
+            // A request sent before that login gets its session back
+            let config = upstream_config(&upstream, "wrong");
+            let route = route_of(&key, &config);
+            let reused = relogin(&key, &route, client.clone(), &config, anonymous, sent_at)
+                .await
+                .unwrap();
+            assert_eq!(reused, session);
+            assert!(relogin(
+                &key,
+                &route,
+                client.clone(),
+                &config,
+                anonymous,
+                Instant::now()
+            )
+            .await
+            .is_err());
+
+            let user = ProxyUser::new("relogin-test", Vec::new());
+            let requester = Requester {
+                user: Some(&user),
+                jar: None,
+            };
+            assert!(
+                relogin(&key, &route, client, &config, requester, Instant::now())
+                    .await
+                    .is_err()
+            );
+        });
+    }
 
-    if let Some(form_auth) = setup_form(config) {
-        for (key, value) in form_auth {
-            if key == target_url {
-                post_data = value;
-            }
-        }
+    #[test]
+    fn test_login_with_jar() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream();
+            let client = Arc::new(mock::http_client());
+            let config = upstream_config(&upstream, "s3cret");
+            let login_url = format!("{}/login", upstream);
+            let jar = jar::open(&mut HeaderMap::new(), &CookieSettings::default(), None);
+
+            let response = client.get(login_url.parse().unwrap()).await.unwrap();
+            let page = FetchedPage::read(&login_url, response).await.unwrap();
+            jar::store(&jar, &login_url, &page.headers);
+            let requester = Requester {
+                user: None,
+                jar: Some(&jar),
+            };
+            handle_forms(
+                page.body,
+                &page.headers,
+                &login_url,
+                client,
+                "",
+                config,
+                requester,
+            )
+            .await
+            .unwrap();
+
+            assert_eq!(
+                jar::cookie_header(&jar, &format!("{}/app", upstream)).as_deref(),
+                Some("pre=1; sid=fresh")
+            );
+        });
     }
-    if !form.inputs.is_empty() {
-        // Filled in inputs will be sent as they already were
-        for input in form.inputs.iter() {
-            if !input.value.is_empty() {
-                post_data.push((input.name.clone(), input.value.clone()));
-            }
-        }
+
+    #[test]
+    fn test_relogin_with_jar() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream();
+            let client = Arc::new(mock::http_client());
+            let config = upstream_config(&upstream, "s3cret");
+            let (key, route) = find_relogin(&format!("{}/app", upstream), config.clone()).unwrap();
+            let jar = jar::open(&mut HeaderMap::new(), &CookieSettings::default(), None);
+            let requester = Requester {
+                user: None,
+                jar: Some(&jar),
+            };
+
+            // The pre-session cookie of the login page goes through the jar
+            relogin(&key, &route, client, &config, requester, Instant::now())
+                .await
+                .unwrap();
+            assert_eq!(
+                jar::cookie_header(&jar, &format!("{}/app", upstream)).as_deref(),
+                Some("pre=1; sid=fresh")
+            );
+        });
     }
-    post_data
-}
 
-pub async fn handle_forms(
-    body: String,
-    target_url: &str,
-    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
-    session: &str,
-    config: Value,
-) -> Result<Response<Body>, ()> {
-    if let Some(forms) = extract_form_elements(&body) {
-        println!("Form element identified!");
-        if let Some(form) = forms.forms.iter().next() {
-            let form_cred: Vec<(String, String)> =
-                match_credential(target_url.to_string(), config, form);
-
-            let mut action = form.action.clone();
-            if action.starts_with('/') {
-                action = format!("{}{}", target_url, form.action.clone())
-            }
+    #[test]
+    fn test_relogin_per_browser() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream();
+            let client = Arc::new(mock::http_client());
+            let config = upstream_config(&upstream, "s3cret");
+            let (key, route) = find_relogin(&format!("{}/app", upstream), config.clone()).unwrap();
+            let settings = CookieSettings::default();
+            let first = jar::open(&mut HeaderMap::new(), &settings, None);
+            let second = jar::open(&mut HeaderMap::new(), &settings, None);
+            let browser = |jar| Requester {
+                user: None,
+                jar: Some(jar),
+            };
 
-            return post::handle_post(action, form_cred, client, session).await;
-        }
+            let sent_at = Instant::now();
+            relogin(
+                &key,
+                &route,
+                client.clone(),
+                &config,
+                browser(&first),
+                sent_at,
+            )
+            .await
+            .unwrap();
+            // Another anonymous browser logs in on its own, and fails here
+            let config = upstream_config(&upstream, "wrong");
+            let route = route_of(&key, &config);
+            assert!(
+                relogin(&key, &route, client, &config, browser(&second), sent_at)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(
+                jar::cookie_header(&second, &format!("{}/app", upstream)).as_deref(),
+                Some("pre=1")
+            );
+        });
+    }
+
+    #[test]
+    fn test_manual_fallback() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream();
+            let client = Arc::new(mock::http_client());
+            let mut config = upstream_config(&upstream, "wrong");
+            let login_url = format!("{}/login", upstream);
+            let user = ProxyUser::new("fallback-test", Vec::new());
+            let requester = Requester {
+                user: Some(&user),
+                jar: None,
+            };
+            let login_page = || async {
+                let response = client.get(login_url.parse().unwrap()).await.unwrap();
+                FetchedPage::read(&login_url, response).await.unwrap()
+            };
+            let submit = |page: FetchedPage, config: Value| {
+                let client = client.clone();
+                async move {
+                    let url = page.url.clone();
+                    handle_forms(
+                        page.body,
+                        &page.headers,
+                        &url,
+                        client,
+                        "",
+                        config,
+                        requester,
+                    )
+                    .await
+                }
+            };
+
+            let error = submit(login_page().await, config.clone()).await.unwrap();
+            assert_eq!(error.status(), 502);
+
+            config["form"][&login_url].as_table_mut().unwrap().insert(
+                "fallback".to_string(),
+                toml::from_str("mode = \"manual\"").unwrap(),
+            );
+            success::clear_failure(&login_url, requester);
+            assert!(submit(login_page().await, config.clone()).await.is_err());
+            assert!(fallback::is_handed_over(&login_url, requester));
+            assert!(active_manual_logins()
+                .iter()
+                .any(|manual| manual.user.as_deref() == Some("fallback-test")
+                    && manual.reason == "upstream answered 401 Unauthorized"));
+            let route = route_of(&login_url, &config);
+            assert!(relogin(
+                &login_url,
+                &route,
+                client.clone(),
+                &config,
+                requester,
+                Instant::now()
+            )
+            .await
+            .is_err());
+        });
+    }
+
+    #[test]
+    fn test_totp_only_for_posted_forms() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let key = "http://totp.example/login";
+            let config: Value = toml::from_str(&format!(
+                r#"
+                [form."{key}"]
+                user = "alice"
+
+                [form."{key}".totp]
+                secret = "MFRGGZDFMZTWQ2LK"
+                field = "otp"
+                "#,
+            ))
+            .unwrap();
+            let route = route_of(key, &config);
+            let page = |action: &str| {
+                let form = format!(
+                    r#"<form method="post" action="{}"><input name="user">
+                    <input type="password" name="pass"><input name="otp"></form>"#,
+                    action
+                );
+                FetchedPage::new(key, HeaderMap::new(), form)
+            };
+
+            let refused = page("https://elsewhere.example/collect");
+            assert!(form_submission(key, &route, &refused, &config, None)
+                .await
+                .is_err());
+            // the refused form didn't spend the current code
+            let posted = page("/session");
+            let submission = tokio::time::timeout(
+                std::time::Duration::from_secs(1),
+                form_submission(key, &route, &posted, &config, None),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert!(submission
+                .fields
+                .iter()
+                .any(|(name, code)| name == "otp" && code.len() == 6));
+        });
+    }
+
+    #[test]
+    fn test_login_page_again_is_a_failure() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // wrong credentials, the login form again with a 200
+            let upstream = mock::serve(|_req: Request<Body>| async {
+                Response::new(Body::from(
+                    r#"<p>Try again</p><form method="post"><input name="user">
+                    <input type="password" name="pass"></form>"#,
+                ))
+            });
+            let login = |success: LoginSuccess| {
+                let action = format!("{}/login", upstream);
+                async move {
+                    let form = FormBody {
+                        content_type: "application/x-www-form-urlencoded".to_string(),
+                        body: b"user=alice&pass=wrong".to_vec(),
+                    };
+                    let client = Arc::new(mock::http_client());
+                    let rules = FormRules::default();
+                    let mut cookies = LoginCookies::Session(String::new());
+                    post::handle_post(
+                        action,
+                        form,
+                        HeaderMap::new(),
+                        client,
+                        &mut cookies,
+                        &success,
+                        &rules,
+                    )
+                    .await
+                }
+            };
+
+            let reason = login(LoginSuccess::default()).await.unwrap_err();
+            assert!(
+                reason.ends_with("/login is still a login page"),
+                "{}",
+                reason
+            );
+            // configured checks are left alone
+            let status_only = LoginSuccess {
+                status: vec![200],
+                ..LoginSuccess::default()
+            };
+            assert!(login(status_only).await.is_ok());
+        });
+    }
+
+    fn route_of(key: &str, config: &Value) -> FormRoute {
+        setup_form(config.clone()).unwrap().remove(key).unwrap()
     }
-    Err(())
 }
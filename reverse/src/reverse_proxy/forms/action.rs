@@ -0,0 +1,106 @@
+use scraper::{Html, Selector};
+use url::{Origin, Url};
+
+// Base URL of the document: its first <base href>, else its own URL
+fn base_url(document: &Html, document_url: &Url) -> Url {
+    let href = Selector::parse("base[href]").ok().and_then(|selector| {
+        document
+            .select(&selector)
+            .next()
+            .and_then(|base| base.value().attr("href"))
+            .map(|href| href.to_string())
+    });
+
+    href.and_then(|href| document_url.join(href.trim()).ok())
+        .unwrap_or_else(|| document_url.clone())
+}
+
+/// URL a form of `document` posts to: an empty action is the document
+/// URL itself, any other one is resolved against the document base URL.
+pub fn resolve(document: &Html, document_url: &str, action: &str) -> Option<Url> {
+    let document_url = Url::parse(document_url).ok()?;
+    let action = action.trim();
+
+    if action.is_empty() {
+        return Some(document_url);
+    }
+    base_url(document, &document_url).join(action).ok()
+}
+
+/// Whether `action` lies on the origin of one of `upstreams`, the URLs of
+/// the form route, the only hosts its credentials may be posted to. The
+/// document the form comes from doesn't count, it may have been redirected
+/// anywhere.
+pub fn is_upstream(action: &Url, upstreams: &[&str]) -> bool {
+    let origin = action.origin();
+    let same_origin = |url: &str| {
+        Url::parse(url)
+            .map(|url| url.origin() == origin)
+            .unwrap_or(false)
+    };
+
+    if !matches!(origin, Origin::Tuple(..)) {
+        return false;
+    }
+    upstreams.iter().any(|upstream| same_origin(upstream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE_URL: &str = "https://app.example/account/login?next=/home";
+
+    fn resolve_in(html: &str, action: &str) -> String {
+        resolve(&Html::parse_document(html), PAGE_URL, action)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let page = "<form></form>";
+
+        assert_eq!(resolve_in(page, ""), PAGE_URL);
+        assert_eq!(
+            resolve_in(page, "session.php"),
+            "https://app.example/account/session.php"
+        );
+        assert_eq!(resolve_in(page, "/session"), "https://app.example/session");
+        assert_eq!(
+            resolve_in(page, "?step=2"),
+            "https://app.example/account/login?step=2"
+        );
+        assert_eq!(
+            resolve_in(page, "//sso.example/login"),
+            "https://sso.example/login"
+        );
+
+        let based = r#"<head><base href="/auth/"></head><form></form>"#;
+        assert_eq!(
+            resolve_in(based, "session"),
+            "https://app.example/auth/session"
+        );
+        assert_eq!(resolve_in(based, ""), PAGE_URL);
+    }
+
+    #[test]
+    fn test_is_upstream() {
+        let upstreams = ["https://app.example/login", "https://sso.example:8443/"];
+        let upstream = |action: &str| is_upstream(&Url::parse(action).unwrap(), &upstreams);
+
+        assert!(upstream("https://app.example/session"));
+        assert!(upstream("https://sso.example:8443/login"));
+        // another upstream of the proxy isn't one of the route
+        assert!(!upstream("https://intranet.example/collect"));
+        assert!(!upstream("http://app.example/session"));
+        assert!(!upstream("https://sso.example/login"));
+        assert!(!upstream("https://evil.example/collect"));
+        // nor is the page the login was redirected to
+        assert!(!is_upstream(
+            &Url::parse(PAGE_URL).unwrap(),
+            &["https://sso.example:8443/"]
+        ));
+        assert!(!upstream("javascript:alert(1)"));
+    }
+}
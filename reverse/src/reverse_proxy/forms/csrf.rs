@@ -0,0 +1,208 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, SET_COOKIE};
+use scraper::{Html, Selector};
+
+use super::FormFields;
+
+// Field the token goes in when neither the config nor a csrf-param meta names one
+const DEFAULT_FIELD: &str = "csrf_token";
+
+/// Where the login page carries its CSRF token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenSource {
+    /// `<meta name="..." content="token">`
+    Meta(String),
+    /// `<input name="..." value="token">`
+    Input(String),
+    /// A cookie set by the login page, for double-submit protections
+    Cookie(String),
+    /// A response header
+    Header(HeaderName),
+}
+
+/// How the token is sent back with the login form.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenTarget {
+    /// As a form field, named after the source or the page when `None`
+    Field(Option<String>),
+    Header(HeaderName),
+}
+
+/// The `csrf` subtable of a [form."url"] route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CsrfStrategy {
+    pub source: TokenSource,
+    pub target: TokenTarget,
+}
+
+fn select_attr(document: &Html, selector: &str, attr: &str) -> Option<String> {
+    let selector = Selector::parse(selector).ok()?;
+
+    document
+        .select(&selector)
+        .find_map(|element| element.value().attr(attr))
+        .map(|value| value.to_string())
+}
+
+fn set_cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(SET_COOKIE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .filter_map(|header| header.split(';').next()?.split_once('='))
+        .find(|(cookie, _)| cookie.trim() == name)
+        .map(|(_, value)| value.trim().trim_matches('"').to_string())
+}
+
+fn css_string(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+impl CsrfStrategy {
+    /// Reads the token from the login page just fetched, so that every
+    /// login attempt uses a fresh one.
+    pub fn extract(&self, document: &Html, headers: &HeaderMap) -> Option<String> {
+        match &self.source {
+            TokenSource::Meta(name) => select_attr(
+                document,
+                &format!("meta[name={}]", css_string(name)),
+                "content",
+            ),
+            TokenSource::Input(name) => select_attr(
+                document,
+                &format!("input[name={}]", css_string(name)),
+                "value",
+            ),
+            TokenSource::Cookie(name) => set_cookie_value(headers, name),
+            TokenSource::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        }
+    }
+
+    fn field_name(&self, document: &Html) -> String {
+        match (&self.target, &self.source) {
+            (TokenTarget::Field(Some(field)), _) => field.clone(),
+            (_, TokenSource::Input(name)) => name.clone(),
+            // Rails and its followers name the field in a csrf-param meta
+            _ => select_attr(document, "meta[name=csrf-param]", "content")
+                .unwrap_or_else(|| DEFAULT_FIELD.to_string()),
+        }
+    }
+
+    /// Adds `token` to the login fields or headers.
+    pub fn send(
+        &self,
+        token: &str,
+        document: &Html,
+        fields: &mut FormFields,
+        headers: &mut HeaderMap,
+    ) -> Result<(), ()> {
+        match &self.target {
+            TokenTarget::Field(_) => {
+                let name = self.field_name(document);
+
+                fields.retain(|(field, _)| *field != name);
+                fields.push((name, token.to_string()));
+            }
+            TokenTarget::Header(name) => {
+                let value = HeaderValue::from_str(token).map_err(|_| ())?;
+
+                headers.insert(name.clone(), value);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PAGE: &str = r#"
+        <html><head>
+            <meta name="csrf-param" content="authenticity_token">
+            <meta name="csrf-token" content="meta-token">
+        </head><body>
+            <form method="post"><input type="hidden" name="_token" value="input-token"></form>
+        </body></html>
+    "#;
+
+    fn headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.append(SET_COOKIE, "session=1; Path=/".parse().unwrap());
+        headers.append(
+            SET_COOKIE,
+            "XSRF-TOKEN=cookie-token; Path=/; SameSite=Lax"
+                .parse()
+                .unwrap(),
+        );
+        headers.insert("x-csrf-token", "header-token".parse().unwrap());
+        headers
+    }
+
+    fn strategy(source: TokenSource, target: TokenTarget) -> CsrfStrategy {
+        CsrfStrategy { source, target }
+    }
+
+    #[test]
+    fn test_extract() {
+        let document = Html::parse_document(PAGE);
+        let field = TokenTarget::Field(None);
+        let extract =
+            |source: TokenSource| strategy(source, field.clone()).extract(&document, &headers());
+
+        assert_eq!(
+            extract(TokenSource::Meta("csrf-token".to_string())),
+            Some("meta-token".to_string())
+        );
+        assert_eq!(
+            extract(TokenSource::Input("_token".to_string())),
+            Some("input-token".to_string())
+        );
+        assert_eq!(
+            extract(TokenSource::Cookie("XSRF-TOKEN".to_string())),
+            Some("cookie-token".to_string())
+        );
+        assert_eq!(
+            extract(TokenSource::Header(HeaderName::from_static("x-csrf-token"))),
+            Some("header-token".to_string())
+        );
+        assert_eq!(extract(TokenSource::Meta("other".to_string())), None);
+    }
+
+    #[test]
+    fn test_send() {
+        let document = Html::parse_document(PAGE);
+        let send = |strategy: CsrfStrategy| {
+            let mut fields = vec![("_token".to_string(), "stale".to_string())];
+            let mut headers = HeaderMap::new();
+            strategy
+                .send("fresh", &document, &mut fields, &mut headers)
+                .unwrap();
+            (fields, headers)
+        };
+
+        let (fields, _) = send(strategy(
+            TokenSource::Input("_token".to_string()),
+            TokenTarget::Field(None),
+        ));
+        assert_eq!(fields, vec![("_token".to_string(), "fresh".to_string())]);
+
+        let (fields, _) = send(strategy(
+            TokenSource::Meta("csrf-token".to_string()),
+            TokenTarget::Field(None),
+        ));
+        assert_eq!(
+            fields[1],
+            ("authenticity_token".to_string(), "fresh".to_string())
+        );
+
+        let (fields, headers) = send(strategy(
+            TokenSource::Cookie("XSRF-TOKEN".to_string()),
+            TokenTarget::Header(HeaderName::from_static("x-xsrf-token")),
+        ));
+        assert_eq!(fields.len(), 1);
+        assert_eq!(headers["x-xsrf-token"], "fresh");
+    }
+}
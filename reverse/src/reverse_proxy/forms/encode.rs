@@ -0,0 +1,238 @@
+use encoding_rs::{Encoding, UTF_8};
+use rand::{distributions::Alphanumeric, Rng};
+use url::form_urlencoded::byte_serialize;
+
+use super::FormFields;
+
+/// The `enctype` of a form; unknown values fall back to urlencoded.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Enctype {
+    UrlEncoded,
+    Multipart,
+    TextPlain,
+}
+
+/// An encoded form submission.
+#[derive(Debug, PartialEq)]
+pub struct FormBody {
+    pub content_type: String,
+    pub body: Vec<u8>,
+}
+
+impl Enctype {
+    pub fn parse(enctype: &str) -> Self {
+        match enctype.trim().to_ascii_lowercase().as_str() {
+            "multipart/form-data" => Enctype::Multipart,
+            "text/plain" => Enctype::TextPlain,
+            _ => Enctype::UrlEncoded,
+        }
+    }
+}
+
+/// First encoding of `accept-charset` that is known, UTF-8 otherwise.
+pub fn charset(accept_charset: &str) -> &'static Encoding {
+    accept_charset
+        .split(|c: char| c.is_ascii_whitespace() || c == ',')
+        .find_map(|label| Encoding::for_label(label.as_bytes()))
+        .map(Encoding::output_encoding)
+        .unwrap_or(UTF_8)
+}
+
+// Lone CR and LF become CRLF, like a browser does before encoding
+fn normalize_newlines(value: &str) -> String {
+    value
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\r\n")
+}
+
+// Characters the encoding lacks are sent as &#NNNN; references
+fn encode_text(value: &str, encoding: &'static Encoding) -> Vec<u8> {
+    encoding.encode(&normalize_newlines(value)).0.into_owned()
+}
+
+fn multipart_name(name: &str, encoding: &'static Encoding) -> Vec<u8> {
+    let escaped = name
+        .replace('\n', "%0A")
+        .replace('\r', "%0D")
+        .replace('"', "%22");
+
+    encode_text(&escaped, encoding)
+}
+
+fn boundary() -> String {
+    let random: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect();
+
+    format!("----ReverseProxyFormBoundary{}", random)
+}
+
+fn url_encoded(fields: &FormFields, encoding: &'static Encoding) -> Vec<u8> {
+    let pairs: Vec<String> = fields
+        .iter()
+        .map(|(name, value)| {
+            let name: String = byte_serialize(&encode_text(name, encoding)).collect();
+            let value: String = byte_serialize(&encode_text(value, encoding)).collect();
+            format!("{}={}", name, value)
+        })
+        .collect();
+
+    pairs.join("&").into_bytes()
+}
+
+fn multipart(
+    fields: &FormFields,
+    files: &[String],
+    encoding: &'static Encoding,
+    boundary: &str,
+) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for (name, value) in fields {
+        body.extend_from_slice(format!("--{}\r\n", boundary).as_bytes());
+        body.extend_from_slice(b"Content-Disposition: form-data; name=\"");
+        body.extend_from_slice(&multipart_name(name, encoding));
+        // File inputs are never filled in: they go out as empty files
+        if files.contains(name) && value.is_empty() {
+            body.extend_from_slice(
+                b"\"; filename=\"\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+            );
+        } else {
+            body.extend_from_slice(b"\"\r\n\r\n");
+            body.extend_from_slice(&encode_text(value, encoding));
+        }
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+    body
+}
+
+fn text_plain(fields: &FormFields, encoding: &'static Encoding) -> Vec<u8> {
+    let mut body = Vec::new();
+
+    for (name, value) in fields {
+        body.extend_from_slice(&encode_text(&format!("{}={}\r\n", name, value), encoding));
+    }
+    body
+}
+
+/// Encodes `fields` the way a browser submits a form of that `enctype`;
+/// `files` names the file inputs among them.
+pub fn encode(
+    fields: &FormFields,
+    files: &[String],
+    enctype: Enctype,
+    encoding: &'static Encoding,
+) -> FormBody {
+    let charset = encoding.name();
+
+    match enctype {
+        Enctype::UrlEncoded => FormBody {
+            content_type: "application/x-www-form-urlencoded".to_string(),
+            body: url_encoded(fields, encoding),
+        },
+        Enctype::Multipart => {
+            let boundary = boundary();
+            FormBody {
+                content_type: format!("multipart/form-data; boundary={}", boundary),
+                body: multipart(fields, files, encoding, &boundary),
+            }
+        }
+        Enctype::TextPlain => FormBody {
+            content_type: format!("text/plain; charset={}", charset),
+            body: text_plain(fields, encoding),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> FormFields {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_charset() {
+        assert_eq!(charset(""), UTF_8);
+        assert_eq!(charset("unknown ISO-8859-1"), encoding_rs::WINDOWS_1252);
+        assert_eq!(charset("Shift_JIS, utf-8"), encoding_rs::SHIFT_JIS);
+        assert_eq!(charset("utf-16le"), UTF_8);
+    }
+
+    #[test]
+    fn test_url_encoded() {
+        let login = fields(&[("user name", "zoë"), ("note", "a+b&c\nd"), ("sym", "€")]);
+
+        let utf8 = encode(&login, &[], Enctype::parse("bogus"), UTF_8);
+        assert_eq!(utf8.content_type, "application/x-www-form-urlencoded");
+        assert_eq!(
+            String::from_utf8(utf8.body).unwrap(),
+            "user+name=zo%C3%AB&note=a%2Bb%26c%0D%0Ad&sym=%E2%82%AC"
+        );
+
+        let latin1 = encode(&login, &[], Enctype::UrlEncoded, charset("iso-8859-1"));
+        assert_eq!(
+            String::from_utf8(latin1.body).unwrap(),
+            "user+name=zo%EB&note=a%2Bb%26c%0D%0Ad&sym=%80"
+        );
+
+        let ascii = encode(
+            &fields(&[("k", "日本")]),
+            &[],
+            Enctype::UrlEncoded,
+            charset("iso-8859-2"),
+        );
+        assert_eq!(
+            String::from_utf8(ascii.body).unwrap(),
+            "k=%26%2326085%3B%26%2326412%3B"
+        );
+    }
+
+    #[test]
+    fn test_multipart() {
+        let login = fields(&[("user", "alice"), ("na\"me", "two\nlines"), ("avatar", "")]);
+        let form = encode(
+            &login,
+            &["avatar".to_string()],
+            Enctype::parse("Multipart/Form-Data"),
+            UTF_8,
+        );
+        let boundary = form
+            .content_type
+            .strip_prefix("multipart/form-data; boundary=")
+            .unwrap();
+
+        assert_eq!(
+            String::from_utf8(form.body.clone()).unwrap(),
+            format!(
+                "--{b}\r\nContent-Disposition: form-data; name=\"user\"\r\n\r\nalice\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"na%22me\"\r\n\r\ntwo\r\nlines\r\n\
+                 --{b}\r\nContent-Disposition: form-data; name=\"avatar\"; filename=\"\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n\r\n\
+                 --{b}--\r\n",
+                b = boundary
+            )
+        );
+    }
+
+    #[test]
+    fn test_text_plain() {
+        let form = encode(
+            &fields(&[("user", "alice"), ("pass", "a=b")]),
+            &[],
+            Enctype::TextPlain,
+            UTF_8,
+        );
+
+        assert_eq!(form.content_type, "text/plain; charset=UTF-8");
+        assert_eq!(form.body, b"user=alice\r\npass=a=b\r\n");
+    }
+}
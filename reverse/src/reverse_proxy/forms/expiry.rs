@@ -0,0 +1,98 @@
+use hyper::{
+    header::{HeaderMap, CONTENT_TYPE, LOCATION},
+    Body, Response, StatusCode,
+};
+use regex::Regex;
+use scraper::Html;
+
+use super::FormRoute;
+use crate::reverse_proxy::errors::ProxyError;
+
+/// How an upstream shows its session expired, from [form."url".expiry]:
+/// an answer status, a redirection to a matching location or, with
+/// `login_page`, a page passing the route's login page rules.
+#[derive(Debug, Clone, Default)]
+pub struct SessionExpiry {
+    pub status: Vec<u16>,
+    pub redirect: Option<Regex>,
+    pub login_page: bool,
+}
+
+impl SessionExpiry {
+    fn expired_answer(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        if self.status.contains(&status.as_u16()) {
+            return true;
+        }
+        let location = headers
+            .get(LOCATION)
+            .and_then(|location| location.to_str().ok());
+
+        match (&self.redirect, location) {
+            (Some(redirect), Some(location)) => {
+                status.is_redirection() && redirect.is_match(location)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn is_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .is_some_and(|content_type| content_type.contains("html"))
+}
+
+/// Whether `response` to a request for `target_url` shows the upstream
+/// session of `route` expired, handing the response back as it was.
+pub async fn is_expired(
+    route: &FormRoute,
+    target_url: &str,
+    response: Response<Body>,
+) -> Result<(bool, Response<Body>), ProxyError> {
+    let expiry = match &route.expiry {
+        Some(expiry) => expiry,
+        None => return Ok((false, response)),
+    };
+    if expiry.expired_answer(response.status(), response.headers()) {
+        return Ok((true, response));
+    }
+    if !expiry.login_page || !response.status().is_success() || !is_html(response.headers()) {
+        return Ok((false, response));
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await?;
+    let document = Html::parse_document(&String::from_utf8_lossy(&body));
+    let expired = route.rules.is_login_page(target_url, &document);
+
+    Ok((expired, Response::from_parts(parts, Body::from(body))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_expired_answer() {
+        let expiry = SessionExpiry {
+            status: vec![401, 440],
+            redirect: Some(Regex::new("/login").unwrap()),
+            login_page: false,
+        };
+        let mut headers = HeaderMap::new();
+
+        assert!(expiry.expired_answer(StatusCode::UNAUTHORIZED, &headers));
+        assert!(!expiry.expired_answer(StatusCode::OK, &headers));
+        headers.insert(
+            LOCATION,
+            HeaderValue::from_static("https://app.example/login?next=/a"),
+        );
+        assert!(expiry.expired_answer(StatusCode::FOUND, &headers));
+        assert!(!expiry.expired_answer(StatusCode::OK, &headers));
+        headers.insert(LOCATION, HeaderValue::from_static("/home"));
+        assert!(!expiry.expired_answer(StatusCode::FOUND, &headers));
+        assert!(!SessionExpiry::default().expired_answer(StatusCode::UNAUTHORIZED, &headers));
+    }
+}
@@ -0,0 +1,460 @@
+use scraper::{ElementRef, Html, Selector};
+
+use super::FormFields;
+
+/// One name/value pair a form control may submit. A select gives one per
+/// option, `checked` telling whether the option is selected.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub id: String,
+    pub types: String,
+    pub name: String,
+    pub value: String,
+    pub checked: bool,
+    pub disabled: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Form {
+    // Index among all the <form> elements of the document
+    pub position: usize,
+    pub id: String,
+    pub action: String,
+    pub method: String,
+    pub enctype: String,
+    pub accept_charset: String,
+    pub inputs: Vec<Input>,
+}
+
+#[derive(Debug)]
+pub struct ServerFormElements {
+    pub forms: Vec<Form>,
+}
+
+// Input types a browser knows, any other one falls back to "text"
+const INPUT_TYPES: [&str; 22] = [
+    "hidden",
+    "text",
+    "search",
+    "tel",
+    "url",
+    "email",
+    "password",
+    "date",
+    "month",
+    "week",
+    "time",
+    "datetime-local",
+    "number",
+    "range",
+    "color",
+    "checkbox",
+    "radio",
+    "file",
+    "submit",
+    "image",
+    "reset",
+    "button",
+];
+
+impl Input {
+    fn new(element: ElementRef, types: &str, value: &str) -> Self {
+        let attr = |name: &str| element.value().attr(name).unwrap_or("").to_string();
+
+        Input {
+            id: attr("id"),
+            types: types.to_string(),
+            name: attr("name"),
+            value: value.to_string(),
+            checked: element.value().attr("checked").is_some(),
+            disabled: is_disabled(element),
+        }
+    }
+
+    fn is_button(&self) -> bool {
+        matches!(self.types.as_str(), "submit" | "image" | "reset" | "button")
+    }
+
+    fn is_submit(&self) -> bool {
+        matches!(self.types.as_str(), "submit" | "image")
+    }
+}
+
+impl Form {
+    fn new(position: usize, id: &str, action: &str, method: &str) -> Self {
+        Form {
+            position,
+            id: id.to_string(),
+            action: action.to_string(),
+            method: method.to_string(),
+            enctype: String::new(),
+            accept_charset: String::new(),
+            inputs: Vec::new(),
+        }
+    }
+
+    fn add_input(&mut self, input: Input) {
+        self.inputs.push(input);
+    }
+
+    /// Button submitting the form when the user presses Enter: its first
+    /// enabled submit button.
+    fn submitter(&self) -> Option<&Input> {
+        self.inputs
+            .iter()
+            .find(|input| input.is_submit() && !input.disabled)
+    }
+
+    /// The name/value pairs a browser would send, in tree order.
+    pub fn successful_controls(&self) -> FormFields {
+        let submitter = self.submitter();
+        let mut fields = Vec::new();
+
+        for input in self.inputs.iter() {
+            if input.disabled || input.name.is_empty() {
+                continue;
+            }
+            let submitted = match input.types.as_str() {
+                "checkbox" | "radio" | "option" => input.checked,
+                _ if input.is_button() => {
+                    submitter.is_some_and(|submitter| std::ptr::eq(submitter, input))
+                }
+                _ => true,
+            };
+            if !submitted {
+                continue;
+            }
+            match input.types.as_str() {
+                "image" => {
+                    fields.push((format!("{}.x", input.name), "0".to_string()));
+                    fields.push((format!("{}.y", input.name), "0".to_string()));
+                }
+                _ => fields.push((input.name.clone(), input.value.clone())),
+            }
+        }
+        fields
+    }
+
+    /// Names of the file inputs of the form.
+    pub fn file_fields(&self) -> Vec<String> {
+        self.inputs
+            .iter()
+            .filter(|input| input.types == "file")
+            .map(|input| input.name.clone())
+            .collect()
+    }
+
+    /// Successful controls with `credentials` written over the fields of
+    /// the same name; the ones the form lacks are appended.
+    pub fn fill(&self, credentials: &FormFields) -> FormFields {
+        let mut fields = self.successful_controls();
+
+        for (name, value) in credentials {
+            match fields.iter_mut().find(|(field, _)| field == name) {
+                Some(field) => field.1 = value.clone(),
+                None => fields.push((name.clone(), value.clone())),
+            }
+        }
+        fields
+    }
+}
+
+impl ServerFormElements {
+    fn new() -> Self {
+        ServerFormElements { forms: Vec::new() }
+    }
+
+    fn add_form(&mut self, form: Form) {
+        self.forms.push(form);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.forms.is_empty()
+    }
+}
+
+// Disabled itself, or inside a disabled fieldset but not in its first legend
+fn is_disabled(element: ElementRef) -> bool {
+    if element.value().attr("disabled").is_some() {
+        return true;
+    }
+    let mut child = element;
+
+    for ancestor in element.ancestors().filter_map(ElementRef::wrap) {
+        let fieldset = ancestor.value();
+        if fieldset.name() == "fieldset" && fieldset.attr("disabled").is_some() {
+            let first_legend = ancestor
+                .children()
+                .filter_map(ElementRef::wrap)
+                .find(|legend| legend.value().name() == "legend");
+            if first_legend.map(|legend| legend.id()) != Some(child.id()) {
+                return true;
+            }
+        }
+        child = ancestor;
+    }
+    false
+}
+
+fn text_of(element: ElementRef) -> String {
+    element.text().collect()
+}
+
+fn option_inputs(select: ElementRef, option_selector: &Selector) -> Vec<Input> {
+    let multiple = select.value().attr("multiple").is_some();
+    let select_disabled = is_disabled(select);
+    let mut options: Vec<Input> = select
+        .select(option_selector)
+        .map(|option| {
+            let label = text_of(option)
+                .split_whitespace()
+                .collect::<Vec<_>>()
+                .join(" ");
+            let value = option.value().attr("value").map(str::to_string);
+            let mut input = Input::new(option, "option", &value.unwrap_or(label));
+
+            input.name = select.value().attr("name").unwrap_or("").to_string();
+            input.checked = option.value().attr("selected").is_some();
+            input.disabled = select_disabled
+                || input.disabled
+                || option
+                    .ancestors()
+                    .filter_map(ElementRef::wrap)
+                    .take_while(|parent| parent.id() != select.id())
+                    .any(|parent| {
+                        parent.value().name() == "optgroup"
+                            && parent.value().attr("disabled").is_some()
+                    });
+            input
+        })
+        .collect();
+
+    // A single-choice select always has one selected option: the last
+    // marked one, or else the first enabled one
+    if !multiple {
+        let selected = options
+            .iter()
+            .rposition(|option| option.checked)
+            .or_else(|| options.iter().position(|option| !option.disabled));
+        for (index, option) in options.iter_mut().enumerate() {
+            option.checked = Some(index) == selected;
+        }
+    }
+    options
+}
+
+fn control_inputs(control: ElementRef, option_selector: &Selector) -> Vec<Input> {
+    let element = control.value();
+    let value = element.attr("value").unwrap_or("");
+
+    match element.name() {
+        "input" => {
+            let types = element.attr("type").unwrap_or("text").to_lowercase();
+            let types = match INPUT_TYPES.contains(&types.as_str()) {
+                true => types,
+                false => "text".to_string(),
+            };
+            let value = match (types.as_str(), element.attr("value")) {
+                ("checkbox" | "radio", None) => "on",
+                _ => value,
+            };
+            vec![Input::new(control, &types, value)]
+        }
+        "button" => {
+            let types = match element.attr("type").map(str::to_lowercase) {
+                Some(types) if types == "reset" || types == "button" => types,
+                _ => "submit".to_string(),
+            };
+            vec![Input::new(control, &types, value)]
+        }
+        "textarea" => {
+            let text = text_of(control).replace("\r\n", "\n").replace('\n', "\r\n");
+            vec![Input::new(control, "textarea", &text)]
+        }
+        "select" => option_inputs(control, option_selector),
+        _ => Vec::new(),
+    }
+}
+
+// Controls listed with a form attribute belong to the form of that id,
+// the others to their nearest form ancestor
+fn belongs_to(control: ElementRef, form: ElementRef) -> bool {
+    match control.value().attr("form") {
+        Some(owner) => !owner.is_empty() && form.value().attr("id") == Some(owner),
+        None => control
+            .ancestors()
+            .filter_map(ElementRef::wrap)
+            .find(|ancestor| ancestor.value().name() == "form")
+            .is_some_and(|ancestor| ancestor.id() == form.id()),
+    }
+}
+
+pub fn form_base_elements(document: &Html) -> ServerFormElements {
+    let mut form_elems = ServerFormElements::new();
+    let selectors = (
+        Selector::parse("form"),
+        Selector::parse("input, button, select, textarea"),
+        Selector::parse("option"),
+    );
+    let (form_select, control_select, option_select) = match selectors {
+        (Ok(form_select), Ok(control_select), Ok(option_select)) => {
+            (form_select, control_select, option_select)
+        }
+        _ => return form_elems,
+    };
+
+    for (position, element) in document.select(&form_select).enumerate() {
+        let id = element.value().attr("id").unwrap_or("").to_string();
+        let action = element.value().attr("action").unwrap_or("").to_string();
+        let method = element.value().attr("method").unwrap_or("get").to_string();
+
+        if method.to_lowercase() != "get" {
+            let mut form = Form::new(position, &id, &action, &method);
+            form.enctype = element.value().attr("enctype").unwrap_or("").to_string();
+            form.accept_charset = element
+                .value()
+                .attr("accept-charset")
+                .unwrap_or("")
+                .to_string();
+
+            for control in document.select(&control_select) {
+                if belongs_to(control, element) {
+                    for input in control_inputs(control, &option_select) {
+                        form.add_input(input);
+                    }
+                }
+            }
+            form_elems.add_form(form)
+        }
+    }
+    form_elems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields(pairs: &[(&str, &str)]) -> FormFields {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    fn forms(html: &str) -> Vec<Form> {
+        form_base_elements(&Html::parse_document(html)).forms
+    }
+
+    #[test]
+    fn test_successful_controls() {
+        let forms = forms(
+            r#"
+            <form id="login" action="/session" method="post">
+                <input name="user" value="guest">
+                <input type="PASSWORD" name="pass">
+                <input type="weird" name="fallback" value="1">
+                <input type="checkbox" name="remember">
+                <input type="checkbox" name="terms" value="yes" checked>
+                <input type="radio" name="mode" value="a">
+                <input type="radio" name="mode" value="b" checked>
+                <input type="file" name="avatar">
+                <input name="off" value="x" disabled>
+                <input value="no name">
+                <fieldset disabled>
+                    <legend><input name="in_legend" value="1"></legend>
+                    <input name="in_fieldset" value="1">
+                </fieldset>
+                <select name="lang">
+                    <option disabled>--</option>
+                    <option>  English
+                        (UK) </option>
+                    <option value="fr">French</option>
+                </select>
+                <select name="tags" multiple>
+                    <option value="a" selected>A</option>
+                    <option value="b">B</option>
+                    <optgroup disabled><option value="c" selected>C</option></optgroup>
+                </select>
+                <textarea name="note">line one
+line two</textarea>
+                <button type="reset" name="reset">Reset</button>
+                <button name="go" value="1">Go</button>
+                <input type="submit" name="other" value="Other">
+            </form>
+            <input form="login" name="outside" value="1">
+            <input form="elsewhere" name="stray" value="1">
+            "#,
+        );
+
+        assert_eq!(
+            forms[0].successful_controls(),
+            fields(&[
+                ("user", "guest"),
+                ("pass", ""),
+                ("fallback", "1"),
+                ("terms", "yes"),
+                ("mode", "b"),
+                ("avatar", ""),
+                ("in_legend", "1"),
+                ("lang", "English (UK)"),
+                ("tags", "a"),
+                ("note", "line one\r\nline two"),
+                ("go", "1"),
+                ("outside", "1"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_controls_are_scoped_to_their_form() {
+        let forms = forms(
+            r#"
+            <form id="search" method="post"><input name="q"></form>
+            <form id="signin" method="post">
+                <input name="username">
+                <input name="moved" form="search">
+                <input type="image" name="login">
+            </form>
+            <form method="get"><input name="ignored"></form>
+            "#,
+        );
+
+        assert_eq!(forms.len(), 2);
+        assert_eq!(
+            forms[0].successful_controls(),
+            fields(&[("q", ""), ("moved", "")])
+        );
+        assert_eq!(
+            forms[1].successful_controls(),
+            fields(&[("username", ""), ("login.x", "0"), ("login.y", "0")])
+        );
+        assert_eq!(forms[1].position, 1);
+    }
+
+    #[test]
+    fn test_fill() {
+        let forms = forms(
+            r#"
+            <form method="post">
+                <input type="hidden" name="token" value="abc">
+                <input name="username" value="placeholder">
+                <input type="password" name="password">
+            </form>
+            "#,
+        );
+
+        assert_eq!(
+            forms[0].fill(&fields(&[
+                ("username", "alice"),
+                ("password", "secret"),
+                ("remember", "on"),
+            ])),
+            fields(&[
+                ("token", "abc"),
+                ("username", "alice"),
+                ("password", "secret"),
+                ("remember", "on"),
+            ])
+        );
+    }
+}
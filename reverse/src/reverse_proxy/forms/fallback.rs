@@ -0,0 +1,136 @@
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use super::{LoginOwner, Requester};
+
+pub const DEFAULT_MANUAL_TTL: Duration = Duration::from_secs(3600);
+
+/// What a route does once its automated login failed, from
+/// [form."url".fallback]: answer with an error page, or hand the upstream
+/// login page over to the user for `ttl`.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Fallback {
+    #[default]
+    Error,
+    Manual {
+        ttl: Duration,
+    },
+}
+
+/// A route the user logs in to by hand, the proxy staying out of it.
+/// Anonymous browsers get it for their proxy session only.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ManualLogin {
+    pub route: String,
+    pub user: Option<String>,
+    pub reason: String,
+    pub since: DateTime<Utc>,
+    pub until: DateTime<Utc>,
+    owner: Option<LoginOwner>,
+}
+
+static MANUAL_LOGINS: Lazy<Mutex<Vec<ManualLogin>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Leaves the login of `route` to `requester` for `ttl`.
+pub fn hand_over(
+    route: &str,
+    requester: Requester<'_>,
+    reason: &str,
+    ttl: Duration,
+) -> ManualLogin {
+    let now = Utc::now();
+    let manual = ManualLogin {
+        route: route.to_string(),
+        user: requester.user.map(|user| user.name.clone()),
+        reason: reason.to_string(),
+        since: now,
+        until: now + chrono::Duration::from_std(ttl).unwrap_or_else(|_| chrono::Duration::zero()),
+        owner: requester.owner(),
+    };
+
+    let mut manual_logins = MANUAL_LOGINS.lock().unwrap_or_else(PoisonError::into_inner);
+    manual_logins.retain(|known| {
+        known.until > now && (known.route != manual.route || known.owner != manual.owner)
+    });
+    manual_logins.push(manual.clone());
+    manual
+}
+
+pub fn is_handed_over(route: &str, requester: Requester<'_>) -> bool {
+    let owner = match requester.owner() {
+        Some(owner) => owner,
+        None => return false,
+    };
+
+    active_manual_logins()
+        .iter()
+        .any(|manual| manual.route == route && manual.owner.as_ref() == Some(&owner))
+}
+
+pub fn active_manual_logins() -> Vec<ManualLogin> {
+    let manual_logins = MANUAL_LOGINS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Utc::now();
+
+    manual_logins
+        .iter()
+        .filter(|manual| manual.until > now)
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::{jar, portal::ProxyUser, sessions::CookieSettings};
+    use hyper::header::HeaderMap;
+
+    fn as_user(user: &ProxyUser) -> Requester<'_> {
+        Requester {
+            user: Some(user),
+            jar: None,
+        }
+    }
+
+    #[test]
+    fn test_hand_over() {
+        let route = "https://fallback.example/login";
+        let alice_user = ProxyUser::new("fallback-alice", Vec::new());
+        let bob_user = ProxyUser::new("fallback-bob", Vec::new());
+        let (alice, bob) = (as_user(&alice_user), as_user(&bob_user));
+
+        assert!(!is_handed_over(route, alice));
+        let manual = hand_over(route, alice, "captcha", DEFAULT_MANUAL_TTL);
+        assert_eq!(manual.user.as_deref(), Some("fallback-alice"));
+        assert!(is_handed_over(route, alice));
+        assert!(!is_handed_over(route, bob));
+        assert!(active_manual_logins().contains(&manual));
+
+        hand_over(route, bob, "captcha", Duration::ZERO);
+        assert!(!is_handed_over(route, bob));
+        hand_over(route, alice, "changed page", DEFAULT_MANUAL_TTL);
+        let alice_logins: Vec<ManualLogin> = active_manual_logins()
+            .into_iter()
+            .filter(|manual| manual.user.as_deref() == Some("fallback-alice"))
+            .collect();
+        assert_eq!(alice_logins.len(), 1);
+        assert_eq!(alice_logins[0].reason, "changed page");
+    }
+
+    #[test]
+    fn test_anonymous_hand_over() {
+        let route = "https://fallback.example/anonymous";
+        let new_jar = || jar::open(&mut HeaderMap::new(), &CookieSettings::default(), None);
+        let (first, second) = (new_jar(), new_jar());
+        let browser = |jar| Requester { user: None, jar };
+
+        let manual = hand_over(route, browser(Some(&first)), "captcha", DEFAULT_MANUAL_TTL);
+        assert_eq!(manual.user, None);
+        assert!(is_handed_over(route, browser(Some(&first))));
+        assert!(!is_handed_over(route, browser(Some(&second))));
+        assert!(!is_handed_over(route, browser(None)));
+    }
+}
@@ -1,15 +1,52 @@
-use hyper::{Body, Client, Method, Response};
+use hyper::{
+    header::{HeaderMap, HeaderValue, LOCATION, SET_COOKIE},
+    Body, Client, Method, Response,
+};
 use hyper_tls::HttpsConnector;
 
+use scraper::Html;
 use std::sync::Arc;
 
+use super::{encode::FormBody, rules::FormRules, steps::merge_cookies, success::LoginSuccess};
 use crate::reverse_proxy::{
-    errors::ProxyError, handle_redirection, sessions::process_session, status::bad_request,
+    errors::ProxyError,
+    handle_redirection,
+    jar::{self, JarSession},
+    sessions::process_session,
+    utils,
 };
 
+/// Cookies a login sends upstream: those of the browser's jar, or else
+/// the ones the pages of the login set.
+pub enum LoginCookies<'a> {
+    Jar(&'a JarSession),
+    Session(String),
+}
+
+impl LoginCookies<'_> {
+    /// `Cookie` header value for a request to `url`.
+    pub fn header(&self, url: &str) -> String {
+        match self {
+            LoginCookies::Jar(jar) => jar::cookie_header(jar, url).unwrap_or_default(),
+            LoginCookies::Session(cookies) => cookies.clone(),
+        }
+    }
+
+    /// Keeps the cookies a response from `url` sets.
+    pub fn keep(&mut self, url: &str, headers: &HeaderMap) {
+        match self {
+            LoginCookies::Jar(jar) => jar::store(jar, url, headers),
+            LoginCookies::Session(cookies) => {
+                *cookies = merge_cookies(cookies, &process_session(headers))
+            }
+        }
+    }
+}
+
 pub async fn make_post_request(
     action: String,
-    params: Vec<(String, String)>,
+    form: FormBody,
+    headers: HeaderMap,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
     session: &str,
 ) -> Result<Response<Body>, ProxyError> {
@@ -18,46 +55,74 @@ pub async fn make_post_request(
         Err(_) => action.clone(),
     };
 
-    let post_data = match serde_urlencoded::to_string(params.clone()) {
-        Ok(res) => res,
-        Err(_) => return bad_request(),
-    };
+    // The body holds the credentials, only where it goes is logged
+    println!("Posting the login form to {}", action);
 
-    println!("POST DATA :: {}", post_data);
-
-    let request = hyper::Request::builder()
+    let mut request = hyper::Request::builder()
         .method(Method::POST)
         .uri(uri)
-        .header("Content-Type", "application/x-www-form-urlencoded")
+        .header("Content-Type", form.content_type)
         .header("Cookie", session)
-        .body(post_data.into())?;
+        .body(form.body.into())?;
+    request.headers_mut().extend(headers);
 
     let response = client.request(request).await?;
     Ok(response)
 }
 
+/// Posts the login form and follows where it leads, `Err` telling why
+/// the login didn't pass the `success` checks. Without any, the page it
+/// leads to mustn't be a login page by the route's `rules`.
 pub async fn handle_post(
     action: String,
-    form_cred: Vec<(String, String)>,
+    form: FormBody,
+    headers: HeaderMap,
     client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
-    session: &str,
-) -> Result<Response<Body>, ()> {
-    match make_post_request(action, form_cred, client.clone(), session).await {
-        Ok(res) => {
-            let new_cookies = process_session(&res.headers().clone());
-            if res.status().is_redirection() {
-                match handle_redirection(res, client, &new_cookies).await {
-                    Ok(new_res) => {
-                        return Ok(new_res);
-                    }
-                    Err(err) => {
-                        eprintln!("POST Request error : {}", err);
-                        return Err(());
-                    }
-                }
+    cookies: &mut LoginCookies<'_>,
+    success: &LoginSuccess,
+    rules: &FormRules,
+) -> Result<Response<Body>, String> {
+    let session = cookies.header(&action);
+    let mut res = make_post_request(action.clone(), form, headers, client.clone(), &session)
+        .await
+        .map_err(|err| format!("POST Request error : {}", err))?;
+    success.check_answer(res.status(), res.headers())?;
+    cookies.keep(&action, res.headers());
+
+    let mut page_url = action.clone();
+    let res = match res.status().is_redirection() {
+        true => {
+            if let Some(location) = utils::absolute_location(&action, res.headers()) {
+                page_url = location.to_str().unwrap_or(&action).to_string();
+                res.headers_mut().insert(LOCATION, location);
+            }
+            let set_cookies: Vec<HeaderValue> =
+                res.headers().get_all(SET_COOKIE).iter().cloned().collect();
+            let mut redirected = handle_redirection(res, client, &cookies.header(&page_url))
+                .await
+                .map_err(|err| format!("POST Request error : {}", err))?;
+            cookies.keep(&page_url, redirected.headers());
+            // The session cookies usually come with the redirection
+            for set_cookie in set_cookies {
+                redirected.headers_mut().append(SET_COOKIE, set_cookie);
             }
-            Ok(res)
+            redirected
         }
-        Err(_) => Err(()),
+        false => res,
+    };
+    let detect_login_page = success.is_default();
+    if !success.checks_body() && !detect_login_page {
+        return Ok(res);
     }
+    let (parts, body) = res.into_parts();
+    let body = hyper::body::to_bytes(body)
+        .await
+        .map_err(|err| err.to_string())?;
+    let page = String::from_utf8_lossy(&body);
+    success.check_body(&page)?;
+    if detect_login_page && rules.is_login_page(&page_url, &Html::parse_document(&page)) {
+        return Err(format!("{} is still a login page", page_url));
+    }
+
+    Ok(Response::from_parts(parts, Body::from(body)))
 }
@@ -0,0 +1,193 @@
+use regex::Regex;
+use scraper::{ElementRef, Html, Selector};
+
+use super::Form;
+use crate::reverse_proxy::utils::clean_url;
+
+// Pages with a password input are login pages unless `login_urls` says otherwise
+pub const DEFAULT_DETECT: &str = "input[type=password]";
+
+/// The `rules` subtable of a [form."url"] route: when a page is a login
+/// page and which of its forms gets submitted.
+#[derive(Debug, Clone)]
+pub struct FormRules {
+    pub login_urls: Vec<String>,
+    pub detect: Option<Selector>,
+    pub selector: Option<Selector>,
+    pub form_id: Option<String>,
+    pub action_pattern: Option<Regex>,
+    pub required_fields: Vec<String>,
+}
+
+impl Default for FormRules {
+    fn default() -> Self {
+        FormRules {
+            login_urls: Vec::new(),
+            detect: Selector::parse(DEFAULT_DETECT).ok(),
+            selector: None,
+            form_id: None,
+            action_pattern: None,
+            required_fields: Vec::new(),
+        }
+    }
+}
+
+impl FormRules {
+    pub fn is_login_url(&self, target_url: &str) -> bool {
+        let target = clean_url(target_url);
+
+        self.login_urls.iter().any(|url| clean_url(url) == target)
+    }
+
+    /// A page is a login page when its URL is listed in `login_urls` or
+    /// when the `detect` selector matches something in it.
+    pub fn is_login_page(&self, target_url: &str, document: &Html) -> bool {
+        self.is_login_url(target_url)
+            || self
+                .detect
+                .as_ref()
+                .is_some_and(|detect| document.select(detect).next().is_some())
+    }
+
+    fn accepts(&self, form: &Form, document: &Html, element: ElementRef) -> bool {
+        if let Some(selector) = &self.selector {
+            if !document
+                .select(selector)
+                .any(|selected| selected.id() == element.id())
+            {
+                return false;
+            }
+        }
+        if let Some(form_id) = &self.form_id {
+            if &form.id != form_id {
+                return false;
+            }
+        }
+        if let Some(pattern) = &self.action_pattern {
+            if !pattern.is_match(&form.action) {
+                return false;
+            }
+        }
+        self.required_fields
+            .iter()
+            .all(|field| form.inputs.iter().any(|input| &input.name == field))
+    }
+
+    /// Form of `document` to log in with: among the ones passing every
+    /// configured rule, the first holding what `detect` matched, if any.
+    pub fn pick<'a>(&self, document: &Html, forms: &'a [Form]) -> Option<&'a Form> {
+        let form_selector = Selector::parse("form").ok()?;
+        let elements: Vec<ElementRef> = document.select(&form_selector).collect();
+        let candidates: Vec<(&Form, ElementRef)> = forms
+            .iter()
+            .filter_map(|form| Some((form, *elements.get(form.position)?)))
+            .filter(|(form, element)| self.accepts(form, document, *element))
+            .collect();
+
+        let detected = self.detect.as_ref().and_then(|detect| {
+            candidates
+                .iter()
+                .find(|(_, element)| element.select(detect).next().is_some())
+        });
+        detected.or(candidates.first()).map(|(form, _)| *form)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::forms::extract::form_base_elements;
+
+    const PAGE: &str = r#"
+        <html><body>
+            <form id="search" action="/search" method="post">
+                <input type="text" name="q">
+            </form>
+            <div class="login">
+                <form id="signin" action="/session" method="post">
+                    <input type="text" name="username">
+                    <input type="password" name="password">
+                </form>
+            </div>
+            <form id="comment" action="/comments" method="post">
+                <input type="text" name="body">
+            </form>
+        </body></html>
+    "#;
+
+    fn pick_id(rules: &FormRules, html: &str) -> Option<String> {
+        let document = Html::parse_document(html);
+        let forms = form_base_elements(&document);
+
+        rules
+            .pick(&document, &forms.forms)
+            .map(|form| form.id.clone())
+    }
+
+    #[test]
+    fn test_is_login_page() {
+        let rules = FormRules::default();
+        let search = Html::parse_document(r#"<form method="post"><input name="q"></form>"#);
+
+        assert!(rules.is_login_page("https://app.example/", &Html::parse_document(PAGE)));
+        assert!(!rules.is_login_page("https://app.example/", &search));
+
+        let rules = FormRules {
+            login_urls: vec!["https://app.example/login/".to_string()],
+            detect: None,
+            ..FormRules::default()
+        };
+        assert!(rules.is_login_page("https://app.example/login", &search));
+        assert!(!rules.is_login_page("https://app.example/", &Html::parse_document(PAGE)));
+    }
+
+    #[test]
+    fn test_pick() {
+        let rule = |rules: FormRules| pick_id(&rules, PAGE);
+
+        assert_eq!(rule(FormRules::default()), Some("signin".to_string()));
+        assert_eq!(
+            rule(FormRules {
+                detect: None,
+                ..FormRules::default()
+            }),
+            Some("search".to_string())
+        );
+        assert_eq!(
+            rule(FormRules {
+                selector: Selector::parse("div.login form").ok(),
+                ..FormRules::default()
+            }),
+            Some("signin".to_string())
+        );
+        assert_eq!(
+            rule(FormRules {
+                form_id: Some("comment".to_string()),
+                ..FormRules::default()
+            }),
+            Some("comment".to_string())
+        );
+        assert_eq!(
+            rule(FormRules {
+                action_pattern: Regex::new("^/sess").ok(),
+                ..FormRules::default()
+            }),
+            Some("signin".to_string())
+        );
+        assert_eq!(
+            rule(FormRules {
+                required_fields: vec!["username".to_string(), "password".to_string()],
+                ..FormRules::default()
+            }),
+            Some("signin".to_string())
+        );
+        assert_eq!(
+            rule(FormRules {
+                form_id: Some("search".to_string()),
+                required_fields: vec!["password".to_string()],
+                ..FormRules::default()
+            }),
+            None
+        );
+    }
+}
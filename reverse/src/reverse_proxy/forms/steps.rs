@@ -0,0 +1,604 @@
+use hyper::{
+    header::{HeaderMap, HeaderValue, COOKIE, LOCATION, SET_COOKIE},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+
+use regex::Regex;
+use scraper::{Html, Selector};
+use std::{sync::Arc, time::Duration};
+use tokio::time::timeout;
+use toml::Value;
+use url::Url;
+
+use super::{
+    action, extract_form_elements,
+    post::{self, LoginCookies},
+    submission, FormFields, FormRoute, FormRules, LoginPage,
+};
+use crate::reverse_proxy::portal::ProxyUser;
+
+pub const DEFAULT_STEP_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Redirections followed after each step before giving up
+const MAX_REDIRECTS: usize = 5;
+// Step fields holding it get the current TOTP code of the route
+const TOTP_PLACEHOLDER: &str = "{{totp}}";
+
+/// A value read from the page of a step, for the fields of the next ones.
+#[derive(Debug, Clone)]
+pub enum Extraction {
+    Css {
+        selector: Selector,
+        attribute: Option<String>,
+    },
+    Pattern(Regex),
+}
+
+/// What the response to a step must look like for the login to go on.
+/// Without `status`, any status below 400 will do.
+#[derive(Debug, Clone, Default)]
+pub struct StepSuccess {
+    pub status: Vec<u16>,
+    pub contains: Option<String>,
+    pub present: Option<Selector>,
+    pub absent: Option<Selector>,
+}
+
+/// One [[form."url".steps]] entry: the page to work on, the form to
+/// submit there and what to fill it with.
+#[derive(Debug, Clone)]
+pub struct LoginStep {
+    /// Page fetched for the step, instead of the one the last step led to
+    pub url: Option<String>,
+    pub selector: Option<Selector>,
+    /// Values where `{{name}}` stands for a credential or extracted value
+    pub fields: FormFields,
+    pub extract: Vec<(String, Extraction)>,
+    pub success: StepSuccess,
+    pub timeout: Duration,
+}
+
+/// An upstream response read in full.
+pub struct FetchedPage {
+    pub url: String,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+impl Extraction {
+    /// `regex:<pattern>` takes the first capture group found in the page,
+    /// `<selector> @<attribute>` an attribute and a bare selector the text.
+    pub fn parse(spec: &str) -> Option<Self> {
+        if let Some(pattern) = spec.strip_prefix("regex:") {
+            return Regex::new(pattern).ok().map(Extraction::Pattern);
+        }
+        let (selector, attribute) = match spec.rsplit_once(" @") {
+            Some((selector, attribute)) => (selector, Some(attribute.trim().to_string())),
+            None => (spec, None),
+        };
+
+        Some(Extraction::Css {
+            selector: Selector::parse(selector.trim()).ok()?,
+            attribute,
+        })
+    }
+
+    fn extract(&self, body: &str, document: &Html) -> Option<String> {
+        match self {
+            Extraction::Pattern(pattern) => {
+                let captures = pattern.captures(body)?;
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|found| found.as_str().to_string())
+            }
+            Extraction::Css {
+                selector,
+                attribute,
+            } => {
+                let element = document.select(selector).next()?;
+                match attribute {
+                    Some(attribute) => element.value().attr(attribute).map(str::to_string),
+                    None => Some(element.text().collect::<String>().trim().to_string()),
+                }
+            }
+        }
+    }
+}
+
+impl StepSuccess {
+    fn check(&self, page: &FetchedPage) -> Result<(), String> {
+        let status = page.status;
+        let expected = match self.status.is_empty() {
+            true => status.as_u16() < 400,
+            false => self.status.contains(&status.as_u16()),
+        };
+        if !expected {
+            return Err(format!("upstream answered {}", status));
+        }
+        if let Some(text) = &self.contains {
+            if !page.body.contains(text.as_str()) {
+                return Err(format!("response lacks {:?}", text));
+            }
+        }
+        let document = Html::parse_document(&page.body);
+        if let Some(present) = &self.present {
+            if document.select(present).next().is_none() {
+                return Err("expected element missing from the response".to_string());
+            }
+        }
+        if let Some(absent) = &self.absent {
+            if document.select(absent).next().is_some() {
+                return Err("unexpected element in the response".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FetchedPage {
+    pub fn new(url: &str, headers: HeaderMap, body: String) -> Self {
+        FetchedPage {
+            url: url.to_string(),
+            status: StatusCode::OK,
+            headers,
+            body,
+        }
+    }
+
+    pub async fn read(url: &str, response: Response<Body>) -> Result<Self, String> {
+        let (parts, body) = response.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        Ok(FetchedPage {
+            url: url.to_string(),
+            status: parts.status,
+            headers: parts.headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    fn into_response(self) -> Response<Body> {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+        response
+    }
+}
+
+fn fill(template: &str, values: &FormFields) -> String {
+    values
+        .iter()
+        .fold(template.to_string(), |text, (name, value)| {
+            text.replace(&format!("{{{{{}}}}}", name), value)
+        })
+}
+
+// Cookies of `cookies` overridden by the ones a response set
+pub fn merge_cookies(cookies: &str, set: &str) -> String {
+    let pairs = |cookies: &str| -> Vec<String> {
+        cookies
+            .split(';')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(str::to_string)
+            .collect()
+    };
+    let name = |pair: &str| pair.split('=').next().unwrap_or("").trim().to_string();
+    let set = pairs(set);
+    let replaced: Vec<String> = set.iter().map(|pair| name(pair)).collect();
+
+    pairs(cookies)
+        .into_iter()
+        .filter(|pair| !replaced.contains(&name(pair)))
+        .chain(set)
+        .collect::<Vec<String>>()
+        .join("; ")
+}
+
+/// State of a login going through the steps of a route.
+struct StepLogin<'a> {
+    key: &'a str,
+    route: &'a FormRoute,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    config: &'a Value,
+    user: Option<&'a ProxyUser>,
+    cookies: LoginCookies<'a>,
+    // Every cookie the upstream set, for the browser
+    set_cookies: Vec<HeaderValue>,
+    // Credentials and extracted values the step fields refer to
+    values: FormFields,
+}
+
+impl StepLogin<'_> {
+    fn keep_cookies(&mut self, url: &str, headers: &HeaderMap) {
+        self.cookies.keep(url, headers);
+        self.set_cookies
+            .extend(headers.get_all(SET_COOKIE).iter().cloned());
+    }
+
+    // Reads `response`, following its redirections with the login cookies
+    // as long as they stay on the upstream of the route
+    async fn follow(&mut self, url: &str, response: Response<Body>) -> Result<FetchedPage, String> {
+        let mut url = Url::parse(url).map_err(|err| err.to_string())?;
+        let mut response = response;
+
+        for _ in 0..MAX_REDIRECTS {
+            self.keep_cookies(url.as_str(), response.headers());
+            if !response.status().is_redirection() {
+                return FetchedPage::read(url.as_str(), response).await;
+            }
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .ok_or("redirection without a location")?;
+            url = url.join(location).map_err(|err| err.to_string())?;
+            if !action::is_upstream(&url, &self.route.upstreams(self.key)) {
+                return Err(format!("redirected outside the upstream to {}", url));
+            }
+            response = self.get(url.as_str()).await?;
+        }
+        Err("too many redirections".to_string())
+    }
+
+    async fn get(&self, url: &str) -> Result<Response<Body>, String> {
+        let request = Request::builder()
+            .method(Method::GET)
+            .uri(url)
+            .header(COOKIE, self.cookies.header(url))
+            .body(Body::empty())
+            .map_err(|err| err.to_string())?;
+
+        self.client
+            .request(request)
+            .await
+            .map_err(|err| err.to_string())
+    }
+
+    async fn step(&mut self, step: &LoginStep, page: FetchedPage) -> Result<FetchedPage, String> {
+        let page = match &step.url {
+            Some(url) => {
+                let response = self.get(url).await?;
+                self.follow(url, response).await?
+            }
+            None => page,
+        };
+        let mut submission = {
+            let document = Html::parse_document(&page.body);
+            for (name, extraction) in step.extract.iter() {
+                let value = extraction
+                    .extract(&page.body, &document)
+                    .ok_or(format!("nothing to extract for {}", name))?;
+                self.values.retain(|(known, _)| known != name);
+                self.values.push((name.clone(), value));
+            }
+
+            let forms = extract_form_elements(&document).ok_or("no form on the page")?;
+            let rules = FormRules {
+                selector: step.selector.clone(),
+                ..FormRules::default()
+            };
+            let form = rules
+                .pick(&document, &forms.forms)
+                .ok_or("no form matches the step")?;
+            let fields = step
+                .fields
+                .iter()
+                .map(|(name, template)| (name.clone(), fill(template, &self.values)))
+                .collect();
+            let login_page = LoginPage {
+                url: &page.url,
+                document: &document,
+                headers: &page.headers,
+            };
+            submission(
+                self.key,
+                self.route,
+                &login_page,
+                form,
+                fields,
+                self.config,
+                self.user,
+            )
+            .map_err(|_| "the form can't be submitted".to_string())?
+        };
+        // The code is taken last, once the step's form is ready to be posted
+        if let Some(totp) = self.route.totp(self.user) {
+            let wants_code = step
+                .fields
+                .iter()
+                .any(|(_, template)| template.contains(TOTP_PLACEHOLDER));
+            if wants_code {
+                let code = totp.fresh_code().await;
+                for (_, value) in submission.fields.iter_mut() {
+                    *value = value.replace(TOTP_PLACEHOLDER, &code);
+                }
+            }
+        }
+
+        let action = submission.action.to_string();
+        let response = post::make_post_request(
+            action.clone(),
+            submission.body(),
+            submission.headers,
+            self.client.clone(),
+            &self.cookies.header(&action),
+        )
+        .await
+        .map_err(|err| err.to_string())?;
+        let page = self.follow(&action, response).await?;
+
+        step.success.check(&page)?;
+        Ok(page)
+    }
+}
+
+/// Goes through the steps of the route `key` from the login page `page`, and
+/// answers with the page the last one led to, or why one failed.
+pub async fn run(
+    key: &str,
+    route: &FormRoute,
+    page: FetchedPage,
+    client: Arc<Client<HttpsConnector<hyper::client::HttpConnector>>>,
+    cookies: LoginCookies<'_>,
+    config: &Value,
+    user: Option<&ProxyUser>,
+) -> Result<Response<Body>, String> {
+    let mut login = StepLogin {
+        key,
+        route,
+        client,
+        config,
+        user,
+        cookies,
+        set_cookies: Vec::new(),
+        values: route.credentials.resolve(user).cloned().unwrap_or_default(),
+    };
+    let total = route.steps.len();
+    let mut page = page;
+
+    for (index, step) in route.steps.iter().enumerate() {
+        println!("Login step {}/{} from {}", index + 1, total, page.url);
+        page = match timeout(step.timeout, login.step(step, page)).await {
+            Ok(Ok(page)) => page,
+            Ok(Err(reason)) => {
+                return Err(format!("step {}/{} failed: {}", index + 1, total, reason));
+            }
+            Err(_) => {
+                return Err(format!(
+                    "step {}/{} timed out after {:?}",
+                    index + 1,
+                    total,
+                    step.timeout
+                ));
+            }
+        };
+    }
+    println!("Logged in after {} steps", total);
+
+    let mut response = page.into_response();
+    response.headers_mut().remove(SET_COOKIE);
+    for set_cookie in login.set_cookies {
+        response.headers_mut().append(SET_COOKIE, set_cookie);
+    }
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::{config::setup_form, mock};
+    use hyper::header::SET_COOKIE;
+    use tokio::runtime::Runtime;
+
+    const IDENTIFY: &str = r#"
+        <form id="search" action="/search" method="post"><input name="q"></form>
+        <form id="identify" action="/identify" method="post"><input name="login"></form>
+    "#;
+
+    fn page(body: &str) -> Response<Body> {
+        Response::new(Body::from(body.to_string()))
+    }
+
+    // Identifier, then password, then consent, with a session cookie throughout
+    fn mock_upstream(delay: Duration) -> String {
+        mock::serve(move |req: Request<Body>| async move {
+            let path = req.uri().path().to_string();
+            let cookie = req
+                .headers()
+                .get(COOKIE)
+                .and_then(|cookie| cookie.to_str().ok())
+                .unwrap_or("")
+                .to_string();
+            let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+            let form: Vec<(String, String)> = serde_urlencoded::from_bytes(&body).unwrap();
+            let has =
+                |name: &str, value: &str| form.contains(&(name.to_string(), value.to_string()));
+            tokio::time::sleep(delay).await;
+
+            match path.as_str() {
+                "/identify" if has("login", "alice") => Response::builder()
+                    .status(303)
+                    .header(SET_COOKIE, "flow=1; Path=/")
+                    .header(LOCATION, "/auth/start")
+                    .body(Body::empty())
+                    .unwrap(),
+                "/auth/start" if cookie.contains("flow=1") => page(
+                    r#"<p class="flow" data-id="f-42"></p>
+                    <form method="post" action="password">
+                        <input type="password" name="secret">
+                        <input type="hidden" name="flow_id">
+                    </form>"#,
+                ),
+                "/auth/password" if has("secret", "s3cret") && has("flow_id", "f-42") => {
+                    Response::builder()
+                        .header(SET_COOKIE, "flow=2; Path=/")
+                        .body(Body::from(
+                            r#"<form method="post" action="/consent">
+                                <button name="allow" value="yes">Allow</button>
+                            </form>"#,
+                        ))
+                        .unwrap()
+                }
+                "/consent" if has("allow", "yes") && cookie == "flow=2" => {
+                    page("<h1>Welcome alice</h1>")
+                }
+                _ => Response::builder()
+                    .status(401)
+                    .body(Body::from("<p class=\"error\">Denied</p>"))
+                    .unwrap(),
+            }
+        })
+    }
+
+    fn route(upstream: &str, secret: &str, timeout: u64) -> FormRoute {
+        let config: Value = toml::from_str(&format!(
+            r#"
+            [redirections]
+            "/" = "{upstream}/"
+
+            [form."{upstream}/login"]
+            username = "alice"
+            password = "{secret}"
+
+            [[form."{upstream}/login".steps]]
+            selector = "form#identify"
+            fields = {{ login = "{{{{username}}}}" }}
+
+            [[form."{upstream}/login".steps]]
+            url = "{upstream}/auth/start"
+            extract = {{ flow = "p.flow @data-id" }}
+            fields = {{ secret = "{{{{password}}}}", flow_id = "{{{{flow}}}}" }}
+            success = {{ absent = ".error" }}
+            timeout = {timeout}
+
+            [[form."{upstream}/login".steps]]
+            success = {{ status = 200, contains = "Welcome" }}
+            "#,
+        ))
+        .unwrap();
+
+        setup_form(config)
+            .unwrap()
+            .remove(&format!("{}/login", upstream))
+            .unwrap()
+    }
+
+    async fn login(upstream: &str, route: &FormRoute) -> Result<String, ()> {
+        let config: Value =
+            toml::from_str(&format!("[redirections]\n\"/\" = \"{}/\"\n", upstream)).unwrap();
+        let start = FetchedPage::new(
+            &format!("{}/login", upstream),
+            HeaderMap::new(),
+            IDENTIFY.to_string(),
+        );
+        let key = format!("{}/login", upstream);
+        let response = run(
+            &key,
+            route,
+            start,
+            Arc::new(mock::http_client()),
+            LoginCookies::Session(String::new()),
+            &config,
+            None,
+        )
+        .await
+        .map_err(|_| ())?;
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        Ok(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[test]
+    fn test_steps() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream(Duration::ZERO);
+
+            let welcome = login(&upstream, &route(&upstream, "s3cret", 5)).await;
+            assert_eq!(welcome, Ok("<h1>Welcome alice</h1>".to_string()));
+            assert_eq!(
+                login(&upstream, &route(&upstream, "wrong", 5)).await,
+                Err(())
+            );
+        });
+    }
+
+    #[test]
+    fn test_step_timeout() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let upstream = mock_upstream(Duration::from_millis(600));
+
+            assert_eq!(
+                login(&upstream, &route(&upstream, "s3cret", 1)).await,
+                Err(())
+            );
+        });
+    }
+
+    #[test]
+    fn test_step_redirected_off_origin() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let collected = Arc::new(std::sync::Mutex::new(Vec::new()));
+            let seen = collected.clone();
+            let elsewhere = mock::serve(move |req: Request<Body>| {
+                seen.lock()
+                    .unwrap()
+                    .push(req.headers().get(COOKIE).cloned());
+                async { page("<h1>Welcome alice</h1>") }
+            });
+            let upstream = mock::serve(move |req: Request<Body>| {
+                let location = format!("{}/collect", elsewhere);
+                async move {
+                    match req.uri().path() {
+                        "/identify" => Response::builder()
+                            .status(303)
+                            .header(SET_COOKIE, "flow=1; Path=/")
+                            .header(LOCATION, location)
+                            .body(Body::empty())
+                            .unwrap(),
+                        _ => page("<p class=\"error\">Denied</p>"),
+                    }
+                }
+            });
+
+            assert_eq!(
+                login(&upstream, &route(&upstream, "s3cret", 5)).await,
+                Err(())
+            );
+            assert!(collected.lock().unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn test_extraction() {
+        let body = r#"<div id="a" data-x="1"> text </div><script>var token = "t-9";</script>"#;
+        let document = Html::parse_document(body);
+        let extract = |spec: &str| Extraction::parse(spec).unwrap().extract(body, &document);
+
+        assert_eq!(extract("#a @data-x"), Some("1".to_string()));
+        assert_eq!(extract("div#a"), Some("text".to_string()));
+        assert_eq!(
+            extract(r#"regex:token = "([^"]+)""#),
+            Some("t-9".to_string())
+        );
+        assert_eq!(extract("#missing"), None);
+        assert!(Extraction::parse("regex:(").is_none());
+    }
+
+    #[test]
+    fn test_merge_cookies() {
+        assert_eq!(
+            merge_cookies("a=1; flow=1", "flow=2; b=3; "),
+            "a=1; flow=2; b=3"
+        );
+        assert_eq!(merge_cookies("", ""), "");
+    }
+}
@@ -0,0 +1,221 @@
+use hyper::{
+    header::{HeaderMap, LOCATION, SET_COOKIE},
+    StatusCode,
+};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::{LoginOwner, Requester};
+
+pub const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(300);
+
+/// What tells a login form submission worked, from [form."url".success].
+/// The status, redirection and cookie are those of the answer to the POST,
+/// the body patterns apply to the page it leads to.
+#[derive(Debug, Clone)]
+pub struct LoginSuccess {
+    pub status: Vec<u16>,
+    pub redirect: Option<Regex>,
+    pub cookie: Option<String>,
+    pub present: Option<Regex>,
+    pub absent: Option<Regex>,
+    /// How long a failed login isn't submitted again
+    pub retry_after: Duration,
+}
+
+// Route and whose login it is, None for anonymous browsers without a
+// proxy session, which all log in with the same credentials
+type FailureKey = (String, Option<LoginOwner>);
+
+// Last failed login per route and user or proxy session
+static FAILURES: Lazy<Mutex<HashMap<FailureKey, Instant>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl Default for LoginSuccess {
+    fn default() -> Self {
+        LoginSuccess {
+            status: Vec::new(),
+            redirect: None,
+            cookie: None,
+            present: None,
+            absent: None,
+            retry_after: DEFAULT_RETRY_AFTER,
+        }
+    }
+}
+
+fn cookie_name(set_cookie: &str) -> &str {
+    set_cookie
+        .split(';')
+        .next()
+        .and_then(|pair| pair.split_once('='))
+        .map(|(name, _)| name.trim())
+        .unwrap_or("")
+}
+
+impl LoginSuccess {
+    /// Checks the answer to the login POST itself.
+    pub fn check_answer(&self, status: StatusCode, headers: &HeaderMap) -> Result<(), String> {
+        let expected = match self.status.is_empty() {
+            true => status.as_u16() < 400,
+            false => self.status.contains(&status.as_u16()),
+        };
+        if !expected {
+            return Err(format!("upstream answered {}", status));
+        }
+        if let Some(redirect) = &self.redirect {
+            let location = headers
+                .get(LOCATION)
+                .and_then(|location| location.to_str().ok());
+            match location {
+                Some(location) if redirect.is_match(location) => {}
+                Some(location) => return Err(format!("redirected to {}", location)),
+                None => return Err("no redirection".to_string()),
+            }
+        }
+        if let Some(cookie) = &self.cookie {
+            let is_set = headers
+                .get_all(SET_COOKIE)
+                .iter()
+                .filter_map(|set_cookie| set_cookie.to_str().ok())
+                .any(|set_cookie| cookie_name(set_cookie) == cookie);
+            if !is_set {
+                return Err(format!("no {} cookie set", cookie));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether nothing was configured to tell a login worked, leaving it to
+    /// the status and to the page it leads to not being a login page.
+    pub fn is_default(&self) -> bool {
+        self.status.is_empty()
+            && self.redirect.is_none()
+            && self.cookie.is_none()
+            && !self.checks_body()
+    }
+
+    pub fn checks_body(&self) -> bool {
+        self.present.is_some() || self.absent.is_some()
+    }
+
+    /// Checks the page the login led to.
+    pub fn check_body(&self, body: &str) -> Result<(), String> {
+        if let Some(present) = &self.present {
+            if !present.is_match(body) {
+                return Err(format!("response doesn't match {}", present));
+            }
+        }
+        if let Some(absent) = &self.absent {
+            if absent.is_match(body) {
+                return Err(format!("response matches {}", absent));
+            }
+        }
+        Ok(())
+    }
+}
+
+fn failure_key(route: &str, requester: Requester<'_>) -> FailureKey {
+    (route.to_string(), requester.owner())
+}
+
+/// Whether the login of `route` failed less than `retry_after` ago.
+pub fn recently_failed(route: &str, requester: Requester<'_>, retry_after: Duration) -> bool {
+    let failures = FAILURES.lock().unwrap_or_else(PoisonError::into_inner);
+
+    failures
+        .get(&failure_key(route, requester))
+        .is_some_and(|failed_at| failed_at.elapsed() < retry_after)
+}
+
+pub fn record_failure(route: &str, requester: Requester<'_>) {
+    let mut failures = FAILURES.lock().unwrap_or_else(PoisonError::into_inner);
+    failures.insert(failure_key(route, requester), Instant::now());
+}
+
+pub fn clear_failure(route: &str, requester: Requester<'_>) {
+    let mut failures = FAILURES.lock().unwrap_or_else(PoisonError::into_inner);
+    failures.remove(&failure_key(route, requester));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::{jar, portal::ProxyUser, sessions::CookieSettings};
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn test_check() {
+        let success = LoginSuccess {
+            status: vec![302],
+            redirect: Some(Regex::new("^/home").unwrap()),
+            cookie: Some("sid".to_string()),
+            present: Some(Regex::new("Welcome").unwrap()),
+            absent: Some(Regex::new("(?i)invalid password").unwrap()),
+            ..LoginSuccess::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(LOCATION, HeaderValue::from_static("/home?first=1"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("lang=en"));
+        headers.append(SET_COOKIE, HeaderValue::from_static("sid=42; HttpOnly"));
+
+        assert_eq!(success.check_answer(StatusCode::FOUND, &headers), Ok(()));
+        assert!(success.check_answer(StatusCode::OK, &headers).is_err());
+        headers.insert(LOCATION, HeaderValue::from_static("/login?error=1"));
+        assert!(success.check_answer(StatusCode::FOUND, &headers).is_err());
+
+        assert_eq!(success.check_body("<h1>Welcome alice</h1>"), Ok(()));
+        assert!(success.check_body("Welcome. Invalid password").is_err());
+        assert!(success.check_body("<form></form>").is_err());
+
+        let default = LoginSuccess::default();
+        assert_eq!(
+            default.check_answer(StatusCode::OK, &HeaderMap::new()),
+            Ok(())
+        );
+        assert!(default
+            .check_answer(StatusCode::UNAUTHORIZED, &HeaderMap::new())
+            .is_err());
+        assert!(!default.checks_body());
+        assert!(default.is_default());
+        assert!(!success.is_default());
+    }
+
+    #[test]
+    fn test_failures() {
+        let user = ProxyUser::new("failure-test", Vec::new());
+        let user = Requester {
+            user: Some(&user),
+            jar: None,
+        };
+        let new_jar = || jar::open(&mut HeaderMap::new(), &CookieSettings::default(), None);
+        let (first, second) = (new_jar(), new_jar());
+        let browser = |jar| Requester { user: None, jar };
+        let route = "https://failure.example/login";
+
+        assert!(!recently_failed(route, user, DEFAULT_RETRY_AFTER));
+        record_failure(route, user);
+        assert!(recently_failed(route, user, DEFAULT_RETRY_AFTER));
+        assert!(!recently_failed(route, user, Duration::ZERO));
+        assert!(!recently_failed(route, browser(None), DEFAULT_RETRY_AFTER));
+        clear_failure(route, user);
+        assert!(!recently_failed(route, user, DEFAULT_RETRY_AFTER));
+
+        record_failure(route, browser(Some(&first)));
+        assert!(recently_failed(
+            route,
+            browser(Some(&first)),
+            DEFAULT_RETRY_AFTER
+        ));
+        assert!(!recently_failed(
+            route,
+            browser(Some(&second)),
+            DEFAULT_RETRY_AFTER
+        ));
+    }
+}
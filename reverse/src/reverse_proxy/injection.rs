@@ -0,0 +1,217 @@
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION},
+    Body, Client, Request, Uri,
+};
+use hyper_tls::HttpsConnector;
+
+use std::str::FromStr;
+use toml::Value;
+use url::Url;
+
+use super::{
+    config::setup_credentials,
+    oauth2::{self, OAuth2Client},
+    scope::match_scope,
+};
+
+/// Static credential added by the proxy to every request of a route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InjectedCredential {
+    Bearer(String),
+    Header { name: HeaderName, value: String },
+    Query { name: String, value: String },
+}
+
+/// Credential configured for a route in the [credentials] table.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RouteCredential {
+    Static(InjectedCredential),
+    OAuth2(OAuth2Client),
+}
+
+impl InjectedCredential {
+    pub fn new(kind: &str, name: Option<&str>, value: &str) -> Option<Self> {
+        match (kind, name) {
+            ("bearer", _) => Some(InjectedCredential::Bearer(value.to_string())),
+            ("header", Some(name)) => match HeaderName::from_str(name) {
+                Ok(name) => Some(InjectedCredential::Header {
+                    name,
+                    value: value.to_string(),
+                }),
+                Err(err) => {
+                    eprintln!("Invalid header name {}: {}", name, err);
+                    None
+                }
+            },
+            ("query", Some(name)) => Some(InjectedCredential::Query {
+                name: name.to_string(),
+                value: value.to_string(),
+            }),
+            _ => {
+                eprintln!("Unsupported credential type {} (name: {:?})", kind, name);
+                None
+            }
+        }
+    }
+
+    fn header_name(&self) -> Option<&HeaderName> {
+        match self {
+            InjectedCredential::Bearer(_) => Some(&AUTHORIZATION),
+            InjectedCredential::Header { name, .. } => Some(name),
+            InjectedCredential::Query { .. } => None,
+        }
+    }
+}
+
+impl RouteCredential {
+    /// Turns the route credential into the value to inject, acquiring an
+    /// access token for OAuth2 routes.
+    pub async fn resolve(
+        &self,
+        client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+    ) -> Result<InjectedCredential, ()> {
+        match self {
+            RouteCredential::Static(credential) => Ok(credential.clone()),
+            RouteCredential::OAuth2(oauth) => {
+                let token = oauth2::access_token(oauth, client).await?;
+                Ok(InjectedCredential::Bearer(token))
+            }
+        }
+    }
+
+    fn header_name(&self) -> Option<&HeaderName> {
+        match self {
+            RouteCredential::Static(credential) => credential.header_name(),
+            RouteCredential::OAuth2(_) => Some(&AUTHORIZATION),
+        }
+    }
+}
+
+fn replace_query_param(uri: &Uri, name: &str, value: &str) -> Option<Uri> {
+    let mut url = Url::parse(&uri.to_string()).ok()?;
+    let kept: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(key, _)| key != name)
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect();
+
+    url.query_pairs_mut()
+        .clear()
+        .extend_pairs(kept)
+        .append_pair(name, value);
+    Uri::from_str(url.as_str()).ok()
+}
+
+/// Sets the credential on `req`, dropping whatever the client sent under the same name.
+pub fn inject(req: &mut Request<Body>, credential: &InjectedCredential) {
+    match credential {
+        InjectedCredential::Bearer(token) => {
+            req.headers_mut().remove(AUTHORIZATION);
+            match HeaderValue::from_str(&format!("Bearer {}", token)) {
+                Ok(value) => {
+                    req.headers_mut().insert(AUTHORIZATION, value);
+                }
+                Err(err) => eprintln!("Invalid bearer token: {}", err),
+            }
+        }
+        InjectedCredential::Header { name, value } => {
+            req.headers_mut().remove(name);
+            match HeaderValue::from_str(value) {
+                Ok(value) => {
+                    req.headers_mut().insert(name, value);
+                }
+                Err(err) => eprintln!("Invalid value for header {}: {}", name, err),
+            }
+        }
+        InjectedCredential::Query { name, value } => {
+            match replace_query_param(req.uri(), name, value) {
+                Some(uri) => *req.uri_mut() = uri,
+                None => eprintln!("Failed to add query parameter {} to {}", name, req.uri()),
+            }
+        }
+    }
+    println!("Route credential injected");
+}
+
+/// Keeps an injected header from being echoed back to the client.
+pub fn strip_response(headers: &mut HeaderMap, credential: &RouteCredential) {
+    if let Some(name) = credential.header_name() {
+        headers.remove(name);
+    }
+}
+
+pub fn find_credential(uri: &str, config: Value) -> Option<RouteCredential> {
+    let credentials = setup_credentials(config)?;
+
+    match_scope(&credentials, uri, None).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str) -> Request<Body> {
+        Request::builder()
+            .uri(uri)
+            .header(AUTHORIZATION, "Bearer client-token")
+            .header("x-api-key", "client-key")
+            .header("x-api-key", "other-client-key")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn test_inject_bearer() {
+        let mut req = request("http://host/api");
+        inject(&mut req, &InjectedCredential::Bearer("secret".to_string()));
+
+        let values: Vec<_> = req.headers().get_all(AUTHORIZATION).iter().collect();
+        assert_eq!(values, vec!["Bearer secret"]);
+    }
+
+    #[test]
+    fn test_inject_header() {
+        let mut req = request("http://host/api");
+        let credential = InjectedCredential::new("header", Some("X-API-Key"), "secret").unwrap();
+        inject(&mut req, &credential);
+
+        let values: Vec<_> = req.headers().get_all("x-api-key").iter().collect();
+        assert_eq!(values, vec!["secret"]);
+        assert!(req.headers().contains_key(AUTHORIZATION));
+    }
+
+    #[test]
+    fn test_inject_query() {
+        let mut req = request("http://host/api?api_key=client&page=2&api_key=other");
+        let credential = InjectedCredential::new("query", Some("api_key"), "s3cr3t&").unwrap();
+        inject(&mut req, &credential);
+
+        assert_eq!(
+            req.uri().to_string(),
+            "http://host/api?page=2&api_key=s3cr3t%26"
+        );
+    }
+
+    #[test]
+    fn test_strip_response() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("secret"));
+        headers.insert("x-request-id", HeaderValue::from_static("42"));
+
+        let credential = InjectedCredential::new("header", Some("X-API-Key"), "secret").unwrap();
+        strip_response(&mut headers, &RouteCredential::Static(credential));
+        assert!(!headers.contains_key("x-api-key"));
+        assert!(headers.contains_key("x-request-id"));
+    }
+
+    #[test]
+    fn test_new_invalid() {
+        assert_eq!(InjectedCredential::new("header", None, "secret"), None);
+        assert_eq!(InjectedCredential::new("query", None, "secret"), None);
+        assert_eq!(InjectedCredential::new("cookie", Some("a"), "secret"), None);
+        assert_eq!(
+            InjectedCredential::new("header", Some("bad name"), "x"),
+            None
+        );
+    }
+}
@@ -0,0 +1,616 @@
+use hyper::header::{HeaderMap, HeaderValue, COOKIE, SET_COOKIE};
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    str,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use url::Url;
+
+use super::{
+    cookie::{parse_set_cookie, read_cookies},
+    cookie_replacement::CookieReplacements,
+    portal::{new_token, ProxyUser},
+    sessions::{header_map_update, replace_set_cookies, CookieSettings},
+};
+
+// https://httpwg.org/specs/rfc6265.html#storage-model
+
+const JAR_COOKIE: &str = "proxy_jar";
+// How often jars left unused are dropped
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+// Suffixes under which anyone can register a domain, on top of the
+// top-level domains. A subset of https://publicsuffix.org/list/
+const PUBLIC_SUFFIXES: &[&str] = &[
+    "co.uk", "org.uk", "ac.uk", "gov.uk", "com.au", "net.au", "org.au", "co.nz", "co.jp", "ne.jp",
+    "or.jp", "co.kr", "com.br", "com.cn", "com.tw", "com.mx", "co.in", "co.za",
+];
+
+/// A cookie an upstream set, as stored by the proxy.
+#[derive(Debug, Clone, PartialEq)]
+struct StoredCookie {
+    name: String,
+    value: String,
+    domain: String,
+    host_only: bool,
+    path: String,
+    secure: bool,
+    // Unix time, none for a session cookie
+    expires: Option<u64>,
+    // Creation order, for the Cookie header ordering
+    order: u64,
+}
+
+impl StoredCookie {
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Upstream cookies of one downstream session, following RFC 6265.
+#[derive(Debug, Default)]
+pub struct CookieJar {
+    cookies: Vec<StoredCookie>,
+    next_order: u64,
+}
+
+/// The jar of the browser sending a request. A new one only exists once
+/// an upstream sets a cookie.
+#[derive(Debug, PartialEq)]
+pub struct JarSession {
+    pub id: String,
+    pub is_new: bool,
+    // Portal user the jar is for
+    user: Option<String>,
+    idle: Duration,
+    max_jars: usize,
+    // Hides the cookies scripts read too
+    hide_all: bool,
+}
+
+struct OpenJar {
+    jar: CookieJar,
+    // Tokens the browser holds instead of the upstream values
+    replacements: CookieReplacements,
+    user: Option<String>,
+    idle: Duration,
+    last_used: Instant,
+}
+
+impl OpenJar {
+    fn is_idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_used) >= self.idle
+    }
+}
+
+static JARS: Lazy<Mutex<HashMap<String, OpenJar>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// RFC 6265 5.1.3: `host` is `domain` or one of its subdomains.
+pub fn domain_match(host: &str, domain: &str) -> bool {
+    if host == domain {
+        return true;
+    }
+    host.parse::<IpAddr>().is_err()
+        && host
+            .strip_suffix(domain)
+            .is_some_and(|prefix| prefix.ends_with('.'))
+}
+
+/// RFC 6265 5.3 step 5: nobody owns `domain`, a cookie can't be set for it.
+pub fn is_public_suffix(domain: &str) -> bool {
+    !domain.contains('.') || PUBLIC_SUFFIXES.contains(&domain)
+}
+
+/// RFC 6265 5.1.4: the directory of the request path.
+pub fn default_path(request_path: &str) -> String {
+    if !request_path.starts_with('/') {
+        return "/".to_string();
+    }
+    match request_path.rfind('/') {
+        Some(0) | None => "/".to_string(),
+        Some(last) => request_path[..last].to_string(),
+    }
+}
+
+/// RFC 6265 5.1.4: `request_path` lies under `cookie_path`.
+pub fn path_match(request_path: &str, cookie_path: &str) -> bool {
+    if request_path == cookie_path {
+        return true;
+    }
+    request_path
+        .strip_prefix(cookie_path)
+        .is_some_and(|rest| cookie_path.ends_with('/') || rest.starts_with('/'))
+}
+
+fn request_host(url: &Url) -> Option<String> {
+    url.host_str().map(|host| {
+        host.trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_lowercase()
+    })
+}
+
+impl CookieJar {
+    /// Stores the cookie of a `Set-Cookie` header answering `url`.
+    pub fn store(&mut self, set_cookie: &[u8], url: &Url, now: u64) {
+        let cookie = match parse_set_cookie(set_cookie, now) {
+            Some(cookie) => cookie,
+            None => return,
+        };
+        let host = match request_host(url) {
+            Some(host) => host,
+            None => return,
+        };
+        let secure_origin = url.scheme() == "https";
+        if cookie.attrs.secure && !secure_origin {
+            return;
+        }
+
+        let domain = String::from_utf8_lossy(cookie.attrs.domain).to_lowercase();
+        let (domain, host_only) = match domain.is_empty() {
+            true => (host.clone(), true),
+            false if is_public_suffix(&domain) && domain == host => (host.clone(), true),
+            false if is_public_suffix(&domain) => return,
+            false if domain_match(&host, &domain) => (domain, false),
+            false => return,
+        };
+        let path = match cookie.attrs.path {
+            [b'/', ..] => String::from_utf8_lossy(cookie.attrs.path).into_owned(),
+            _ => default_path(url.path()),
+        };
+        let mut stored = StoredCookie {
+            name: String::from_utf8_lossy(cookie.name).into_owned(),
+            value: String::from_utf8_lossy(cookie.value).into_owned(),
+            domain,
+            host_only,
+            path,
+            secure: cookie.attrs.secure,
+            expires: cookie.attrs.max_age.map(|max_age| now + max_age as u64),
+            order: self.next_order,
+        };
+
+        let replaced = self.cookies.iter().position(|known| {
+            known.name == stored.name && known.domain == stored.domain && known.path == stored.path
+        });
+        match replaced {
+            Some(index) => {
+                stored.order = self.cookies[index].order;
+                self.cookies[index] = stored;
+            }
+            None => {
+                self.next_order += 1;
+                self.cookies.push(stored);
+            }
+        }
+        self.cookies.retain(|cookie| !cookie.is_expired(now));
+    }
+
+    /// `Cookie` header value for a request to `url`.
+    pub fn header(&self, url: &Url, now: u64) -> Option<String> {
+        let host = request_host(url)?;
+        let secure_origin = url.scheme() == "https";
+        let mut cookies: Vec<&StoredCookie> = self
+            .cookies
+            .iter()
+            .filter(|cookie| match cookie.host_only {
+                true => cookie.domain == host,
+                false => domain_match(&host, &cookie.domain),
+            })
+            .filter(|cookie| path_match(url.path(), &cookie.path))
+            .filter(|cookie| secure_origin || !cookie.secure)
+            .filter(|cookie| !cookie.is_expired(now))
+            .collect();
+        // Longer paths first, then the oldest cookies
+        cookies.sort_by(|a, b| b.path.len().cmp(&a.path.len()).then(a.order.cmp(&b.order)));
+
+        match cookies.is_empty() {
+            true => None,
+            false => Some(
+                cookies
+                    .iter()
+                    .map(|cookie| format!("{}={}", cookie.name, cookie.value))
+                    .collect::<Vec<String>>()
+                    .join("; "),
+            ),
+        }
+    }
+}
+
+/// Jar of the browser sending `headers` for `user`, a new one when it has
+/// none, left it unused for `jar_idle` or got it as another user. The jar
+/// cookie is taken out of the headers.
+pub fn open(
+    headers: &mut HeaderMap,
+    settings: &CookieSettings,
+    user: Option<&ProxyUser>,
+) -> JarSession {
+    let mut kept = Vec::new();
+    let mut id = None;
+    for (name, value) in headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|cookie| read_cookies(cookie.as_bytes()))
+    {
+        match name == JAR_COOKIE.as_bytes() {
+            true => id = str::from_utf8(value).ok().map(|value| value.to_string()),
+            false => kept.push(format!(
+                "{}={}",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(value)
+            )),
+        }
+    }
+    headers.remove(COOKIE);
+    if let Ok(cookie) = HeaderValue::from_str(&kept.join("; ")) {
+        if !kept.is_empty() {
+            headers.insert(COOKIE, cookie);
+        }
+    }
+
+    let user = user.map(|user| user.name.clone());
+    let mut session = JarSession {
+        id: new_token(),
+        is_new: true,
+        user,
+        idle: settings.jar_idle,
+        max_jars: settings.jar_max,
+        hide_all: settings.replace_all,
+    };
+    let id = match id {
+        Some(id) => id,
+        None => return session,
+    };
+    let mut jars = JARS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+    let open = match jars.get_mut(&id) {
+        Some(open) => open,
+        None => return session,
+    };
+    open.idle = session.idle;
+    if open.user != session.user {
+        println!("Jar opened by another user, starting a new one");
+        jars.remove(&id);
+        return session;
+    }
+    if open.is_idle(now) {
+        jars.remove(&id);
+        return session;
+    }
+
+    open.last_used = now;
+    session.id = id;
+    session.is_new = false;
+    session
+}
+
+// Runs `f` on the jar of `session`, creating it first when `create` holds
+// and fewer than `max_jars` are open
+fn with_jar<T>(session: &JarSession, create: bool, f: impl FnOnce(&mut OpenJar) -> T) -> Option<T> {
+    let mut jars = JARS.lock().unwrap_or_else(PoisonError::into_inner);
+    if !jars.contains_key(&session.id) {
+        if !create {
+            return None;
+        }
+        if jars.len() >= session.max_jars {
+            eprintln!(
+                "Jar Error: {} jars open, upstream cookies not kept",
+                jars.len()
+            );
+            return None;
+        }
+        let open = OpenJar {
+            jar: CookieJar::default(),
+            replacements: CookieReplacements::new(),
+            user: session.user.clone(),
+            idle: session.idle,
+            last_used: Instant::now(),
+        };
+        jars.insert(session.id.clone(), open);
+    }
+
+    jars.get_mut(&session.id).map(f)
+}
+
+/// Whether the jar of `session` exists, its cookie being due to the browser.
+pub fn is_open(session: &JarSession) -> bool {
+    with_jar(session, false, |_| ()).is_some()
+}
+
+/// Opens the jar of `session` even without cookies, so the browser keeps
+/// its proxy session.
+pub fn keep(session: &JarSession) {
+    with_jar(session, true, |_| ());
+}
+
+/// Drops the jars left unused for their `jar_idle`, every minute.
+pub async fn sweep() {
+    loop {
+        tokio::time::sleep(SWEEP_INTERVAL).await;
+        let mut jars = JARS.lock().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+        jars.retain(|_, open| !open.is_idle(now));
+    }
+}
+
+/// `Cookie` header value of the jar of `session` for a request to `url`.
+pub fn cookie_header(session: &JarSession, url: &str) -> Option<String> {
+    let url = Url::parse(url).ok()?;
+
+    with_jar(session, false, |open| open.jar.header(&url, unix_time()))?
+}
+
+/// Sends the cookies of the jar of `session` matching `url` along with the
+/// ones of `headers`, the jar's winning over the browser's.
+pub fn attach(session: &JarSession, url: &str, headers: &mut HeaderMap) {
+    let jar_cookies = match cookie_header(session, url) {
+        Some(jar_cookies) => jar_cookies,
+        None => return,
+    };
+    let jar_names: Vec<&[u8]> = read_cookies(jar_cookies.as_bytes())
+        .map(|(name, _)| name)
+        .collect();
+    let mut cookies: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|cookie| read_cookies(cookie.as_bytes()))
+        .filter(|(name, _)| !jar_names.contains(name))
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(value)
+            )
+        })
+        .collect();
+    cookies.push(jar_cookies);
+
+    if let Ok(cookie) = HeaderValue::from_str(&cookies.join("; ")) {
+        headers.insert(COOKIE, cookie);
+    }
+}
+
+/// Keeps in the jar of `session` the cookies a response from `url` sets.
+pub fn store(session: &JarSession, url: &str, headers: &HeaderMap) {
+    let url = match Url::parse(url) {
+        Ok(url) => url,
+        Err(_) => return,
+    };
+    if !headers.contains_key(SET_COOKIE) {
+        return;
+    }
+    with_jar(session, true, |open| {
+        let now = unix_time();
+        for set_cookie in headers.get_all(SET_COOKIE) {
+            open.jar.store(set_cookie.as_bytes(), &url, now);
+        }
+    });
+}
+
+/// Gives the upstream values back to the cookies the browser of `session`
+/// only knows by their token.
+pub fn reveal_cookies(session: &JarSession, headers: &mut HeaderMap) {
+    with_jar(session, false, |open| {
+        header_map_update(headers, COOKIE, &open.replacements)
+    });
+}
+
+/// Swaps the values of the cookies `headers` set, answering a request for
+/// `request_path`, for tokens only the jar of `session` maps back.
+pub fn hide_cookies(session: &JarSession, request_path: &str, headers: &mut HeaderMap) {
+    if !headers.contains_key(SET_COOKIE) {
+        return;
+    }
+    with_jar(session, true, |open| {
+        replace_set_cookies(
+            headers,
+            request_path,
+            session.hide_all,
+            &mut open.replacements,
+        )
+    });
+}
+
+/// `Set-Cookie` handing the jar `id` to the browser, `Secure` when it
+/// reached the proxy over HTTPS.
+pub fn jar_cookie(id: &str, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly{}; SameSite=Lax",
+        JAR_COOKIE,
+        id,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn url(url: &str) -> Url {
+        Url::parse(url).unwrap()
+    }
+
+    fn header(jar: &CookieJar, target: &str, now: u64) -> Option<String> {
+        jar.header(&url(target), now)
+    }
+
+    #[test]
+    fn test_matching() {
+        assert!(domain_match("example.com", "example.com"));
+        assert!(domain_match("www.example.com", "example.com"));
+        assert!(!domain_match("wwwexample.com", "example.com"));
+        assert!(!domain_match("example.com", "www.example.com"));
+        assert!(!domain_match("10.0.0.1", "0.0.1"));
+
+        assert_eq!(default_path(""), "/");
+        assert_eq!(default_path("/"), "/");
+        assert_eq!(default_path("/login"), "/");
+        assert_eq!(default_path("/app/login"), "/app");
+        assert_eq!(default_path("/app/"), "/app");
+
+        assert!(path_match("/app", "/app"));
+        assert!(path_match("/app/page", "/app"));
+        assert!(path_match("/app/page", "/app/"));
+        assert!(path_match("/app", "/"));
+        assert!(!path_match("/application", "/app"));
+        assert!(!path_match("/", "/app"));
+    }
+
+    #[test]
+    fn test_store_and_send() {
+        let mut jar = CookieJar::default();
+        let login = url("https://app.example.com/account/login");
+
+        jar.store(b"sid=1; Path=/; Secure; HttpOnly", &login, 1000);
+        jar.store(b"pref=dark", &login, 1000);
+        jar.store(b"wide=yes; Domain=.example.com; Path=/", &login, 1000);
+        jar.store(b"foreign=no; Domain=other.example", &login, 1000);
+        jar.store(b"deep=1; Path=/account/settings", &login, 1000);
+        jar.store(b"short=1; Max-Age=10", &login, 1000);
+
+        assert_eq!(
+            header(&jar, "https://app.example.com/account/settings/x", 1000).as_deref(),
+            Some("deep=1; pref=dark; short=1; sid=1; wide=yes")
+        );
+        assert_eq!(
+            header(&jar, "https://app.example.com/home", 1000).as_deref(),
+            Some("sid=1; wide=yes")
+        );
+        assert_eq!(
+            header(&jar, "http://app.example.com/home", 1000).as_deref(),
+            Some("wide=yes")
+        );
+        assert_eq!(
+            header(&jar, "https://api.example.com/account/x", 1020).as_deref(),
+            Some("wide=yes")
+        );
+        assert_eq!(header(&jar, "https://other.example/", 1000), None);
+
+        jar.store(b"sid=2; Path=/", &login, 1010);
+        jar.store(b"pref=; Max-Age=0", &login, 1010);
+        assert_eq!(
+            header(&jar, "https://app.example.com/account/", 1020).as_deref(),
+            Some("sid=2; wide=yes")
+        );
+        jar.store(b"secure=1; Secure", &url("http://app.example.com/"), 1000);
+        assert!(!header(&jar, "https://app.example.com/", 1020)
+            .unwrap()
+            .contains("secure"));
+    }
+
+    #[test]
+    fn test_public_suffix() {
+        let mut jar = CookieJar::default();
+        let login = url("https://app.example.co.uk/login");
+
+        jar.store(b"tld=1; Domain=uk", &login, 1000);
+        jar.store(b"suffix=1; Domain=co.uk", &login, 1000);
+        jar.store(b"site=1; Domain=example.co.uk", &login, 1000);
+        assert_eq!(
+            header(&jar, "https://www.example.co.uk/", 1000).as_deref(),
+            Some("site=1")
+        );
+
+        let mut jar = CookieJar::default();
+        jar.store(
+            b"local=1; Domain=localhost",
+            &url("http://localhost/"),
+            1000,
+        );
+        assert_eq!(
+            header(&jar, "http://localhost/", 1000).as_deref(),
+            Some("local=1")
+        );
+        assert!(jar.cookies[0].host_only);
+        assert!(is_public_suffix("com"));
+        assert!(!is_public_suffix("example.com"));
+    }
+
+    #[test]
+    fn test_sessions() {
+        let settings = CookieSettings {
+            jar_idle: Duration::from_secs(60),
+            ..CookieSettings::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("theme=light; sid=browser"));
+        let session = open(&mut headers, &settings, None);
+        assert!(session.is_new);
+        assert_eq!(headers[COOKIE], "theme=light; sid=browser");
+
+        // Only an upstream setting a cookie opens the jar
+        store(&session, "http://app.example/page", &HeaderMap::new());
+        assert!(!is_open(&session));
+        let mut response = HeaderMap::new();
+        response.append(SET_COOKIE, HeaderValue::from_static("sid=upstream; Path=/"));
+        store(&session, "http://app.example/login", &response);
+        assert!(is_open(&session));
+
+        assert!(jar_cookie(&session.id, true).starts_with(&format!("proxy_jar={};", session.id)));
+        let cookie = format!("proxy_jar={}; a=1", session.id);
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        let reopened = open(&mut headers, &settings, None);
+        assert_eq!(reopened.id, session.id);
+        assert!(!reopened.is_new);
+        assert_eq!(headers[COOKIE], "a=1");
+
+        headers.insert(COOKIE, HeaderValue::from_static("a=1; sid=browser"));
+        attach(&session, "http://app.example/page", &mut headers);
+        assert_eq!(headers[COOKIE], "a=1; sid=upstream");
+        let mut other = HeaderMap::new();
+        attach(&session, "http://other.example/", &mut other);
+        assert!(!other.contains_key(COOKIE));
+        assert_eq!(
+            cookie_header(&session, "http://app.example/").as_deref(),
+            Some("sid=upstream")
+        );
+
+        let idle = CookieSettings {
+            jar_idle: Duration::ZERO,
+            ..CookieSettings::default()
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        assert!(open(&mut headers, &idle, None).is_new);
+        assert!(!is_open(&session));
+    }
+
+    #[test]
+    fn test_jar_owner_and_limit() {
+        let settings = CookieSettings::default();
+        let alice = ProxyUser::new("jar-alice", Vec::new());
+        let bob = ProxyUser::new("jar-bob", Vec::new());
+        let session = open(&mut HeaderMap::new(), &settings, Some(&alice));
+        keep(&session);
+
+        let cookie = HeaderValue::from_str(&format!("proxy_jar={}", session.id)).unwrap();
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, cookie.clone());
+        assert!(!open(&mut headers, &settings, Some(&alice)).is_new);
+        headers.insert(COOKIE, cookie);
+        let other = open(&mut headers, &settings, Some(&bob));
+        assert!(other.is_new);
+        assert_ne!(other.id, session.id);
+        assert!(!is_open(&session));
+
+        let full = CookieSettings {
+            jar_max: 0,
+            ..CookieSettings::default()
+        };
+        let refused = open(&mut HeaderMap::new(), &full, None);
+        let mut response = HeaderMap::new();
+        response.append(SET_COOKIE, HeaderValue::from_static("sid=1"));
+        store(&refused, "http://app.example/", &response);
+        assert!(!is_open(&refused));
+    }
+}
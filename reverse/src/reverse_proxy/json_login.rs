@@ -0,0 +1,416 @@
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONTENT_TYPE, COOKIE},
+    Body, Client, Method, Request,
+};
+use hyper_tls::HttpsConnector;
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex as AsyncMutex;
+use toml::Value;
+
+use super::{
+    config::setup_json_login,
+    forms::FormFields,
+    portal::{ProxyUser, UserCredentials},
+    scope::match_scope,
+};
+
+/// Where the login response carries the session token.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenLocation {
+    /// JSON pointer into the response body, such as `/data/token`
+    Pointer(String),
+    Header(HeaderName),
+}
+
+/// How the token goes on the requests of the route.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenInjection {
+    /// Header whose value is `format` with `{token}` replaced
+    Header {
+        name: HeaderName,
+        format: String,
+    },
+    Cookie(String),
+}
+
+/// A [json_login."url"] route: an API login call whose token
+/// authenticates the requests under the route.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonLogin {
+    pub endpoint: String,
+    pub method: Method,
+    /// JSON body where `{{field}}` in strings stands for a credential field
+    pub body: serde_json::Value,
+    pub token: TokenLocation,
+    pub injection: TokenInjection,
+    pub ttl: Duration,
+    pub credentials: UserCredentials<FormFields>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    token: String,
+    expires_at: Instant,
+}
+
+type TokenSlot = Arc<AsyncMutex<Option<CachedToken>>>;
+
+// One slot per login and proxy user, its lock makes concurrent logins single-flight
+static TOKENS: Lazy<Mutex<HashMap<String, TokenSlot>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn fill_template(template: &serde_json::Value, fields: &FormFields) -> serde_json::Value {
+    match template {
+        serde_json::Value::String(text) => {
+            let filled = fields.iter().fold(text.clone(), |text, (name, value)| {
+                text.replace(&format!("{{{{{}}}}}", name), value)
+            });
+            serde_json::Value::String(filled)
+        }
+        serde_json::Value::Array(values) => values
+            .iter()
+            .map(|value| fill_template(value, fields))
+            .collect(),
+        serde_json::Value::Object(entries) => entries
+            .iter()
+            .map(|(key, value)| (key.clone(), fill_template(value, fields)))
+            .collect(),
+        other => other.clone(),
+    }
+}
+
+impl JsonLogin {
+    fn cache_key(&self, user: Option<&ProxyUser>) -> String {
+        format!(
+            "{} {}",
+            self.endpoint,
+            user.map(|user| user.name.as_str()).unwrap_or("")
+        )
+    }
+
+    fn login_request(&self, fields: &FormFields) -> Result<Request<Body>, ()> {
+        let body = fill_template(&self.body, fields).to_string();
+
+        Request::builder()
+            .method(self.method.clone())
+            .uri(&self.endpoint)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .map_err(|err| {
+                eprintln!("JSON login Error: {}", err);
+            })
+    }
+
+    fn extract_token(&self, headers: &HeaderMap, body: &[u8]) -> Option<String> {
+        match &self.token {
+            TokenLocation::Pointer(pointer) => {
+                let json: serde_json::Value = serde_json::from_slice(body).ok()?;
+                match json.pointer(pointer)? {
+                    serde_json::Value::String(token) => Some(token.clone()),
+                    serde_json::Value::Number(token) => Some(token.to_string()),
+                    _ => None,
+                }
+            }
+            TokenLocation::Header(name) => headers
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+fn token_slot(login: &JsonLogin, user: Option<&ProxyUser>) -> TokenSlot {
+    let mut tokens = TOKENS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    tokens.entry(login.cache_key(user)).or_default().clone()
+}
+
+async fn fetch_token(
+    login: &JsonLogin,
+    user: Option<&ProxyUser>,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<CachedToken, ()> {
+    let fields = match login.credentials.resolve(user) {
+        Some(fields) => fields,
+        None => {
+            eprintln!("JSON login Error: No credentials for {}", login.endpoint);
+            return Err(());
+        }
+    };
+    let logged_in_at = Instant::now();
+    let response = client
+        .request(login.login_request(fields)?)
+        .await
+        .map_err(|err| {
+            eprintln!("JSON login Error: Login endpoint unreachable: {}", err);
+        })?;
+    let status = response.status();
+    let (parts, body) = response.into_parts();
+    let body = hyper::body::to_bytes(body).await.map_err(|err| {
+        eprintln!("JSON login Error: {}", err);
+    })?;
+
+    if !status.is_success() {
+        eprintln!("JSON login Error: Login endpoint answered {}", status);
+        return Err(());
+    }
+    let token = match login.extract_token(&parts.headers, &body) {
+        Some(token) => token,
+        None => {
+            eprintln!("JSON login Error: No token in the login response");
+            return Err(());
+        }
+    };
+    println!("Logged in through {}", login.endpoint);
+
+    Ok(CachedToken {
+        token,
+        expires_at: logged_in_at + login.ttl,
+    })
+}
+
+/// Returns the session token of `user` on the route, logging in when there
+/// is none or it is past its ttl. Concurrent callers wait for a single login.
+pub async fn session_token(
+    login: &JsonLogin,
+    user: Option<&ProxyUser>,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<String, ()> {
+    let slot = token_slot(login, user);
+    let mut cached = slot.lock().await;
+
+    if let Some(token) = cached.as_ref() {
+        if Instant::now() < token.expires_at {
+            return Ok(token.token.clone());
+        }
+    }
+
+    let token = fetch_token(login, user, client).await?;
+    let session = token.token.clone();
+    *cached = Some(token);
+    Ok(session)
+}
+
+// Drops `rejected` from the cache, unless another request already replaced it
+async fn invalidate(login: &JsonLogin, user: Option<&ProxyUser>, rejected: &str) {
+    let slot = token_slot(login, user);
+    let mut cached = slot.lock().await;
+
+    if cached.as_ref().is_some_and(|token| token.token == rejected) {
+        *cached = None;
+    }
+}
+
+/// Returns a session token to use in place of one the upstream rejected.
+pub async fn refresh_token(
+    login: &JsonLogin,
+    user: Option<&ProxyUser>,
+    rejected: &str,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<String, ()> {
+    invalidate(login, user, rejected).await;
+    session_token(login, user, client).await
+}
+
+/// Sets the session token on `req`, dropping whatever the client sent under
+/// the same header or cookie name.
+pub fn inject(req: &mut Request<Body>, login: &JsonLogin, token: &str) {
+    let (name, value) = match &login.injection {
+        TokenInjection::Header { name, format } => (name.clone(), format.replace("{token}", token)),
+        TokenInjection::Cookie(cookie) => {
+            let mut cookies: Vec<String> = req
+                .headers()
+                .get_all(COOKIE)
+                .iter()
+                .filter_map(|header| header.to_str().ok())
+                .flat_map(|header| header.split(';'))
+                .map(|pair| pair.trim())
+                .filter(|pair| {
+                    !pair.is_empty() && pair.split('=').next().map(str::trim) != Some(cookie)
+                })
+                .map(|pair| pair.to_string())
+                .collect();
+            cookies.push(format!("{}={}", cookie, token));
+            (COOKIE, cookies.join("; "))
+        }
+    };
+
+    match HeaderValue::from_str(&value) {
+        Ok(value) => {
+            req.headers_mut().insert(name, value);
+        }
+        Err(err) => eprintln!("Invalid session token for {}: {}", name, err),
+    }
+}
+
+pub fn find_login(uri: &str, config: Value) -> Option<JsonLogin> {
+    let logins = setup_json_login(config)?;
+
+    match_scope(&logins, uri, None).cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::mock::{self, http_client};
+    use hyper::Response;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+
+    // Mock login API numbering the sessions it opens
+    fn mock_login_api() -> (String, Arc<AtomicUsize>) {
+        let logins = Arc::new(AtomicUsize::new(0));
+        let counter = logins.clone();
+        let upstream = mock::serve(move |req: Request<Body>| {
+            let counter = counter.clone();
+            async move {
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+
+                if json["auth"]["login"] != "svc" || json["auth"]["password"] != "p\"w" {
+                    return Response::builder().status(401).body(Body::empty()).unwrap();
+                }
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let token = format!(r#"{{"data":{{"token":"session-{}"}}}}"#, n);
+                Response::builder()
+                    .header("x-session", format!("header-{}", n))
+                    .body(Body::from(token))
+                    .unwrap()
+            }
+        });
+
+        (format!("{}/api/login", upstream), logins)
+    }
+
+    fn config(endpoint: &str, password: &str, extra: &str) -> Value {
+        toml::from_str(&format!(
+            r#"
+            [json_login."https://app.example/api/"]
+            endpoint = "{}"
+            body = '{{"auth": {{"login": "{{{{username}}}}", "password": "{{{{password}}}}"}}}}'
+            username = "svc"
+            password = '{}'
+            {}
+            "#,
+            endpoint, password, extra
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_session_token_is_cached() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (endpoint, logins) = mock_login_api();
+            let config = config(&endpoint, "p\"w", r#"token_pointer = "/data/token""#);
+            let login = find_login("https://app.example/api/items", config).unwrap();
+            let client = http_client();
+
+            assert_eq!(
+                session_token(&login, None, &client).await,
+                Ok("session-1".to_string())
+            );
+            assert_eq!(
+                session_token(&login, None, &client).await,
+                Ok("session-1".to_string())
+            );
+            let alice = ProxyUser::new("alice", Vec::new());
+            assert_eq!(
+                session_token(&login, Some(&alice), &client).await,
+                Ok("session-2".to_string())
+            );
+            assert_eq!(logins.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_token_from_header_into_cookie() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (endpoint, _) = mock_login_api();
+            let config = config(
+                &endpoint,
+                "p\"w",
+                "token_header = \"X-Session\"\ncookie = \"sid\"\nttl = 0",
+            );
+            let login = find_login("https://app.example/api/", config).unwrap();
+            let client = http_client();
+
+            let token = session_token(&login, None, &client).await.unwrap();
+            assert!(token.starts_with("header-"));
+
+            let mut req = Request::builder()
+                .header(COOKIE, "theme=dark; sid=forged")
+                .body(Body::empty())
+                .unwrap();
+            inject(&mut req, &login, &token);
+            assert_eq!(req.headers()[COOKIE], format!("theme=dark; sid={}", token));
+
+            // a zero ttl logs in again
+            assert_ne!(session_token(&login, None, &client).await.unwrap(), token);
+        });
+    }
+
+    #[test]
+    fn test_refresh_rejected_token() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (endpoint, logins) = mock_login_api();
+            let config = config(&endpoint, "p\"w", r#"token_pointer = "/data/token""#);
+            let login = find_login("https://app.example/api/", config).unwrap();
+            let bob = ProxyUser::new("bob", Vec::new());
+            let client = http_client();
+
+            let rejected = session_token(&login, Some(&bob), &client).await.unwrap();
+            let fresh = refresh_token(&login, Some(&bob), &rejected, &client)
+                .await
+                .unwrap();
+            assert_ne!(fresh, rejected);
+            // a late refresh for the same rejection keeps the fresh token
+            assert_eq!(
+                refresh_token(&login, Some(&bob), &rejected, &client).await,
+                Ok(fresh)
+            );
+            assert_eq!(logins.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_rejected_login() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (endpoint, logins) = mock_login_api();
+            let config = config(&endpoint, "wrong", "");
+            let login = find_login("https://app.example/api/", config).unwrap();
+
+            assert_eq!(session_token(&login, None, &http_client()).await, Err(()));
+            assert_eq!(logins.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn test_fill_template_and_inject_header() {
+        let template =
+            serde_json::json!({"user": "{{username}}", "keep": 1, "list": ["x{{username}}x"]});
+        let fields = vec![("username".to_string(), "a\"b".to_string())];
+
+        assert_eq!(
+            fill_template(&template, &fields),
+            serde_json::json!({"user": "a\"b", "keep": 1, "list": ["xa\"bx"]})
+        );
+
+        let config = config("http://127.0.0.1:1/login", "", "");
+        let login = find_login("https://app.example/api/", config).unwrap();
+        let mut req = Request::builder()
+            .header("authorization", "Basic forged")
+            .body(Body::empty())
+            .unwrap();
+        inject(&mut req, &login, "abc");
+        assert_eq!(req.headers()["authorization"], "Bearer abc");
+    }
+}
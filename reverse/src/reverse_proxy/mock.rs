@@ -0,0 +1,46 @@
+use hyper::{
+    client::HttpConnector,
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn},
+    Body, Client, Request, Response, Server,
+};
+use hyper_tls::HttpsConnector;
+
+use std::{convert::Infallible, future::Future, net::SocketAddr};
+
+/// Runs a local upstream answering with `respond`, which also gets the
+/// address of the connection, and returns its base URL.
+pub fn serve_peers<F, R>(respond: F) -> String
+where
+    F: Fn(Request<Body>, SocketAddr) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    let make_svc = make_service_fn(move |conn: &AddrStream| {
+        let remote = conn.remote_addr();
+        let respond = respond.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let response = respond(req, remote);
+                async move { Ok::<_, Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(make_svc);
+    let url = format!("http://{}", server.local_addr());
+    tokio::spawn(server);
+
+    url
+}
+
+/// Runs a local upstream answering with `respond` and returns its base URL.
+pub fn serve<F, R>(respond: F) -> String
+where
+    F: Fn(Request<Body>) -> R + Clone + Send + Sync + 'static,
+    R: Future<Output = Response<Body>> + Send + 'static,
+{
+    serve_peers(move |req, _| respond(req))
+}
+
+pub fn http_client() -> Client<HttpsConnector<HttpConnector>> {
+    Client::builder().build(HttpsConnector::new())
+}
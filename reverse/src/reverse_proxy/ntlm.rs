@@ -0,0 +1,798 @@
+use hyper::{
+    body::HttpBody,
+    client::conn,
+    header::{
+        HeaderMap, HeaderValue, AUTHORIZATION, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING,
+        WWW_AUTHENTICATE,
+    },
+    Body, Request, Response, StatusCode, Uri,
+};
+use native_tls::TlsConnector;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tokio_tls::TlsConnector as TokioTlsConnector;
+
+use base64::{engine::general_purpose, Engine as _};
+use hmac::{Hmac, Mac};
+use md4::{Digest, Md4};
+use md5::Md5;
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    future, io,
+    sync::{Mutex, PoisonError},
+    task::Poll,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use toml::Value;
+
+use super::{config::setup_ntlm, portal::ProxyUser, scope::match_scope, ProxyError};
+
+// https://learn.microsoft.com/en-us/openspecs/windows_protocols/ms-nlmp
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+// Authenticated connections kept per upstream
+const MAX_POOLED_CONNECTIONS: usize = 8;
+
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const NEGOTIATE_FLAGS: u32 = NEGOTIATE_UNICODE
+    | REQUEST_TARGET
+    | NEGOTIATE_NTLM
+    | NEGOTIATE_ALWAYS_SIGN
+    | NEGOTIATE_EXTENDED_SESSIONSECURITY
+    | NEGOTIATE_TARGET_INFO
+    | NEGOTIATE_128
+    | NEGOTIATE_56;
+
+const AV_EOL: u16 = 0x0000;
+const AV_TIMESTAMP: u16 = 0x0007;
+
+// Seconds between 1601-01-01 (FILETIME epoch) and 1970-01-01
+const FILETIME_EPOCH_OFFSET: u64 = 11_644_473_600;
+
+type HmacMd5 = Hmac<Md5>;
+
+trait Io: AsyncRead + AsyncWrite + Unpin + Send {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Io for T {}
+
+#[derive(Debug)]
+pub struct NtlmCredentials {
+    pub domain: String,
+    pub username: String,
+    pub password: String,
+    pub workstation: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ChallengeMessage {
+    pub flags: u32,
+    pub server_challenge: [u8; 8],
+    pub target_info: Vec<u8>,
+}
+
+impl NtlmCredentials {
+    pub fn new(domain: &str, username: &str, password: &str, workstation: &str) -> Self {
+        NtlmCredentials {
+            domain: domain.to_string(),
+            username: username.to_string(),
+            password: password.to_string(),
+            workstation: workstation.to_string(),
+        }
+    }
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(|c| c.to_le_bytes()).collect()
+}
+
+fn hmac_md5(key: &[u8], parts: &[&[u8]]) -> [u8; 16] {
+    let mut mac = HmacMd5::new_from_slice(key).expect("HMAC accepts keys of any size");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+fn ntowfv2(credentials: &NtlmCredentials) -> [u8; 16] {
+    let nt_hash = Md4::digest(utf16le(&credentials.password));
+    let identity = format!(
+        "{}{}",
+        credentials.username.to_uppercase(),
+        credentials.domain
+    );
+
+    hmac_md5(&nt_hash, &[&utf16le(&identity)])
+}
+
+fn push_security_buffer(header: &mut Vec<u8>, payload: &mut Vec<u8>, offset: usize, data: &[u8]) {
+    let len = data.len() as u16;
+    header.extend_from_slice(&len.to_le_bytes());
+    header.extend_from_slice(&len.to_le_bytes());
+    header.extend_from_slice(&((offset + payload.len()) as u32).to_le_bytes());
+    payload.extend_from_slice(data);
+}
+
+fn read_security_buffer(message: &[u8], at: usize) -> Option<&[u8]> {
+    let field = message.get(at..at + 8)?;
+    let len = u16::from_le_bytes([field[0], field[1]]) as usize;
+    let offset = u32::from_le_bytes([field[4], field[5], field[6], field[7]]) as usize;
+
+    message.get(offset..offset + len)
+}
+
+fn av_pair(target_info: &[u8], av_id: u16) -> Option<&[u8]> {
+    let mut rest = target_info;
+
+    while rest.len() >= 4 {
+        let id = u16::from_le_bytes([rest[0], rest[1]]);
+        let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+        let value = rest.get(4..4 + len)?;
+
+        if id == AV_EOL {
+            break;
+        }
+        if id == av_id {
+            return Some(value);
+        }
+        rest = &rest[4 + len..];
+    }
+    None
+}
+
+fn filetime_now() -> u64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    (since_epoch.as_secs() + FILETIME_EPOCH_OFFSET) * 10_000_000
+        + since_epoch.subsec_nanos() as u64 / 100
+}
+
+pub fn negotiate_message() -> Vec<u8> {
+    let mut message = Vec::with_capacity(32);
+
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&NEGOTIATE_FLAGS.to_le_bytes());
+    // empty DomainNameFields and WorkstationFields
+    message.extend_from_slice(&[0u8; 16]);
+    message
+}
+
+pub fn parse_challenge_message(message: &[u8]) -> Option<ChallengeMessage> {
+    if message.len() < 32 || &message[..8] != SIGNATURE {
+        return None;
+    }
+    if u32::from_le_bytes(message[8..12].try_into().ok()?) != 2 {
+        return None;
+    }
+
+    let flags = u32::from_le_bytes(message[20..24].try_into().ok()?);
+    let server_challenge: [u8; 8] = message[24..32].try_into().ok()?;
+    let target_info = match flags & NEGOTIATE_TARGET_INFO {
+        0 => Vec::new(),
+        _ => read_security_buffer(message, 40)?.to_vec(),
+    };
+
+    Some(ChallengeMessage {
+        flags,
+        server_challenge,
+        target_info,
+    })
+}
+
+/// Computes the NTLMv2 responses to a server challenge.
+///
+/// Returns the LmChallengeResponse, the NtChallengeResponse and the SessionBaseKey.
+pub fn ntlmv2_response(
+    credentials: &NtlmCredentials,
+    server_challenge: &[u8; 8],
+    target_info: &[u8],
+    client_challenge: &[u8; 8],
+    timestamp: u64,
+) -> (Vec<u8>, Vec<u8>, [u8; 16]) {
+    let response_key = ntowfv2(credentials);
+
+    let mut temp = Vec::with_capacity(28 + target_info.len() + 4);
+    temp.extend_from_slice(&[0x01, 0x01, 0, 0, 0, 0, 0, 0]);
+    temp.extend_from_slice(&timestamp.to_le_bytes());
+    temp.extend_from_slice(client_challenge);
+    temp.extend_from_slice(&[0u8; 4]);
+    temp.extend_from_slice(target_info);
+    temp.extend_from_slice(&[0u8; 4]);
+
+    let nt_proof = hmac_md5(&response_key, &[server_challenge, &temp]);
+    let mut nt_response = nt_proof.to_vec();
+    nt_response.extend_from_slice(&temp);
+
+    let mut lm_response = hmac_md5(&response_key, &[server_challenge, client_challenge]).to_vec();
+    lm_response.extend_from_slice(client_challenge);
+
+    let session_base_key = hmac_md5(&response_key, &[&nt_proof]);
+    (lm_response, nt_response, session_base_key)
+}
+
+pub fn authenticate_message(
+    credentials: &NtlmCredentials,
+    challenge: &ChallengeMessage,
+    client_challenge: &[u8; 8],
+) -> Vec<u8> {
+    // A server timestamp means the LMv2 response must not be sent
+    let (timestamp, has_server_time) = match av_pair(&challenge.target_info, AV_TIMESTAMP) {
+        Some(time) if time.len() == 8 => (u64::from_le_bytes(time.try_into().unwrap()), true),
+        _ => (filetime_now(), false),
+    };
+    let (mut lm_response, nt_response, _) = ntlmv2_response(
+        credentials,
+        &challenge.server_challenge,
+        &challenge.target_info,
+        client_challenge,
+        timestamp,
+    );
+    if has_server_time {
+        lm_response = vec![0u8; 24];
+    }
+
+    let flags = NEGOTIATE_FLAGS & (challenge.flags | NEGOTIATE_UNICODE);
+    let header_len = 64;
+    let mut header = Vec::with_capacity(header_len);
+    let mut payload = Vec::new();
+
+    header.extend_from_slice(SIGNATURE);
+    header.extend_from_slice(&3u32.to_le_bytes());
+    push_security_buffer(&mut header, &mut payload, header_len, &lm_response);
+    push_security_buffer(&mut header, &mut payload, header_len, &nt_response);
+    push_security_buffer(
+        &mut header,
+        &mut payload,
+        header_len,
+        &utf16le(&credentials.domain),
+    );
+    push_security_buffer(
+        &mut header,
+        &mut payload,
+        header_len,
+        &utf16le(&credentials.username),
+    );
+    push_security_buffer(
+        &mut header,
+        &mut payload,
+        header_len,
+        &utf16le(&credentials.workstation),
+    );
+    // no EncryptedRandomSessionKey, key exchange isn't negotiated
+    push_security_buffer(&mut header, &mut payload, header_len, &[]);
+    header.extend_from_slice(&flags.to_le_bytes());
+
+    header.extend_from_slice(&payload);
+    header
+}
+
+/// Tells whether a response offers the NTLM scheme.
+pub fn is_requested(resp_headers: &HeaderMap) -> bool {
+    resp_headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .any(|scheme| scheme.trim().eq_ignore_ascii_case("NTLM"))
+}
+
+fn server_token(resp_headers: &HeaderMap) -> Option<Vec<u8>> {
+    resp_headers
+        .get_all(WWW_AUTHENTICATE)
+        .iter()
+        .filter_map(|header| header.to_str().ok())
+        .find_map(|header| header.strip_prefix("NTLM "))
+        .and_then(|token| general_purpose::STANDARD.decode(token.trim()).ok())
+}
+
+pub fn find_credentials(uri: &str, config: Value) -> Option<NtlmCredentials> {
+    let ntlm_auth = setup_ntlm(config)?;
+    let credentials = match_scope(&ntlm_auth, uri, None)?;
+
+    Some(NtlmCredentials::new(
+        &credentials.domain,
+        &credentials.username,
+        &credentials.password,
+        &credentials.workstation,
+    ))
+}
+
+async fn connect(uri: &Uri) -> Result<Box<dyn Io>, ProxyError> {
+    let host = uri
+        .host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI without host"))?;
+    let https = uri.scheme_str() == Some("https");
+    let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+    let stream = TcpStream::connect((host, port)).await?;
+
+    if !https {
+        return Ok(Box::new(stream));
+    }
+
+    let tls_connector = TlsConnector::builder().build().map_err(io::Error::other)?;
+    let tls_stream = TokioTlsConnector::from(tls_connector)
+        .connect(host, stream)
+        .await
+        .map_err(io::Error::other)?;
+    Ok(Box::new(tls_stream))
+}
+
+// Upstream origin, proxy user and NTLM account
+type ConnectionKey = (String, Option<String>, String);
+
+// Connections the handshake authenticated, the key staying once known so
+// the next requests skip the unauthenticated attempt
+static AUTHENTICATED: Lazy<Mutex<HashMap<ConnectionKey, Vec<conn::SendRequest<Body>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn connection_key(
+    uri: &Uri,
+    credentials: &NtlmCredentials,
+    user: Option<&ProxyUser>,
+) -> ConnectionKey {
+    let origin = format!(
+        "{}://{}",
+        uri.scheme_str().unwrap_or("http"),
+        uri.authority()
+            .map(|authority| authority.as_str())
+            .unwrap_or("")
+    );
+    let account = format!("{}\\{}", credentials.domain, credentials.username);
+
+    (origin, user.map(|user| user.name.clone()), account)
+}
+
+/// Tells whether the upstream of `uri` was already authenticated with
+/// `credentials` for `user`, so requests can go to it directly.
+pub fn is_known_upstream(
+    uri: &str,
+    credentials: &NtlmCredentials,
+    user: Option<&ProxyUser>,
+) -> bool {
+    let uri = match uri.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    let connections = AUTHENTICATED.lock().unwrap_or_else(PoisonError::into_inner);
+    connections.contains_key(&connection_key(&uri, credentials, user))
+}
+
+// An idle authenticated connection, the ones the upstream closed being
+// dropped
+async fn take_connection(key: &ConnectionKey) -> Option<conn::SendRequest<Body>> {
+    loop {
+        let mut sender = {
+            let mut connections = AUTHENTICATED.lock().unwrap_or_else(PoisonError::into_inner);
+            connections.get_mut(key)?.pop()?
+        };
+        if let Poll::Ready(Ok(())) = future::poll_fn(|cx| Poll::Ready(sender.poll_ready(cx))).await
+        {
+            return Some(sender);
+        }
+    }
+}
+
+// Puts `sender` back in the pool once the upstream is done answering on it
+fn keep_connection(key: ConnectionKey, mut sender: conn::SendRequest<Body>) {
+    AUTHENTICATED
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .entry(key.clone())
+        .or_default();
+
+    tokio::spawn(async move {
+        if future::poll_fn(|cx| sender.poll_ready(cx)).await.is_err() {
+            return;
+        }
+        let mut connections = AUTHENTICATED.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(pooled) = connections.get_mut(&key) {
+            if pooled.len() < MAX_POOLED_CONNECTIONS {
+                pooled.push(sender);
+            }
+        }
+    });
+}
+
+fn forget_upstream(key: &ConnectionKey) {
+    let mut connections = AUTHENTICATED.lock().unwrap_or_else(PoisonError::into_inner);
+    connections.remove(key);
+}
+
+fn handshake_leg(
+    req: &Request<Body>,
+    token: &[u8],
+    body: Body,
+) -> Result<Request<Body>, ProxyError> {
+    let path = req
+        .uri()
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
+    let mut leg = Request::builder()
+        .method(req.method().clone())
+        .uri(path)
+        .body(body)?;
+
+    *leg.headers_mut() = req.headers().clone();
+    if leg.body().is_end_stream() {
+        // the negotiate leg goes without the body of the request
+        leg.headers_mut().remove(CONTENT_LENGTH);
+        leg.headers_mut().remove(TRANSFER_ENCODING);
+    }
+    let auth = format!("NTLM {}", general_purpose::STANDARD.encode(token));
+    if let Ok(auth_value) = HeaderValue::from_str(&auth) {
+        leg.headers_mut().insert(AUTHORIZATION, auth_value);
+    }
+    leg.headers_mut()
+        .insert(CONNECTION, HeaderValue::from_static("keep-alive"));
+    Ok(leg)
+}
+
+/// Runs the NTLM handshake for `req`, then sends it authenticated.
+///
+/// NTLM authenticates a connection rather than a request, so every message
+/// goes through one dedicated upstream connection instead of the shared pool.
+/// Once authenticated, the connection is kept for the next requests of
+/// `user` to the same upstream.
+pub async fn handshake(
+    req: Request<Body>,
+    credentials: &NtlmCredentials,
+    user: Option<&ProxyUser>,
+) -> Result<Response<Body>, ProxyError> {
+    let key = connection_key(req.uri(), credentials, user);
+    let io = connect(req.uri()).await?;
+    let (mut sender, connection) = conn::handshake(io).await?;
+    tokio::spawn(async move {
+        if let Err(err) = connection.await {
+            eprintln!("NTLM connection error: {}", err);
+        }
+    });
+
+    let negotiate = handshake_leg(&req, &negotiate_message(), Body::empty())?;
+    let challenge_resp = sender.send_request(negotiate).await?;
+    if challenge_resp.status() != StatusCode::UNAUTHORIZED {
+        return Ok(challenge_resp);
+    }
+
+    let challenge = match server_token(challenge_resp.headers())
+        .and_then(|token| parse_challenge_message(&token))
+    {
+        Some(challenge) => challenge,
+        None => {
+            eprintln!("NTLM Error: Invalid challenge message");
+            return Ok(challenge_resp);
+        }
+    };
+    // drain the challenge body so the connection can be reused
+    hyper::body::to_bytes(challenge_resp.into_body()).await?;
+    println!("Identified NTLM challenge!");
+
+    let client_challenge: [u8; 8] = rand::random();
+    let authenticate = authenticate_message(credentials, &challenge, &client_challenge);
+    let (parts, body) = req.into_parts();
+    let authenticated_req = handshake_leg(
+        &Request::from_parts(parts, Body::empty()),
+        &authenticate,
+        body,
+    )?;
+
+    println!("NTLM authentication request sent!");
+    future::poll_fn(|cx| sender.poll_ready(cx)).await?;
+    let response = sender.send_request(authenticated_req).await?;
+    if response.status() != StatusCode::UNAUTHORIZED {
+        keep_connection(key, sender);
+    }
+    Ok(response)
+}
+
+/// Sends `req` over an authenticated connection to its upstream, running
+/// the handshake again on a new one when none is idle or the upstream
+/// dropped the authentication. `retry` is the same request, sent in the
+/// latter case.
+pub async fn send(
+    req: Request<Body>,
+    retry: Request<Body>,
+    credentials: &NtlmCredentials,
+    user: Option<&ProxyUser>,
+) -> Result<Response<Body>, ProxyError> {
+    let key = connection_key(req.uri(), credentials, user);
+    let mut sender = match take_connection(&key).await {
+        Some(sender) => sender,
+        None => return handshake(retry, credentials, user).await,
+    };
+
+    let (mut parts, body) = req.into_parts();
+    parts.uri = parts
+        .uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/")
+        .parse::<Uri>()
+        .map_err(hyper::http::Error::from)?;
+    let response = sender
+        .send_request(Request::from_parts(parts, body))
+        .await?;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        println!("NTLM connection no longer authenticated");
+        forget_upstream(&key);
+        return handshake(retry, credentials, user).await;
+    }
+    keep_connection(key, sender);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::mock;
+    use std::{
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+    use tokio::runtime::Runtime;
+
+    const SERVER_CHALLENGE: [u8; 8] = [0x01, 0x23, 0x45, 0x67, 0x89, 0xab, 0xcd, 0xef];
+
+    // MS-NLMP 4.2.4.1.3: AV pairs of the challenge (MsvAvNbDomainName, MsvAvNbComputerName)
+    const TARGET_INFO: [u8; 36] = [
+        0x02, 0x00, 0x0c, 0x00, 0x44, 0x00, 0x6f, 0x00, 0x6d, 0x00, 0x61, 0x00, 0x69, 0x00, 0x6e,
+        0x00, 0x01, 0x00, 0x0c, 0x00, 0x53, 0x00, 0x65, 0x00, 0x72, 0x00, 0x76, 0x00, 0x65, 0x00,
+        0x72, 0x00, 0x00, 0x00, 0x00, 0x00,
+    ];
+
+    fn spec_credentials() -> NtlmCredentials {
+        NtlmCredentials::new("Domain", "User", "Password", "COMPUTER")
+    }
+
+    fn challenge_message() -> Vec<u8> {
+        let flags = NEGOTIATE_FLAGS;
+        let mut message = Vec::new();
+        message.extend_from_slice(SIGNATURE);
+        message.extend_from_slice(&2u32.to_le_bytes());
+        // empty TargetNameFields
+        message.extend_from_slice(&[0, 0, 0, 0, 48, 0, 0, 0]);
+        message.extend_from_slice(&flags.to_le_bytes());
+        message.extend_from_slice(&SERVER_CHALLENGE);
+        message.extend_from_slice(&[0u8; 8]);
+        let len = TARGET_INFO.len() as u16;
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&len.to_le_bytes());
+        message.extend_from_slice(&48u32.to_le_bytes());
+        message.extend_from_slice(&TARGET_INFO);
+        message
+    }
+
+    #[test]
+    fn test_ntowfv2_spec_vector() {
+        assert_eq!(
+            ntowfv2(&spec_credentials()),
+            [
+                0x0c, 0x86, 0x8a, 0x40, 0x3b, 0xfd, 0x7a, 0x93, 0xa3, 0x00, 0x1e, 0xf2, 0x2e, 0xf0,
+                0x2e, 0x3f
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ntlmv2_response_spec_vector() {
+        let (lm, nt, session_base_key) = ntlmv2_response(
+            &spec_credentials(),
+            &SERVER_CHALLENGE,
+            &TARGET_INFO,
+            &[0xaa; 8],
+            0,
+        );
+
+        assert_eq!(
+            lm,
+            [
+                0x86, 0xc3, 0x50, 0x97, 0xac, 0x9c, 0xec, 0x10, 0x25, 0x54, 0x76, 0x4a, 0x57, 0xcc,
+                0xcc, 0x19, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa
+            ]
+        );
+        assert_eq!(
+            nt[..16],
+            [
+                0x68, 0xcd, 0x0a, 0xb8, 0x51, 0xe5, 0x1c, 0x96, 0xaa, 0xbc, 0x92, 0x7b, 0xeb, 0xef,
+                0x6a, 0x1c
+            ]
+        );
+        assert_eq!(
+            session_base_key,
+            [
+                0x8d, 0xe4, 0x0c, 0xca, 0xdb, 0xc1, 0x4a, 0x82, 0xf1, 0x5c, 0xb0, 0xad, 0x0d, 0xe9,
+                0x5c, 0xa3
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_challenge_message() {
+        assert_eq!(
+            parse_challenge_message(&challenge_message()),
+            Some(ChallengeMessage {
+                flags: NEGOTIATE_FLAGS,
+                server_challenge: SERVER_CHALLENGE,
+                target_info: TARGET_INFO.to_vec(),
+            })
+        );
+        assert_eq!(parse_challenge_message(&negotiate_message()), None);
+        assert_eq!(parse_challenge_message(b"NTLMSSP\0"), None);
+    }
+
+    #[test]
+    fn test_authenticate_message_fields() {
+        let challenge = parse_challenge_message(&challenge_message()).unwrap();
+        let message = authenticate_message(&spec_credentials(), &challenge, &[0xaa; 8]);
+
+        assert_eq!(&message[..8], SIGNATURE);
+        assert_eq!(message[8], 3);
+        assert_eq!(read_security_buffer(&message, 12).unwrap().len(), 24);
+        assert_eq!(
+            read_security_buffer(&message, 28).unwrap(),
+            utf16le("Domain")
+        );
+        assert_eq!(read_security_buffer(&message, 36).unwrap(), utf16le("User"));
+        assert_eq!(
+            read_security_buffer(&message, 44).unwrap(),
+            utf16le("COMPUTER")
+        );
+    }
+
+    // Stand-in server checking the NTProofStr with the spec credentials
+    fn verify_authenticate(message: &[u8]) -> bool {
+        let nt_response = match read_security_buffer(message, 20) {
+            Some(nt_response) if nt_response.len() > 16 => nt_response,
+            _ => return false,
+        };
+        let response_key = ntowfv2(&spec_credentials());
+        let nt_proof = hmac_md5(&response_key, &[&SERVER_CHALLENGE, &nt_response[16..]]);
+
+        nt_proof[..] == nt_response[..16]
+    }
+
+    // What the stand-in server saw, per connection
+    #[derive(Default)]
+    struct StandIn {
+        challenged: Option<SocketAddr>,
+        authenticated: Vec<SocketAddr>,
+        negotiations: usize,
+    }
+
+    fn stand_in_response(
+        auth: Option<&str>,
+        state: &Mutex<StandIn>,
+        remote: SocketAddr,
+    ) -> Response<Body> {
+        let token = auth
+            .and_then(|auth| auth.strip_prefix("NTLM "))
+            .and_then(|token| general_purpose::STANDARD.decode(token).ok());
+        let unauthorized = |challenge: &str| {
+            Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, challenge)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let mut state = state.lock().unwrap();
+
+        match token {
+            Some(token) if token.get(8) == Some(&1) => {
+                state.challenged = Some(remote);
+                state.negotiations += 1;
+                let challenge = general_purpose::STANDARD.encode(challenge_message());
+                unauthorized(&format!("NTLM {}", challenge))
+            }
+            Some(token) if token.get(8) == Some(&3) => {
+                let same_connection = state.challenged == Some(remote);
+                if same_connection && verify_authenticate(&token) {
+                    state.authenticated.push(remote);
+                    Response::new(Body::from("authenticated"))
+                } else {
+                    unauthorized("NTLM")
+                }
+            }
+            None if state.authenticated.contains(&remote) => {
+                Response::new(Body::from("authenticated"))
+            }
+            _ => unauthorized("NTLM"),
+        }
+    }
+
+    #[test]
+    fn test_handshake_with_stand_in_server() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = Arc::new(Mutex::new(StandIn::default()));
+            let server_state = state.clone();
+            let upstream = mock::serve_peers(move |req: Request<Body>, remote| {
+                let auth = req
+                    .headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|auth| auth.to_str().ok());
+                let response = stand_in_response(auth, &server_state, remote);
+                async move { response }
+            });
+
+            let url = format!("{}/intranet", upstream);
+            let request = || Request::builder().uri(&url).body(Body::empty()).unwrap();
+            assert!(!is_known_upstream(&url, &spec_credentials(), None));
+            let response = handshake(request(), &spec_credentials(), None)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"authenticated");
+
+            // The next request goes over the authenticated connection, back
+            // in the pool once its response was read
+            assert!(is_known_upstream(&url, &spec_credentials(), None));
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            let response = send(request(), request(), &spec_credentials(), None)
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert_eq!(state.lock().unwrap().negotiations, 1);
+            let alice = ProxyUser::new("ntlm-alice", Vec::new());
+            assert!(!is_known_upstream(&url, &spec_credentials(), Some(&alice)));
+        });
+    }
+
+    #[test]
+    fn test_handshake_posts_the_body_once() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let state = Arc::new(Mutex::new(StandIn::default()));
+            let server_state = state.clone();
+            let upstream = mock::serve_peers(move |req: Request<Body>, remote| {
+                let server_state = server_state.clone();
+                async move {
+                    let auth = req
+                        .headers()
+                        .get(AUTHORIZATION)
+                        .and_then(|auth| auth.to_str().ok())
+                        .map(str::to_string);
+                    let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                    let response = stand_in_response(auth.as_deref(), &server_state, remote);
+                    if response.status() == StatusCode::OK {
+                        return Response::new(Body::from(body));
+                    }
+                    assert!(body.is_empty());
+                    response
+                }
+            });
+
+            let response = handshake(
+                Request::builder()
+                    .method("POST")
+                    .uri(format!("{}/intranet", upstream))
+                    .header(CONTENT_LENGTH, "10")
+                    .body(Body::from("user=alice"))
+                    .unwrap(),
+                &spec_credentials(),
+                None,
+            )
+            .await
+            .unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+
+            let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+            assert_eq!(&body[..], b"user=alice");
+            assert_eq!(state.lock().unwrap().negotiations, 1);
+        });
+    }
+}
@@ -0,0 +1,377 @@
+use hyper::{
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Body, Client, Method, Request,
+};
+use hyper_tls::HttpsConnector;
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use tokio::{sync::Mutex as AsyncMutex, time::timeout};
+use url::form_urlencoded::byte_serialize;
+
+use super::basic::encode_auth;
+
+// Tokens are refreshed this long before the expiry announced by the server
+const REFRESH_MARGIN: Duration = Duration::from_secs(30);
+// Lifetime assumed when the token response has no expires_in
+const DEFAULT_EXPIRES_IN: u64 = 300;
+// The refresh lock is held meanwhile, blocking every request of the route
+const TOKEN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OAuth2Client {
+    pub token_url: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+    pub audience: Option<String>,
+}
+
+#[derive(Debug)]
+struct CachedToken {
+    access_token: String,
+    refresh_at: Instant,
+}
+
+type TokenSlot = Arc<AsyncMutex<Option<CachedToken>>>;
+
+// One slot per client, its lock makes concurrent refreshes single-flight
+static TOKENS: Lazy<Mutex<HashMap<String, TokenSlot>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl OAuth2Client {
+    fn cache_key(&self) -> String {
+        format!(
+            "{} {} {} {}",
+            self.token_url,
+            self.client_id,
+            self.scope.as_deref().unwrap_or(""),
+            self.audience.as_deref().unwrap_or("")
+        )
+    }
+
+    fn token_request(&self) -> Result<Request<Body>, ()> {
+        let mut params = vec![("grant_type", "client_credentials")];
+        if let Some(scope) = &self.scope {
+            params.push(("scope", scope));
+        }
+        if let Some(audience) = &self.audience {
+            params.push(("audience", audience));
+        }
+        let body = serde_urlencoded::to_string(params).map_err(|err| {
+            eprintln!("OAuth2 Error: {}", err);
+        })?;
+
+        // RFC 6749 2.3.1: client credentials are form-encoded before Basic encoding
+        let auth = encode_auth(
+            form_encode(&self.client_id),
+            form_encode(&self.client_secret),
+        );
+
+        Request::builder()
+            .method(Method::POST)
+            .uri(&self.token_url)
+            .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+            .header(AUTHORIZATION, auth)
+            .body(Body::from(body))
+            .map_err(|err| {
+                eprintln!("OAuth2 Error: {}", err);
+            })
+    }
+}
+
+fn form_encode(value: &str) -> String {
+    byte_serialize(value.as_bytes()).collect()
+}
+
+fn token_slot(oauth: &OAuth2Client) -> TokenSlot {
+    let mut tokens = TOKENS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    tokens.entry(oauth.cache_key()).or_default().clone()
+}
+
+fn parse_token_response(body: &[u8]) -> Result<(String, u64), ()> {
+    let json: serde_json::Value = serde_json::from_slice(body).map_err(|err| {
+        eprintln!("OAuth2 Error: Invalid token response: {}", err);
+    })?;
+
+    let token_type = json["token_type"].as_str().unwrap_or("Bearer");
+    if !token_type.eq_ignore_ascii_case("bearer") {
+        eprintln!("OAuth2 Error: Unsupported token type {}", token_type);
+        return Err(());
+    }
+    let access_token = match json["access_token"].as_str() {
+        Some(token) => token.to_string(),
+        None => {
+            eprintln!("OAuth2 Error: No access_token in the token response");
+            return Err(());
+        }
+    };
+    let expires_in = json["expires_in"].as_u64().unwrap_or(DEFAULT_EXPIRES_IN);
+
+    Ok((access_token, expires_in))
+}
+
+async fn fetch_token(
+    oauth: &OAuth2Client,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<CachedToken, ()> {
+    let fetched_at = Instant::now();
+    let exchange = async {
+        let response = client
+            .request(oauth.token_request()?)
+            .await
+            .map_err(|err| {
+                eprintln!("OAuth2 Error: Token endpoint unreachable: {}", err);
+            })?;
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|err| {
+                eprintln!("OAuth2 Error: {}", err);
+            })?;
+        Ok((status, body))
+    };
+    let (status, body) = timeout(TOKEN_TIMEOUT, exchange).await.map_err(|_| {
+        eprintln!(
+            "OAuth2 Error: Token endpoint didn't answer within {}s",
+            TOKEN_TIMEOUT.as_secs()
+        );
+    })??;
+
+    if !status.is_success() {
+        eprintln!("OAuth2 Error: Token endpoint answered {}", status);
+        return Err(());
+    }
+    let (access_token, expires_in) = parse_token_response(&body)?;
+    println!("OAuth2 access token acquired (expires in {}s)", expires_in);
+
+    Ok(CachedToken {
+        access_token,
+        refresh_at: fetched_at + Duration::from_secs(expires_in).saturating_sub(REFRESH_MARGIN),
+    })
+}
+
+/// Returns a valid access token, fetching a new one when the cached one is
+/// close to expiry. Concurrent callers wait for a single token request.
+pub async fn access_token(
+    oauth: &OAuth2Client,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<String, ()> {
+    let slot = token_slot(oauth);
+    let mut cached = slot.lock().await;
+
+    if let Some(token) = cached.as_ref() {
+        if Instant::now() < token.refresh_at {
+            return Ok(token.access_token.clone());
+        }
+    }
+
+    let token = fetch_token(oauth, client).await?;
+    let access_token = token.access_token.clone();
+    *cached = Some(token);
+    Ok(access_token)
+}
+
+// Drops `rejected` from the cache, unless another request already replaced it
+async fn invalidate(oauth: &OAuth2Client, rejected: &str) {
+    let slot = token_slot(oauth);
+    let mut cached = slot.lock().await;
+
+    if cached
+        .as_ref()
+        .is_some_and(|token| token.access_token == rejected)
+    {
+        *cached = None;
+    }
+}
+
+/// Returns a token to use in place of one the upstream rejected.
+pub async fn refresh_token(
+    oauth: &OAuth2Client,
+    rejected: &str,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<String, ()> {
+    invalidate(oauth, rejected).await;
+    access_token(oauth, client).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::mock::{self, http_client};
+    use hyper::Response;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::runtime::Runtime;
+
+    // Mock token endpoint numbering the tokens it issues
+    fn mock_token_endpoint(expires_in: u64) -> (String, Arc<AtomicUsize>) {
+        let issued = Arc::new(AtomicUsize::new(0));
+        let counter = issued.clone();
+        let upstream = mock::serve(move |req: Request<Body>| {
+            let counter = counter.clone();
+            async move {
+                let auth = req.headers().get(AUTHORIZATION).cloned();
+                let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                // give concurrent callers time to pile up
+                tokio::time::sleep(Duration::from_millis(20)).await;
+
+                let expected_auth = encode_auth("proxy".to_string(), "s3cret".to_string());
+                if auth.as_ref().and_then(|auth| auth.to_str().ok()) != Some(expected_auth.as_str())
+                    || &body[..] != b"grant_type=client_credentials&scope=api"
+                {
+                    return Response::builder().status(401).body(Body::empty()).unwrap();
+                }
+                let n = counter.fetch_add(1, Ordering::SeqCst) + 1;
+                let token = format!(
+                    r#"{{"access_token":"token-{}","token_type":"Bearer","expires_in":{}}}"#,
+                    n, expires_in
+                );
+                Response::new(Body::from(token))
+            }
+        });
+
+        (format!("{}/token", upstream), issued)
+    }
+
+    fn oauth_client(token_url: &str) -> OAuth2Client {
+        OAuth2Client {
+            token_url: token_url.to_string(),
+            client_id: "proxy".to_string(),
+            client_secret: "s3cret".to_string(),
+            scope: Some("api".to_string()),
+            audience: None,
+        }
+    }
+
+    #[test]
+    fn test_cache_key() {
+        let oauth = oauth_client("https://idp.example/token");
+        let other_audience = OAuth2Client {
+            audience: Some("billing".to_string()),
+            ..oauth.clone()
+        };
+        let other_scope = OAuth2Client {
+            scope: Some("admin".to_string()),
+            ..oauth.clone()
+        };
+
+        assert_ne!(oauth.cache_key(), other_audience.cache_key());
+        assert_ne!(oauth.cache_key(), other_scope.cache_key());
+    }
+
+    #[test]
+    fn test_access_token_is_cached() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (token_url, issued) = mock_token_endpoint(3600);
+            let oauth = oauth_client(&token_url);
+            let client = http_client();
+
+            assert_eq!(
+                access_token(&oauth, &client).await,
+                Ok("token-1".to_string())
+            );
+            assert_eq!(
+                access_token(&oauth, &client).await,
+                Ok("token-1".to_string())
+            );
+            assert_eq!(issued.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_access_token_single_flight() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (token_url, issued) = mock_token_endpoint(3600);
+            let oauth = Arc::new(oauth_client(&token_url));
+            let client = Arc::new(http_client());
+
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let oauth = oauth.clone();
+                    let client = client.clone();
+                    tokio::spawn(async move { access_token(&oauth, &client).await })
+                })
+                .collect();
+            for handle in handles {
+                assert_eq!(handle.await.unwrap(), Ok("token-1".to_string()));
+            }
+            assert_eq!(issued.load(Ordering::SeqCst), 1);
+        });
+    }
+
+    #[test]
+    fn test_access_token_refreshed_before_expiry() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // expires within the refresh margin, so never reused
+            let (token_url, issued) = mock_token_endpoint(10);
+            let oauth = oauth_client(&token_url);
+            let client = http_client();
+
+            assert_eq!(
+                access_token(&oauth, &client).await,
+                Ok("token-1".to_string())
+            );
+            assert_eq!(
+                access_token(&oauth, &client).await,
+                Ok("token-2".to_string())
+            );
+            assert_eq!(issued.load(Ordering::SeqCst), 2);
+        });
+    }
+
+    #[test]
+    fn test_refresh_token() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (token_url, _) = mock_token_endpoint(3600);
+            let oauth = oauth_client(&token_url);
+            let client = http_client();
+
+            let token = access_token(&oauth, &client).await.unwrap();
+            invalidate(&oauth, "some-older-token").await;
+            assert_eq!(access_token(&oauth, &client).await, Ok(token.clone()));
+
+            assert_eq!(
+                refresh_token(&oauth, &token, &client).await,
+                Ok("token-2".to_string())
+            );
+        });
+    }
+
+    #[test]
+    fn test_access_token_rejected_client() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (token_url, issued) = mock_token_endpoint(3600);
+            let mut oauth = oauth_client(&token_url);
+            oauth.client_secret = "wrong".to_string();
+
+            assert_eq!(access_token(&oauth, &http_client()).await, Err(()));
+            assert_eq!(issued.load(Ordering::SeqCst), 0);
+        });
+    }
+
+    #[test]
+    fn test_parse_token_response() {
+        assert_eq!(
+            parse_token_response(br#"{"access_token":"abc","token_type":"bearer"}"#),
+            Ok(("abc".to_string(), DEFAULT_EXPIRES_IN))
+        );
+        assert_eq!(
+            parse_token_response(br#"{"access_token":"abc","token_type":"mac"}"#),
+            Err(())
+        );
+        assert_eq!(
+            parse_token_response(br#"{"error":"invalid_client"}"#),
+            Err(())
+        );
+        assert_eq!(parse_token_response(b"not json"), Err(()));
+    }
+}
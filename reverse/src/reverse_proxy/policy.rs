@@ -0,0 +1,323 @@
+use toml::Value;
+use url::Url;
+
+use super::{
+    config::setup_policy,
+    portal::ProxyUser,
+    scope::{path_has_prefix, CredentialScope},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Effect {
+    Allow,
+    Deny,
+}
+
+/// One [[policy.rules]] entry. Empty lists match anything.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PolicyRule {
+    pub route: CredentialScope,
+    pub users: Vec<String>,
+    pub groups: Vec<String>,
+    pub methods: Vec<String>,
+    pub paths: Vec<String>,
+    pub effect: Effect,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policy {
+    pub rules: Vec<PolicyRule>,
+    pub default: Effect,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Decision {
+    Allow,
+    Deny(String),
+}
+
+impl Effect {
+    pub fn parse(effect: &str) -> Option<Self> {
+        match effect {
+            "allow" => Some(Effect::Allow),
+            "deny" => Some(Effect::Deny),
+            _ => None,
+        }
+    }
+}
+
+// Percent-encoded octet, when it's an unreserved character (RFC 3986 2.3)
+fn decode_unreserved(hex: &[u8]) -> Option<char> {
+    let hex = std::str::from_utf8(hex).ok()?;
+    let byte = u8::from_str_radix(hex, 16).ok()?;
+
+    (byte.is_ascii_alphanumeric() || b"-._~".contains(&byte)).then_some(byte as char)
+}
+
+/// Rules are matched on the path as the upstream is likely to read it:
+/// unreserved characters decoded, empty segments dropped, dot segments
+/// resolved and letters lowercased. `/a/%61dmin`, `//admin` and
+/// `/x/../Admin` are all `/admin`.
+fn normalize_path(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut index = 0;
+
+    while index < bytes.len() {
+        let unreserved = (bytes[index] == b'%' && index + 3 <= bytes.len())
+            .then(|| decode_unreserved(&bytes[index + 1..index + 3]))
+            .flatten();
+        match unreserved {
+            Some(c) => {
+                decoded.push(c);
+                index += 3;
+            }
+            None => {
+                decoded.push(bytes[index] as char);
+                index += 1;
+            }
+        }
+    }
+
+    let mut segments: Vec<&str> = Vec::new();
+    for segment in decoded.split('/') {
+        match segment {
+            "" | "." => {}
+            ".." => {
+                segments.pop();
+            }
+            segment => segments.push(segment),
+        }
+    }
+    format!("/{}", segments.join("/")).to_lowercase()
+}
+
+/// `url` with its path normalized, for routes and targets alike.
+pub fn normalize_url(url: &str) -> Option<Url> {
+    let mut url = Url::parse(url).ok()?;
+    let path = normalize_path(url.path());
+
+    url.set_path(&path);
+    Some(url)
+}
+
+impl PolicyRule {
+    fn grants(&self, user: Option<&ProxyUser>) -> bool {
+        if self.users.is_empty() && self.groups.is_empty() {
+            return true;
+        }
+        match user {
+            Some(user) => {
+                self.users.contains(&user.name)
+                    || user.groups.iter().any(|group| self.groups.contains(group))
+            }
+            None => false,
+        }
+    }
+
+    fn applies(&self, user: Option<&ProxyUser>, method: &str, url: &Url) -> bool {
+        self.route.matches(url, None)
+            && self.grants(user)
+            && (self.methods.is_empty()
+                || self
+                    .methods
+                    .iter()
+                    .any(|allowed| allowed.eq_ignore_ascii_case(method)))
+            && (self.paths.is_empty()
+                || self
+                    .paths
+                    .iter()
+                    .any(|prefix| path_has_prefix(url.path(), &normalize_path(prefix))))
+    }
+}
+
+impl Policy {
+    /// Deny rules win over allow rules; without a matching rule the
+    /// policy default applies.
+    pub fn evaluate(&self, user: Option<&ProxyUser>, method: &str, target: &str) -> Decision {
+        let url = match normalize_url(target) {
+            Some(url) => url,
+            None => return Decision::Deny(format!("unparsable target {}", target)),
+        };
+        let mut allowed = false;
+
+        for (index, rule) in self.rules.iter().enumerate() {
+            if !rule.applies(user, method, &url) {
+                continue;
+            }
+            match rule.effect {
+                Effect::Deny => return Decision::Deny(format!("denied by policy rule {}", index)),
+                Effect::Allow => allowed = true,
+            }
+        }
+
+        match (allowed, self.default) {
+            (true, _) | (false, Effect::Allow) => Decision::Allow,
+            (false, Effect::Deny) => Decision::Deny("no policy rule grants access".to_string()),
+        }
+    }
+}
+
+/// Decides whether `user` may send `method` to `target`.
+///
+/// Without a [policy] table every request is allowed.
+pub fn authorize(user: Option<&ProxyUser>, method: &str, target: &str, config: Value) -> Decision {
+    match setup_policy(config) {
+        Some(policy) => policy.evaluate(user, method, target),
+        None => Decision::Allow,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        let config: Value = toml::from_str(
+            r#"
+            [policy]
+            default = "deny"
+
+            [[policy.rules]]
+            route = "https://app.example/"
+            groups = ["staff"]
+            methods = ["GET", "HEAD"]
+
+            [[policy.rules]]
+            route = "https://app.example/"
+            users = ["alice"]
+
+            [[policy.rules]]
+            route = "https://app.example/"
+            paths = ["/admin"]
+            users = ["bob"]
+            effect = "deny"
+
+            [[policy.rules]]
+            route = "https://app.example/public"
+
+            [[policy.rules]]
+            route = "https://app.example/Secrets"
+            effect = "deny"
+            "#,
+        )
+        .unwrap();
+
+        setup_policy(config).unwrap()
+    }
+
+    fn user(name: &str, groups: &[&str]) -> ProxyUser {
+        ProxyUser::new(name, groups.iter().map(|group| group.to_string()).collect())
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let policy = policy();
+        let alice = user("alice", &[]);
+        let bob = user("bob", &["staff"]);
+        let carol = user("carol", &["sales"]);
+
+        let allowed = |user: Option<&ProxyUser>, method, target| {
+            policy.evaluate(user, method, target) == Decision::Allow
+        };
+
+        assert!(allowed(
+            Some(&alice),
+            "POST",
+            "https://app.example/admin/users"
+        ));
+        assert!(allowed(Some(&bob), "GET", "https://app.example/reports"));
+        assert!(!allowed(Some(&bob), "POST", "https://app.example/reports"));
+        assert!(!allowed(Some(&bob), "GET", "https://app.example/admin"));
+        assert!(!allowed(Some(&carol), "GET", "https://app.example/reports"));
+        assert!(allowed(None, "GET", "https://app.example/public/logo.png"));
+        assert!(!allowed(None, "GET", "https://app.example/reports"));
+        assert!(!allowed(Some(&alice), "GET", "https://other.example/"));
+    }
+
+    #[test]
+    fn test_path_bypasses() {
+        let policy = policy();
+        let bob = user("bob", &["staff"]);
+
+        for target in [
+            "https://app.example/Admin",
+            "https://app.example/ADMIN/users",
+            "https://app.example//admin",
+            "https://app.example/%61dmin",
+            "https://app.example/%2561dmin/../admin",
+            "https://app.example/reports/../admin",
+            "https://app.example/./admin/",
+            "https://app.example/reports/%2e%2e/admin",
+            "https://app.example//secrets",
+            "https://app.example/SECRETS/key",
+        ] {
+            assert_ne!(
+                policy.evaluate(Some(&bob), "GET", target),
+                Decision::Allow,
+                "{}",
+                target
+            );
+        }
+        assert_eq!(
+            policy.evaluate(Some(&bob), "GET", "https://app.example/administration"),
+            Decision::Allow
+        );
+    }
+
+    #[test]
+    fn test_normalize_path() {
+        assert_eq!(normalize_path("/a//b/./c/../D"), "/a/b/d");
+        assert_eq!(normalize_path("/%7Euser/%2Fetc%2f"), "/~user/%2fetc%2f");
+        assert_eq!(normalize_path("/../.."), "/");
+        assert_eq!(normalize_path("/trailing%"), "/trailing%");
+    }
+
+    #[test]
+    fn test_deny_reason() {
+        let policy = policy();
+
+        assert_eq!(
+            policy.evaluate(
+                Some(&user("bob", &["staff"])),
+                "GET",
+                "https://app.example/admin"
+            ),
+            Decision::Deny("denied by policy rule 2".to_string())
+        );
+        assert_eq!(
+            policy.evaluate(None, "GET", "https://app.example/"),
+            Decision::Deny("no policy rule grants access".to_string())
+        );
+    }
+
+    #[test]
+    fn test_broken_rule_denies_everything() {
+        let config: Value = toml::from_str(
+            r#"
+            [policy]
+            default = "allow"
+
+            [[policy.rules]]
+            route = "https://app.example/"
+            effect = "refuse"
+            "#,
+        )
+        .unwrap();
+
+        assert_ne!(
+            authorize(None, "GET", "https://app.example/", config),
+            Decision::Allow
+        );
+    }
+
+    #[test]
+    fn test_authorize_without_policy() {
+        let config: Value = toml::from_str("[redirections]\n").unwrap();
+
+        assert_eq!(
+            authorize(None, "DELETE", "https://app.example/", config),
+            Decision::Allow
+        );
+    }
+}
@@ -0,0 +1,766 @@
+use hyper::{
+    header::{HeaderMap, HeaderValue, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    str,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use toml::Value;
+
+use super::{
+    buffer::{self, BufferLimits},
+    config::setup_portal,
+    cookie::read_cookies,
+    scope::path_has_prefix,
+    status,
+    utils::{self, escape_html},
+    ProxyError,
+};
+
+mod ldap;
+mod oidc;
+mod users;
+
+pub use ldap::LdapConfig;
+pub use oidc::{OidcConfig, DEFAULT_USER_PREFIX};
+use oidc::{OIDC_CALLBACK_PATH, OIDC_LOGIN_PATH};
+
+pub const LOGIN_PATH: &str = "/__proxy/login";
+pub const LOGOUT_PATH: &str = "/__proxy/logout";
+
+const SESSION_COOKIE: &str = "proxy_session";
+pub const DEFAULT_SESSION_TTL: u64 = 8 * 60 * 60;
+
+// Failed logins allowed per client address and username within
+// LOGIN_FAILURE_WINDOW
+const MAX_LOGIN_FAILURES: u32 = 5;
+const LOGIN_FAILURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+// A login form is a few fields
+const MAX_LOGIN_BODY_SIZE: u64 = 16 * 1024;
+
+/// Downstream user authenticated by the proxy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProxyUser {
+    pub name: String,
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortalConfig {
+    pub users_file: Option<String>,
+    pub session_ttl: u64,
+    pub public: Vec<String>,
+    pub groups: HashMap<String, Vec<String>>,
+    pub oidc: Option<OidcConfig>,
+    pub ldap: Option<LdapConfig>,
+}
+
+/// Upstream credentials of a route, chosen by the downstream identity.
+#[derive(Debug, Clone, PartialEq)]
+pub struct UserCredentials<T> {
+    pub users: HashMap<String, T>,
+    pub groups: Vec<(String, T)>,
+    pub shared: Option<T>,
+}
+
+#[derive(Debug)]
+struct ProxySession {
+    user: ProxyUser,
+    expires_at: Instant,
+}
+
+/// What the proxy does with a downstream request once the portal has seen it.
+pub enum Gate {
+    Forward(Request<Body>, Option<ProxyUser>),
+    Respond(Response<Body>),
+}
+
+static SESSIONS: Lazy<Mutex<HashMap<String, ProxySession>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Client address the logins come from, if known, and the username tried
+type FailureKey = (Option<IpAddr>, String);
+
+// Failed logins per client address and username, and when the first of them
+// happened
+static LOGIN_FAILURES: Lazy<Mutex<HashMap<FailureKey, (u32, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl ProxyUser {
+    pub fn new(name: &str, groups: Vec<String>) -> Self {
+        ProxyUser {
+            name: name.to_string(),
+            groups,
+        }
+    }
+}
+
+impl PortalConfig {
+    /// Groups listing `username` in the [portal.groups] table.
+    pub fn groups_of(&self, username: &str) -> Vec<String> {
+        let mut groups: Vec<String> = self
+            .groups
+            .iter()
+            .filter(|(_, members)| members.iter().any(|member| member == username))
+            .map(|(group, _)| group.clone())
+            .collect();
+
+        groups.sort();
+        groups
+    }
+
+    fn is_public(&self, path: &str) -> bool {
+        self.public
+            .iter()
+            .any(|public| path_has_prefix(path, public))
+    }
+}
+
+impl<T> UserCredentials<T> {
+    pub fn is_empty(&self) -> bool {
+        self.users.is_empty() && self.groups.is_empty() && self.shared.is_none()
+    }
+
+    /// The user's own account, then the first account of one of its groups,
+    /// then the shared account. Groups are tried in the order of `groups`,
+    /// which the configuration sorts by priority.
+    pub fn resolve(&self, user: Option<&ProxyUser>) -> Option<&T> {
+        if let Some(user) = user {
+            if let Some(credential) = self.users.get(&user.name) {
+                return Some(credential);
+            }
+            if let Some((_, credential)) = self
+                .groups
+                .iter()
+                .find(|(group, _)| user.groups.contains(group))
+            {
+                return Some(credential);
+            }
+        }
+        self.shared.as_ref()
+    }
+}
+
+pub fn new_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Opens a proxy session for `user` and returns its token.
+pub fn open_session(user: ProxyUser, ttl: u64) -> String {
+    let token = new_token();
+    let mut sessions = SESSIONS.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+
+    sessions.retain(|_, session| session.expires_at > now);
+    sessions.insert(
+        token.clone(),
+        ProxySession {
+            user,
+            expires_at: now + Duration::from_secs(ttl),
+        },
+    );
+    token
+}
+
+pub fn session_user(token: &str) -> Option<ProxyUser> {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    match sessions.get(token) {
+        Some(session) if session.expires_at > Instant::now() => Some(session.user.clone()),
+        Some(_) => {
+            sessions.remove(token);
+            None
+        }
+        None => None,
+    }
+}
+
+pub fn close_session(token: &str) {
+    let mut sessions = SESSIONS.lock().unwrap_or_else(PoisonError::into_inner);
+
+    sessions.remove(token);
+}
+
+/// `Set-Cookie` for the proxy session, `Secure` when the browser reached
+/// the proxy over HTTPS.
+pub fn session_cookie(token: &str, ttl: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly{}; SameSite=Lax",
+        SESSION_COOKIE,
+        token,
+        ttl,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+fn too_many_failures(key: &FailureKey) -> bool {
+    let failures = LOGIN_FAILURES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    failures.get(key).is_some_and(|(count, since)| {
+        *count >= MAX_LOGIN_FAILURES && since.elapsed() < LOGIN_FAILURE_WINDOW
+    })
+}
+
+fn record_login_failure(key: FailureKey) {
+    let mut failures = LOGIN_FAILURES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    failures.retain(|_, (_, since)| since.elapsed() < LOGIN_FAILURE_WINDOW);
+    failures.entry(key).or_insert((0, Instant::now())).0 += 1;
+}
+
+fn clear_login_failures(key: &FailureKey) {
+    let mut failures = LOGIN_FAILURES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+
+    failures.remove(key);
+}
+
+/// Proxy session token sent by the browser, if any.
+pub fn session_token(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|cookie| read_cookies(cookie.as_bytes()))
+        .find(|(name, _)| *name == SESSION_COOKIE.as_bytes())
+        .and_then(|(_, value)| str::from_utf8(value).ok())
+        .map(|value| value.to_string())
+}
+
+// The proxy session is never forwarded upstream
+fn strip_session_cookie(headers: &mut HeaderMap) {
+    let kept: Vec<String> = headers
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|cookie| read_cookies(cookie.as_bytes()))
+        .filter(|(name, _)| *name != SESSION_COOKIE.as_bytes())
+        .map(|(name, value)| {
+            format!(
+                "{}={}",
+                String::from_utf8_lossy(name),
+                String::from_utf8_lossy(value)
+            )
+        })
+        .collect();
+
+    headers.remove(COOKIE);
+    if !kept.is_empty() {
+        if let Ok(cookie) = HeaderValue::from_str(&kept.join("; ")) {
+            headers.insert(COOKIE, cookie);
+        }
+    }
+}
+
+// Only local paths, so the login page can't be used as an open redirect.
+// Browsers drop tabs and newlines from URLs, so control characters are
+// refused as well.
+pub fn safe_next(next: &str) -> String {
+    if next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(char::is_control)
+    {
+        return next.to_string();
+    }
+    "/".to_string()
+}
+
+fn query_param(req: &Request<Body>, key: &str) -> Option<String> {
+    let query = req.uri().query()?;
+
+    serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .ok()?
+        .into_iter()
+        .find(|(name, _)| name == key)
+        .map(|(_, value)| value)
+}
+
+fn login_page(next: &str, error: Option<&str>, portal: &PortalConfig) -> String {
+    let error = match error {
+        Some(error) => format!("<p class=\"error\">{}</p>", escape_html(error)),
+        None => String::new(),
+    };
+    let sso = match portal.oidc {
+        Some(_) => format!(
+            "<p><a href=\"{}?{}\">Log in with single sign-on</a></p>",
+            OIDC_LOGIN_PATH,
+            escape_html(&serde_urlencoded::to_string([("next", next)]).unwrap_or_default())
+        ),
+        None => String::new(),
+    };
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head><meta charset="utf-8"><title>Proxy login</title></head>
+<body>
+<h1>Proxy login</h1>
+{}
+<form method="post" action="{}">
+<input type="hidden" name="next" value="{}">
+<label>Username <input type="text" name="username" autofocus></label>
+<label>Password <input type="password" name="password"></label>
+<input type="submit" value="Log in">
+</form>
+{}
+</body>
+</html>
+"#,
+        error,
+        LOGIN_PATH,
+        escape_html(next),
+        sso
+    )
+}
+
+fn login_response(
+    status: StatusCode,
+    next: &str,
+    error: Option<&str>,
+    portal: &PortalConfig,
+) -> Result<Response<Body>, ProxyError> {
+    Ok(Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(login_page(next, error, portal)))?)
+}
+
+async fn login(req: Request<Body>, portal: &PortalConfig) -> Result<Response<Body>, ProxyError> {
+    if req.method() != Method::POST {
+        let next = safe_next(&query_param(&req, "next").unwrap_or_default());
+        return login_response(StatusCode::OK, &next, None, portal);
+    }
+
+    let secure = utils::is_https(req.uri(), req.headers());
+    // Set by the server on every request it accepts
+    let client_ip = req.extensions().get::<SocketAddr>().map(SocketAddr::ip);
+    let (parts, body) = req.into_parts();
+    let limits = BufferLimits::new(MAX_LOGIN_BODY_SIZE, MAX_LOGIN_BODY_SIZE);
+    let body = match buffer::buffer_body(body, &parts.headers, &limits).await {
+        Ok(buffered) => hyper::body::to_bytes(buffered.to_body().await?).await?,
+        Err(ProxyError::BodyTooLarge(max)) => return status::payload_too_large(max),
+        Err(err) => return Err(err),
+    };
+    let fields: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap_or_default();
+    let field = |key: &str| fields.get(key).cloned().unwrap_or_default();
+    let (username, password, next) = (field("username"), field("password"), field("next"));
+    let next = safe_next(&next);
+    let failure_key = (client_ip, username.clone());
+    if too_many_failures(&failure_key) {
+        eprintln!("Proxy login refused for {}, too many failures", username);
+        return login_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            &next,
+            Some("Too many failed logins, try again later"),
+            portal,
+        );
+    }
+
+    let mut groups = None;
+    if let Some(users_file) = portal.users_file.clone() {
+        let (name, password) = (username.clone(), password.clone());
+        // Password hashes are deliberately slow, keep them off the async workers
+        let valid = tokio::task::spawn_blocking(move || {
+            users::check_credentials(&users_file, &name, &password)
+        })
+        .await
+        .unwrap_or(false);
+        if valid {
+            groups = Some(Vec::new());
+        }
+    }
+    if let (None, Some(directory)) = (&groups, &portal.ldap) {
+        groups = match ldap::authenticate(&username, &password, directory).await {
+            Ok(groups) => groups,
+            Err(_) => {
+                return login_response(
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    &next,
+                    Some("Directory unavailable, try again later"),
+                    portal,
+                )
+            }
+        };
+    }
+
+    let mut groups = match groups {
+        Some(groups) => groups,
+        None => {
+            eprintln!("Proxy login failed for {}", username);
+            record_login_failure(failure_key);
+            return login_response(
+                StatusCode::UNAUTHORIZED,
+                &next,
+                Some("Invalid username or password"),
+                portal,
+            );
+        }
+    };
+
+    println!("Proxy login succeeded for {}", username);
+    clear_login_failures(&failure_key);
+    groups.extend(portal.groups_of(&username));
+    groups.sort();
+    groups.dedup();
+    let user = ProxyUser::new(&username, groups);
+    let token = open_session(user, portal.session_ttl);
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, next)
+        .header(
+            SET_COOKIE,
+            session_cookie(&token, portal.session_ttl, secure),
+        )
+        .body(Body::empty())?)
+}
+
+fn logout(req: &Request<Body>) -> Result<Response<Body>, ProxyError> {
+    if let Some(token) = session_token(req.headers()) {
+        close_session(&token);
+    }
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, LOGIN_PATH)
+        .header(
+            SET_COOKIE,
+            session_cookie("", 0, utils::is_https(req.uri(), req.headers())),
+        )
+        .body(Body::empty())?)
+}
+
+/// Authenticates downstream users before anything is sent upstream.
+///
+/// Without a [portal] table every request is forwarded anonymously.
+pub async fn gate(
+    mut req: Request<Body>,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+    config: Value,
+) -> Result<Gate, ProxyError> {
+    let portal = match setup_portal(config.clone()) {
+        Some(portal) => portal,
+        None => return Ok(Gate::Forward(req, None)),
+    };
+
+    match req.uri().path() {
+        LOGIN_PATH => return Ok(Gate::Respond(login(req, &portal).await?)),
+        LOGOUT_PATH => return Ok(Gate::Respond(logout(&req)?)),
+        _ => {}
+    }
+    if let Some(oidc) = &portal.oidc {
+        match req.uri().path() {
+            OIDC_LOGIN_PATH => {
+                let next = query_param(&req, "next").unwrap_or_default();
+                let secure = utils::is_https(req.uri(), req.headers());
+                return Ok(Gate::Respond(
+                    oidc::start_login(&next, secure, oidc, client).await?,
+                ));
+            }
+            OIDC_CALLBACK_PATH => {
+                return Ok(Gate::Respond(
+                    oidc::callback(&req, oidc, &portal, client).await?,
+                ))
+            }
+            _ => {}
+        }
+    }
+
+    let user = session_token(req.headers()).and_then(|token| session_user(&token));
+    strip_session_cookie(req.headers_mut());
+
+    match user {
+        Some(user) => Ok(Gate::Forward(req, Some(user))),
+        // Judged on the route the request goes to, which the referer picks
+        None if utils::routed_path(
+            &utils::route_ref(req.uri().path(), req.headers()),
+            req.uri(),
+            config,
+        )
+        .is_some_and(|path| portal.is_public(&path)) =>
+        {
+            Ok(Gate::Forward(req, None))
+        }
+        None if req.method() == Method::GET || req.method() == Method::HEAD => {
+            let next = req
+                .uri()
+                .path_and_query()
+                .map(|path| path.as_str())
+                .unwrap_or("/");
+            let location = serde_urlencoded::to_string([("next", next)]).unwrap_or_default();
+            // Without local users the provider is the only way in
+            let login_path = match (&portal.users_file, &portal.oidc) {
+                (None, Some(_)) => OIDC_LOGIN_PATH,
+                _ => LOGIN_PATH,
+            };
+            Ok(Gate::Respond(status::redirect(&format!(
+                "{}?{}",
+                login_path, location
+            ))?))
+        }
+        None => Ok(Gate::Respond(status::login_required()?)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::header::REFERER;
+    use std::{env, fs};
+    use tokio::runtime::Runtime;
+
+    fn portal_config(users_file: &str) -> Value {
+        toml::from_str(&format!(
+            r#"
+            [portal]
+            users_file = "{}"
+            public = ["/public"]
+
+            [portal.groups]
+            ops = ["alice"]
+            admins = ["alice", "bob"]
+
+            [redirections]
+            "/public" = "http://assets.example/"
+            "/app" = "http://app.example/"
+            "#,
+            users_file
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_groups_of() {
+        let portal = setup_portal(portal_config("users")).unwrap();
+
+        assert_eq!(portal.groups_of("alice"), vec!["admins", "ops"]);
+        assert_eq!(portal.groups_of("bob"), vec!["admins"]);
+        assert!(portal.groups_of("carol").is_empty());
+        assert!(portal.is_public("/public/style.css"));
+        assert!(!portal.is_public("/publication"));
+    }
+
+    #[test]
+    fn test_user_credentials() {
+        let credentials = UserCredentials {
+            users: HashMap::from([("alice".to_string(), "alice-account")]),
+            groups: vec![
+                ("dba".to_string(), "dba-account"),
+                ("ops".to_string(), "ops-account"),
+            ],
+            shared: Some("shared-account"),
+        };
+        let user = |name: &str, groups: &[&str]| {
+            ProxyUser::new(name, groups.iter().map(|group| group.to_string()).collect())
+        };
+
+        let alice = user("alice", &["ops"]);
+        assert_eq!(credentials.resolve(Some(&alice)), Some(&"alice-account"));
+        let bob = user("bob", &["ops", "dba"]);
+        assert_eq!(credentials.resolve(Some(&bob)), Some(&"dba-account"));
+        let carol = user("carol", &["sales"]);
+        assert_eq!(credentials.resolve(Some(&carol)), Some(&"shared-account"));
+        assert_eq!(credentials.resolve(None), Some(&"shared-account"));
+
+        let personal_only = UserCredentials {
+            shared: None,
+            ..credentials
+        };
+        assert_eq!(personal_only.resolve(Some(&carol)), None);
+        assert_eq!(personal_only.resolve(None), None);
+    }
+
+    #[test]
+    fn test_sessions() {
+        let user = ProxyUser::new("alice", vec!["ops".to_string()]);
+        let token = open_session(user.clone(), 60);
+
+        assert_eq!(session_user(&token), Some(user));
+        close_session(&token);
+        assert_eq!(session_user(&token), None);
+
+        let expired = open_session(ProxyUser::new("bob", Vec::new()), 0);
+        assert_eq!(session_user(&expired), None);
+    }
+
+    #[test]
+    fn test_strip_session_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            COOKIE,
+            HeaderValue::from_static("app=1; proxy_session=secret; other=2"),
+        );
+
+        assert_eq!(session_token(&headers), Some("secret".to_string()));
+        strip_session_cookie(&mut headers);
+        assert_eq!(headers.get(COOKIE).unwrap(), "app=1; other=2");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_static("proxy_session=secret"));
+        strip_session_cookie(&mut headers);
+        assert!(!headers.contains_key(COOKIE));
+    }
+
+    #[test]
+    fn test_safe_next() {
+        assert_eq!(safe_next("/app?page=2"), "/app?page=2");
+        assert_eq!(safe_next("//evil.example"), "/");
+        assert_eq!(safe_next("https://evil.example"), "/");
+        assert_eq!(safe_next("/\\evil.example"), "/");
+        assert_eq!(safe_next("/\t/evil.example"), "/");
+        assert_eq!(safe_next("/\n/evil.example"), "/");
+        assert_eq!(safe_next(""), "/");
+    }
+
+    #[test]
+    fn test_session_cookie() {
+        assert!(session_cookie("t", 60, true).contains("; Secure"));
+        assert!(!session_cookie("t", 60, false).contains("Secure"));
+    }
+
+    fn request(method: Method, uri: &str, cookie: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(cookie) = cookie {
+            builder = builder.header(COOKIE, cookie);
+        }
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[test]
+    fn test_gate_login_flow() {
+        let users_file = env::temp_dir().join("reverse-test-portal-users");
+        fs::write(&users_file, "alice:$apr1$r31nS4lt$zQODS1FlVpiZWInTNFRuH0\n").unwrap();
+        let config = portal_config(&users_file.display().to_string());
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let client = Client::builder().build(HttpsConnector::new());
+            let anonymous = request(Method::GET, "/app?x=1", None, "");
+            match gate(anonymous, &client, config.clone()).await.unwrap() {
+                Gate::Respond(res) => {
+                    assert_eq!(res.status(), StatusCode::FOUND);
+                    assert_eq!(
+                        res.headers()[LOCATION],
+                        "/__proxy/login?next=%2Fapp%3Fx%3D1"
+                    );
+                }
+                Gate::Forward(..) => panic!("anonymous request forwarded"),
+            }
+
+            let public = request(Method::GET, "/public", None, "");
+            assert!(matches!(
+                gate(public, &client, config.clone()).await.unwrap(),
+                Gate::Forward(_, None)
+            ));
+            let mut asset = request(Method::GET, "/logo.png", None, "");
+            asset
+                .headers_mut()
+                .insert(REFERER, HeaderValue::from_static("http://proxy/public"));
+            assert!(matches!(
+                gate(asset, &client, config.clone()).await.unwrap(),
+                Gate::Forward(_, None)
+            ));
+            // A public looking path routed to a protected upstream
+            let mut smuggled = request(Method::GET, "/public/x", None, "");
+            smuggled
+                .headers_mut()
+                .insert(REFERER, HeaderValue::from_static("http://proxy/app"));
+            assert!(matches!(
+                gate(smuggled, &client, config.clone()).await.unwrap(),
+                Gate::Respond(_)
+            ));
+
+            let wrong = request(
+                Method::POST,
+                LOGIN_PATH,
+                None,
+                "username=alice&password=wrong&next=%2Fapp",
+            );
+            match gate(wrong, &client, config.clone()).await.unwrap() {
+                Gate::Respond(res) => assert_eq!(res.status(), StatusCode::UNAUTHORIZED),
+                Gate::Forward(..) => panic!("login request forwarded"),
+            }
+
+            let guess = |from: &str| {
+                let mut guess = request(
+                    Method::POST,
+                    LOGIN_PATH,
+                    None,
+                    "username=portal-mallory&password=guess",
+                );
+                guess
+                    .extensions_mut()
+                    .insert(from.parse::<SocketAddr>().unwrap());
+                guess
+            };
+            for attempt in 0..=MAX_LOGIN_FAILURES {
+                let expected = match attempt < MAX_LOGIN_FAILURES {
+                    true => StatusCode::UNAUTHORIZED,
+                    false => StatusCode::TOO_MANY_REQUESTS,
+                };
+                match gate(guess("192.0.2.1:4000"), &client, config.clone())
+                    .await
+                    .unwrap()
+                {
+                    Gate::Respond(res) => assert_eq!(res.status(), expected),
+                    Gate::Forward(..) => panic!("login request forwarded"),
+                }
+            }
+            // Failures from one address do not lock the user out elsewhere
+            match gate(guess("192.0.2.2:4000"), &client, config.clone())
+                .await
+                .unwrap()
+            {
+                Gate::Respond(res) => assert_eq!(res.status(), StatusCode::UNAUTHORIZED),
+                Gate::Forward(..) => panic!("login request forwarded"),
+            }
+
+            let oversized = format!("username=alice&password={}", "x".repeat(64 * 1024));
+            let oversized = request(Method::POST, LOGIN_PATH, None, &oversized);
+            match gate(oversized, &client, config.clone()).await.unwrap() {
+                Gate::Respond(res) => assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE),
+                Gate::Forward(..) => panic!("login request forwarded"),
+            }
+
+            let login = request(
+                Method::POST,
+                LOGIN_PATH,
+                None,
+                "username=alice&password=password&next=%2Fapp",
+            );
+            let cookie = match gate(login, &client, config.clone()).await.unwrap() {
+                Gate::Respond(res) => {
+                    assert_eq!(res.status(), StatusCode::SEE_OTHER);
+                    assert_eq!(res.headers()[LOCATION], "/app");
+                    let set_cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+                    set_cookie.split(';').next().unwrap().to_string()
+                }
+                Gate::Forward(..) => panic!("login request forwarded"),
+            };
+
+            let authenticated = request(Method::POST, "/app", Some(&cookie), "");
+            match gate(authenticated, &client, config.clone()).await.unwrap() {
+                Gate::Forward(req, Some(user)) => {
+                    assert_eq!(user.name, "alice");
+                    assert_eq!(user.groups, vec!["admins", "ops"]);
+                    assert!(!req.headers().contains_key(COOKIE));
+                }
+                _ => panic!("authenticated request not forwarded"),
+            }
+        });
+
+        fs::remove_file(users_file).unwrap();
+    }
+}
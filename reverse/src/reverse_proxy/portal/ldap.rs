@@ -0,0 +1,308 @@
+use ldap3::{ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, Scope, SearchEntry};
+
+use once_cell::sync::Lazy;
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+// https://www.rfc-editor.org/rfc/rfc4513 (bind)
+// https://www.rfc-editor.org/rfc/rfc4515 (search filters)
+
+// Result code of a bind with a wrong DN or password
+const INVALID_CREDENTIALS: u32 = 49;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LdapConfig {
+    pub url: String,
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    pub base_dn: String,
+    pub user_filter: String,
+    pub group_base_dn: String,
+    pub group_filter: String,
+    pub group_attribute: String,
+    pub starttls: bool,
+    pub pool_size: usize,
+    pub timeout: Duration,
+}
+
+// Idle connections bound with the service account, kept per directory
+static POOL: Lazy<Mutex<HashMap<String, Vec<Ldap>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl LdapConfig {
+    fn pool_key(&self) -> String {
+        format!("{} {}", self.url, self.bind_dn.as_deref().unwrap_or(""))
+    }
+
+    fn user_filter(&self, username: &str) -> String {
+        self.user_filter
+            .replace("{username}", &ldap_escape(username))
+    }
+
+    fn group_filter(&self, dn: &str, username: &str) -> String {
+        self.group_filter
+            .replace("{dn}", &ldap_escape(dn))
+            .replace("{username}", &ldap_escape(username))
+    }
+}
+
+async fn connect(ldap: &LdapConfig) -> Result<Ldap, ()> {
+    let settings = LdapConnSettings::new()
+        .set_conn_timeout(ldap.timeout)
+        .set_starttls(ldap.starttls);
+    let (conn, handle) = LdapConnAsync::with_settings(settings, &ldap.url)
+        .await
+        .map_err(|err| {
+            eprintln!("LDAP Error: Connecting to {}: {}", ldap.url, err);
+        })?;
+    ldap3::drive!(conn);
+
+    Ok(handle)
+}
+
+// Service account connection used to look users up
+async fn checkout(ldap: &LdapConfig) -> Result<Ldap, ()> {
+    loop {
+        let pooled = {
+            let mut pool = POOL.lock().unwrap_or_else(PoisonError::into_inner);
+            pool.get_mut(&ldap.pool_key()).and_then(|idle| idle.pop())
+        };
+        match pooled {
+            // dropped when the directory closed it while idle
+            Some(mut handle) => {
+                if !handle.is_closed() {
+                    return Ok(handle);
+                }
+            }
+            None => break,
+        }
+    }
+
+    let mut handle = connect(ldap).await?;
+    if let (Some(bind_dn), Some(bind_password)) = (&ldap.bind_dn, &ldap.bind_password) {
+        handle
+            .with_timeout(ldap.timeout)
+            .simple_bind(bind_dn, bind_password)
+            .await
+            .and_then(|result| result.success())
+            .map_err(|err| {
+                eprintln!("LDAP Error: Service account bind as {}: {}", bind_dn, err);
+            })?;
+    }
+    Ok(handle)
+}
+
+fn checkin(ldap: &LdapConfig, handle: Ldap) {
+    let mut pool = POOL.lock().unwrap_or_else(PoisonError::into_inner);
+    let idle = pool.entry(ldap.pool_key()).or_default();
+
+    if idle.len() < ldap.pool_size {
+        idle.push(handle);
+    }
+}
+
+// The timeout of an Ldap handle only covers the operation that follows it,
+// so each call below sets it again
+async fn search(
+    handle: &mut Ldap,
+    ldap: &LdapConfig,
+    base: &str,
+    filter: &str,
+    attrs: Vec<&str>,
+) -> Result<Vec<SearchEntry>, ()> {
+    let (entries, _) = handle
+        .with_timeout(ldap.timeout)
+        .search(base, Scope::Subtree, filter, attrs)
+        .await
+        .and_then(|result| result.success())
+        .map_err(|err| {
+            eprintln!("LDAP Error: Search {} under {}: {}", filter, base, err);
+        })?;
+
+    Ok(entries.into_iter().map(SearchEntry::construct).collect())
+}
+
+async fn find_user(
+    handle: &mut Ldap,
+    ldap: &LdapConfig,
+    username: &str,
+) -> Result<Option<String>, ()> {
+    // "1.1" asks for no attributes, only the DN is needed
+    let mut entries = search(
+        handle,
+        ldap,
+        &ldap.base_dn,
+        &ldap.user_filter(username),
+        vec!["1.1"],
+    )
+    .await?;
+
+    match entries.len() {
+        1 => Ok(entries.pop().map(|entry| entry.dn)),
+        0 => Ok(None),
+        n => {
+            eprintln!(
+                "LDAP Error: {} entries match {}, login refused",
+                n, username
+            );
+            Ok(None)
+        }
+    }
+}
+
+async fn find_groups(
+    handle: &mut Ldap,
+    ldap: &LdapConfig,
+    dn: &str,
+    username: &str,
+) -> Result<Vec<String>, ()> {
+    let entries = search(
+        handle,
+        ldap,
+        &ldap.group_base_dn,
+        &ldap.group_filter(dn, username),
+        vec![ldap.group_attribute.as_str()],
+    )
+    .await?;
+
+    let mut groups: Vec<String> = entries
+        .into_iter()
+        .filter_map(|mut entry| entry.attrs.remove(&ldap.group_attribute))
+        .flatten()
+        .collect();
+    groups.sort();
+    groups.dedup();
+    Ok(groups)
+}
+
+async fn bind_as(ldap: &LdapConfig, dn: &str, password: &str) -> Result<bool, ()> {
+    let mut handle = connect(ldap).await?;
+    let result = handle
+        .with_timeout(ldap.timeout)
+        .simple_bind(dn, password)
+        .await
+        .map_err(|err| {
+            eprintln!("LDAP Error: Bind as {}: {}", dn, err);
+        })?;
+    let _ = handle.with_timeout(ldap.timeout).unbind().await;
+
+    match result.rc {
+        0 => Ok(true),
+        INVALID_CREDENTIALS => Ok(false),
+        _ => {
+            eprintln!("LDAP Error: Bind as {}: {}", dn, result);
+            Err(())
+        }
+    }
+}
+
+/// Search-then-bind login: finds the user entry with the service account,
+/// binds as that entry with `password` and returns the user's groups.
+///
+/// `Ok(None)` means the credentials were refused, `Err` that the directory
+/// couldn't answer.
+pub async fn authenticate(
+    username: &str,
+    password: &str,
+    ldap: &LdapConfig,
+) -> Result<Option<Vec<String>>, ()> {
+    // An empty password would be an unauthenticated bind, which always succeeds
+    if username.is_empty() || password.is_empty() {
+        return Ok(None);
+    }
+
+    let mut handle = checkout(ldap).await?;
+    let dn = match find_user(&mut handle, ldap, username).await {
+        Ok(Some(dn)) => dn,
+        Ok(None) => {
+            checkin(ldap, handle);
+            return Ok(None);
+        }
+        Err(_) => return Err(()),
+    };
+    if !bind_as(ldap, &dn, password).await? {
+        checkin(ldap, handle);
+        return Ok(None);
+    }
+    let groups = find_groups(&mut handle, ldap, &dn, username).await?;
+
+    checkin(ldap, handle);
+    Ok(Some(groups))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    fn ldap_config(url: &str) -> LdapConfig {
+        LdapConfig {
+            url: url.to_string(),
+            bind_dn: Some("cn=admin,dc=example,dc=org".to_string()),
+            bind_password: Some("admin".to_string()),
+            base_dn: "ou=people,dc=example,dc=org".to_string(),
+            user_filter: "(&(objectClass=inetOrgPerson)(uid={username}))".to_string(),
+            group_base_dn: "ou=groups,dc=example,dc=org".to_string(),
+            group_filter: "(member={dn})".to_string(),
+            group_attribute: "cn".to_string(),
+            starttls: false,
+            pool_size: 2,
+            timeout: Duration::from_secs(5),
+        }
+    }
+
+    #[test]
+    fn test_filters_are_escaped() {
+        let ldap = ldap_config("ldap://127.0.0.1:3389");
+
+        assert_eq!(
+            ldap.user_filter("alice"),
+            "(&(objectClass=inetOrgPerson)(uid=alice))"
+        );
+        assert_eq!(
+            ldap.user_filter("*)(uid=*"),
+            "(&(objectClass=inetOrgPerson)(uid=\\2a\\29\\28uid=\\2a))"
+        );
+        assert_eq!(
+            ldap.group_filter("cn=Doe\\, John,ou=people", "john"),
+            "(member=cn=Doe\\5c, John,ou=people)"
+        );
+    }
+
+    #[test]
+    fn test_empty_password_refused() {
+        let rt = Runtime::new().unwrap();
+        // never reaches the (unreachable) directory
+        let ldap = ldap_config("ldap://127.0.0.1:1");
+
+        assert_eq!(rt.block_on(authenticate("alice", "", &ldap)), Ok(None));
+        assert_eq!(rt.block_on(authenticate("alice", "x", &ldap)), Err(()));
+    }
+
+    // Needs the test_ldap_server container:
+    // docker run -d -p 3389:389 ldap_image
+    #[test]
+    #[ignore]
+    fn test_authenticate_against_directory() {
+        let rt = Runtime::new().unwrap();
+        let ldap = ldap_config("ldap://127.0.0.1:3389");
+
+        rt.block_on(async {
+            assert_eq!(
+                authenticate("alice", "alice-password", &ldap).await,
+                Ok(Some(vec!["admins".to_string(), "developers".to_string()]))
+            );
+            assert_eq!(authenticate("alice", "wrong", &ldap).await, Ok(None));
+            assert_eq!(authenticate("nobody", "x", &ldap).await, Ok(None));
+            assert_eq!(
+                authenticate("bob", "bob-password", &ldap).await,
+                Ok(Some(vec!["developers".to_string()]))
+            );
+
+            let pool = POOL.lock().unwrap();
+            assert!(pool[&ldap.pool_key()].len() <= ldap.pool_size);
+        });
+    }
+}
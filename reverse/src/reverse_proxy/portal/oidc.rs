@@ -0,0 +1,882 @@
+use base64::{engine::general_purpose, Engine as _};
+use hyper::{
+    header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, COOKIE, LOCATION, SET_COOKIE},
+    Body, Client, Method, Request, Response, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+
+use once_cell::sync::Lazy;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    str::{self, FromStr},
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+use url::{form_urlencoded::byte_serialize, Url};
+
+use super::{login_response, open_session, safe_next, session_cookie, PortalConfig, ProxyUser};
+use crate::reverse_proxy::{
+    basic::encode_auth, cookie::read_cookies, errors::ProxyError, status, utils,
+};
+
+// https://openid.net/specs/openid-connect-core-1_0.html
+// https://openid.net/specs/openid-connect-discovery-1_0.html
+// https://www.rfc-editor.org/rfc/rfc7636 (PKCE)
+
+pub const OIDC_LOGIN_PATH: &str = "/__proxy/oidc/login";
+pub const OIDC_CALLBACK_PATH: &str = "/__proxy/oidc/callback";
+// Provider identities are named apart from local and directory users
+pub const DEFAULT_USER_PREFIX: &str = "oidc:";
+
+// Ties the callback to the browser that started the login
+const STATE_COOKIE: &str = "proxy_oidc_state";
+
+// Time given to the user to complete the login at the provider
+const PENDING_TTL: Duration = Duration::from_secs(600);
+// Logins in progress at once, new ones are refused above
+const MAX_PENDING: usize = 10_000;
+// Discovery metadata and keys are fetched again after this delay
+const METADATA_TTL: Duration = Duration::from_secs(3600);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OidcConfig {
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: Option<String>,
+    pub redirect_uri: String,
+    pub scopes: String,
+    pub username_claim: String,
+    pub groups_claim: String,
+    pub user_prefix: String,
+}
+
+#[derive(Debug, Clone)]
+struct Provider {
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    fetched_at: Instant,
+}
+
+#[derive(Debug)]
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    next: String,
+    expires_at: Instant,
+}
+
+static PROVIDERS: Lazy<Mutex<HashMap<String, Provider>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+static JWKS: Lazy<Mutex<HashMap<String, (JwkSet, Instant)>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+static PENDING: Lazy<Mutex<HashMap<String, PendingLogin>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+fn random_token() -> String {
+    let bytes: [u8; 32] = rand::random();
+    general_purpose::URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn code_challenge(code_verifier: &str) -> String {
+    general_purpose::URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()))
+}
+
+fn form_encode(value: &str) -> String {
+    byte_serialize(value.as_bytes()).collect()
+}
+
+async fn fetch_json(
+    req: Request<Body>,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<serde_json::Value, ()> {
+    let uri = req.uri().clone();
+    let response = client.request(req).await.map_err(|err| {
+        eprintln!("OIDC Error: {} unreachable: {}", uri, err);
+    })?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|err| {
+            eprintln!("OIDC Error: {}", err);
+        })?;
+
+    if !status.is_success() {
+        eprintln!("OIDC Error: {} answered {}", uri, status);
+        return Err(());
+    }
+    serde_json::from_slice(&body).map_err(|err| {
+        eprintln!("OIDC Error: Invalid JSON from {}: {}", uri, err);
+    })
+}
+
+fn get_request(url: &str) -> Result<Request<Body>, ()> {
+    Request::builder()
+        .uri(url)
+        .header(ACCEPT, "application/json")
+        .body(Body::empty())
+        .map_err(|err| {
+            eprintln!("OIDC Error: {}", err);
+        })
+}
+
+fn parse_discovery(issuer: &str, metadata: &serde_json::Value) -> Result<Provider, ()> {
+    // The metadata must describe the issuer it was fetched for
+    if metadata["issuer"].as_str() != Some(issuer) {
+        eprintln!(
+            "OIDC Error: Discovery issuer {} doesn't match {}",
+            metadata["issuer"], issuer
+        );
+        return Err(());
+    }
+    let endpoint = |key: &str| match metadata[key].as_str() {
+        Some(endpoint) => Ok(endpoint.to_string()),
+        None => {
+            eprintln!("OIDC Error: No {} in the discovery metadata", key);
+            Err(())
+        }
+    };
+
+    Ok(Provider {
+        authorization_endpoint: endpoint("authorization_endpoint")?,
+        token_endpoint: endpoint("token_endpoint")?,
+        jwks_uri: endpoint("jwks_uri")?,
+        fetched_at: Instant::now(),
+    })
+}
+
+async fn discover(
+    oidc: &OidcConfig,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<Provider, ()> {
+    {
+        let providers = PROVIDERS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(provider) = providers.get(&oidc.issuer) {
+            if provider.fetched_at.elapsed() < METADATA_TTL {
+                return Ok(provider.clone());
+            }
+        }
+    }
+
+    let url = format!(
+        "{}/.well-known/openid-configuration",
+        oidc.issuer.trim_end_matches('/')
+    );
+    let metadata = fetch_json(get_request(&url)?, client).await?;
+    let provider = parse_discovery(&oidc.issuer, &metadata)?;
+
+    let mut providers = PROVIDERS.lock().unwrap_or_else(PoisonError::into_inner);
+    providers.insert(oidc.issuer.clone(), provider.clone());
+    Ok(provider)
+}
+
+// The algorithm the provider's key is for, never the one the token claims.
+// Without an alg parameter, the key type and curve tell it.
+fn key_algorithm(jwk: &Jwk) -> Option<Algorithm> {
+    let algorithm = match (&jwk.common.key_algorithm, &jwk.algorithm) {
+        (Some(algorithm), _) => Algorithm::from_str(&algorithm.to_string()).ok()?,
+        (None, AlgorithmParameters::RSA(_)) => Algorithm::RS256,
+        (None, AlgorithmParameters::EllipticCurve(key)) => match key.curve {
+            EllipticCurve::P256 => Algorithm::ES256,
+            EllipticCurve::P384 => Algorithm::ES384,
+            _ => return None,
+        },
+        (None, AlgorithmParameters::OctetKeyPair(_)) => Algorithm::EdDSA,
+        (None, AlgorithmParameters::OctetKey(_)) => return None,
+    };
+
+    // Shared secrets have no place in a public key set
+    match algorithm {
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => None,
+        algorithm => Some(algorithm),
+    }
+}
+
+// Signing keys, fetched again when the token names a key we don't know yet
+async fn signing_key(
+    provider: &Provider,
+    kid: Option<&str>,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<(DecodingKey, Algorithm), ()> {
+    let find = |jwks: &JwkSet| match kid {
+        Some(kid) => jwks.find(kid).cloned(),
+        None if jwks.keys.len() == 1 => jwks.keys.first().cloned(),
+        None => None,
+    };
+
+    let cached = {
+        let keys = JWKS.lock().unwrap_or_else(PoisonError::into_inner);
+        keys.get(&provider.jwks_uri)
+            .filter(|(_, fetched_at)| fetched_at.elapsed() < METADATA_TTL)
+            .and_then(|(jwks, _)| find(jwks))
+    };
+    let jwk = match cached {
+        Some(jwk) => jwk,
+        None => {
+            let json = fetch_json(get_request(&provider.jwks_uri)?, client).await?;
+            let jwks: JwkSet = serde_json::from_value(json).map_err(|err| {
+                eprintln!("OIDC Error: Invalid JWKS: {}", err);
+            })?;
+            let jwk = find(&jwks);
+            let mut keys = JWKS.lock().unwrap_or_else(PoisonError::into_inner);
+            keys.insert(provider.jwks_uri.clone(), (jwks, Instant::now()));
+            match jwk {
+                Some(jwk) => jwk,
+                None => {
+                    eprintln!("OIDC Error: No signing key {:?} in the JWKS", kid);
+                    return Err(());
+                }
+            }
+        }
+    };
+
+    let algorithm = match key_algorithm(&jwk) {
+        Some(algorithm) => algorithm,
+        None => {
+            eprintln!("OIDC Error: No usable algorithm for signing key {:?}", kid);
+            return Err(());
+        }
+    };
+    let key = DecodingKey::from_jwk(&jwk).map_err(|err| {
+        eprintln!("OIDC Error: Unusable signing key: {}", err);
+    })?;
+    Ok((key, algorithm))
+}
+
+/// Checks the ID token signature, issuer, audience, expiry and nonce, and
+/// returns its claims.
+async fn verify_id_token(
+    id_token: &str,
+    nonce: &str,
+    oidc: &OidcConfig,
+    provider: &Provider,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<serde_json::Value, ()> {
+    let header = decode_header(id_token).map_err(|err| {
+        eprintln!("OIDC Error: Invalid ID token: {}", err);
+    })?;
+    let (key, algorithm) = signing_key(provider, header.kid.as_deref(), client).await?;
+    if header.alg != algorithm {
+        eprintln!(
+            "OIDC Error: ID token signed with {:?}, its key is for {:?}",
+            header.alg, algorithm
+        );
+        return Err(());
+    }
+
+    let mut validation = Validation::new(algorithm);
+    validation.set_issuer(&[&oidc.issuer]);
+    validation.set_audience(&[&oidc.client_id]);
+    let claims = decode::<serde_json::Value>(id_token, &key, &validation)
+        .map_err(|err| {
+            eprintln!("OIDC Error: ID token rejected: {}", err);
+        })?
+        .claims;
+
+    if claims["nonce"].as_str() != Some(nonce) {
+        eprintln!("OIDC Error: ID token nonce mismatch");
+        return Err(());
+    }
+    Ok(claims)
+}
+
+/// Maps the ID token claims to a proxy user named with the `user_prefix`,
+/// adding the groups the [portal.groups] table gives to that prefixed name.
+/// A provider account never takes on the groups or credentials of the
+/// local user of the same name.
+fn map_claims(
+    claims: &serde_json::Value,
+    oidc: &OidcConfig,
+    portal: &PortalConfig,
+) -> Option<ProxyUser> {
+    let name = claims[oidc.username_claim.as_str()]
+        .as_str()
+        .or_else(|| claims["sub"].as_str())?;
+    let name = format!("{}{}", oidc.user_prefix, name);
+
+    let mut groups: Vec<String> = match &claims[oidc.groups_claim.as_str()] {
+        serde_json::Value::Array(groups) => groups
+            .iter()
+            .filter_map(|group| group.as_str())
+            .map(|group| group.to_string())
+            .collect(),
+        serde_json::Value::String(group) => vec![group.clone()],
+        _ => Vec::new(),
+    };
+    groups.extend(portal.groups_of(&name));
+    groups.sort();
+    groups.dedup();
+
+    Some(ProxyUser::new(&name, groups))
+}
+
+fn authorization_url(
+    oidc: &OidcConfig,
+    provider: &Provider,
+    state: &str,
+    pending: &PendingLogin,
+) -> Result<String, ()> {
+    let mut url = Url::parse(&provider.authorization_endpoint).map_err(|err| {
+        eprintln!("OIDC Error: Invalid authorization endpoint: {}", err);
+    })?;
+
+    url.query_pairs_mut()
+        .append_pair("response_type", "code")
+        .append_pair("client_id", &oidc.client_id)
+        .append_pair("redirect_uri", &oidc.redirect_uri)
+        .append_pair("scope", &oidc.scopes)
+        .append_pair("state", state)
+        .append_pair("nonce", &pending.nonce)
+        .append_pair("code_challenge", &code_challenge(&pending.code_verifier))
+        .append_pair("code_challenge_method", "S256");
+    Ok(url.to_string())
+}
+
+fn state_cookie(state: &str, max_age: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path={}; Max-Age={}; HttpOnly{}; SameSite=Lax",
+        STATE_COOKIE,
+        state,
+        OIDC_CALLBACK_PATH,
+        max_age,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+/// Sends the user to the provider, remembering the PKCE verifier and nonce
+/// under a fresh state value, also given to the browser in a cookie.
+pub async fn start_login(
+    next: &str,
+    secure: bool,
+    oidc: &OidcConfig,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<Response<Body>, ProxyError> {
+    let provider = match discover(oidc, client).await {
+        Ok(provider) => provider,
+        Err(_) => return status::bad_gateway("identity provider"),
+    };
+    let state = random_token();
+    let pending = PendingLogin {
+        code_verifier: random_token(),
+        nonce: random_token(),
+        next: safe_next(next),
+        expires_at: Instant::now() + PENDING_TTL,
+    };
+    let location = match authorization_url(oidc, &provider, &state, &pending) {
+        Ok(location) => location,
+        Err(_) => return status::bad_gateway("identity provider"),
+    };
+
+    let cookie = state_cookie(&state, PENDING_TTL.as_secs(), secure);
+    let kept = keep_pending(
+        &mut PENDING.lock().unwrap_or_else(PoisonError::into_inner),
+        state,
+        pending,
+    );
+    if !kept {
+        return status::service_unavailable();
+    }
+
+    let mut response = status::redirect(&location)?;
+    if let Ok(cookie) = cookie.parse() {
+        response.headers_mut().insert(SET_COOKIE, cookie);
+    }
+    Ok(response)
+}
+
+// Adds a login to `logins` once the expired ones are dropped, unless
+// MAX_PENDING are still in progress
+fn keep_pending(
+    logins: &mut HashMap<String, PendingLogin>,
+    state: String,
+    pending: PendingLogin,
+) -> bool {
+    let now = Instant::now();
+    logins.retain(|_, login| login.expires_at > now);
+    if logins.len() >= MAX_PENDING {
+        eprintln!(
+            "OIDC Error: {} logins in progress, login refused",
+            logins.len()
+        );
+        return false;
+    }
+    logins.insert(state, pending);
+    true
+}
+
+fn take_pending(state: &str) -> Option<PendingLogin> {
+    let mut logins = PENDING.lock().unwrap_or_else(PoisonError::into_inner);
+
+    logins
+        .remove(state)
+        .filter(|login| login.expires_at > Instant::now())
+}
+
+fn token_request(
+    code: &str,
+    code_verifier: &str,
+    oidc: &OidcConfig,
+    provider: &Provider,
+) -> Result<Request<Body>, ()> {
+    let body = serde_urlencoded::to_string([
+        ("grant_type", "authorization_code"),
+        ("code", code),
+        ("redirect_uri", &oidc.redirect_uri),
+        ("client_id", &oidc.client_id),
+        ("code_verifier", code_verifier),
+    ])
+    .map_err(|err| {
+        eprintln!("OIDC Error: {}", err);
+    })?;
+
+    let mut builder = Request::builder()
+        .method(Method::POST)
+        .uri(&provider.token_endpoint)
+        .header(CONTENT_TYPE, "application/x-www-form-urlencoded")
+        .header(ACCEPT, "application/json");
+    // Public clients rely on PKCE alone
+    if let Some(secret) = &oidc.client_secret {
+        builder = builder.header(
+            AUTHORIZATION,
+            encode_auth(form_encode(&oidc.client_id), form_encode(secret)),
+        );
+    }
+    builder.body(Body::from(body)).map_err(|err| {
+        eprintln!("OIDC Error: {}", err);
+    })
+}
+
+async fn authenticate(
+    query: &str,
+    browser_state: Option<&str>,
+    oidc: &OidcConfig,
+    portal: &PortalConfig,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<(ProxyUser, String), ()> {
+    let params: HashMap<String, String> = serde_urlencoded::from_str(query).unwrap_or_default();
+
+    if let Some(error) = params.get("error") {
+        eprintln!("OIDC Error: Provider refused the login: {}", error);
+        return Err(());
+    }
+    let (code, state) = match (params.get("code"), params.get("state")) {
+        (Some(code), Some(state)) => (code, state),
+        _ => {
+            eprintln!("OIDC Error: Callback without code or state");
+            return Err(());
+        }
+    };
+    // Someone else's callback URL must not log this browser in
+    if browser_state != Some(state.as_str()) {
+        eprintln!("OIDC Error: State doesn't match the one of this browser");
+        return Err(());
+    }
+    let pending = match take_pending(state) {
+        Some(pending) => pending,
+        None => {
+            eprintln!("OIDC Error: Unknown or expired state");
+            return Err(());
+        }
+    };
+
+    let provider = discover(oidc, client).await?;
+    let tokens = fetch_json(
+        token_request(code, &pending.code_verifier, oidc, &provider)?,
+        client,
+    )
+    .await?;
+    let id_token = match tokens["id_token"].as_str() {
+        Some(id_token) => id_token,
+        None => {
+            eprintln!("OIDC Error: No id_token in the token response");
+            return Err(());
+        }
+    };
+    let claims = verify_id_token(id_token, &pending.nonce, oidc, &provider, client).await?;
+
+    match map_claims(&claims, oidc, portal) {
+        Some(user) => Ok((user, pending.next)),
+        None => {
+            eprintln!("OIDC Error: No {} nor sub claim", oidc.username_claim);
+            Err(())
+        }
+    }
+}
+
+/// Completes the login when the provider redirects the user back.
+pub async fn callback(
+    req: &Request<Body>,
+    oidc: &OidcConfig,
+    portal: &PortalConfig,
+    client: &Client<HttpsConnector<hyper::client::HttpConnector>>,
+) -> Result<Response<Body>, ProxyError> {
+    let query = req.uri().query().unwrap_or("");
+    let browser_state = req
+        .headers()
+        .get_all(COOKIE)
+        .iter()
+        .flat_map(|cookie| read_cookies(cookie.as_bytes()))
+        .find(|(name, _)| *name == STATE_COOKIE.as_bytes())
+        .and_then(|(_, value)| str::from_utf8(value).ok());
+    let secure = utils::is_https(req.uri(), req.headers());
+    let (user, next) = match authenticate(query, browser_state, oidc, portal, client).await {
+        Ok(authenticated) => authenticated,
+        Err(_) => {
+            let mut response = login_response(
+                StatusCode::UNAUTHORIZED,
+                "/",
+                Some("Single sign-on failed"),
+                portal,
+            )?;
+            if let Ok(cookie) = state_cookie("", 0, secure).parse() {
+                response.headers_mut().insert(SET_COOKIE, cookie);
+            }
+            return Ok(response);
+        }
+    };
+
+    println!("Proxy OIDC login succeeded for {}", user.name);
+    let token = open_session(user, portal.session_ttl);
+
+    Ok(Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(LOCATION, next)
+        .header(
+            SET_COOKIE,
+            session_cookie(&token, portal.session_ttl, secure),
+        )
+        .header(SET_COOKIE, state_cookie("", 0, secure))
+        .body(Body::empty())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reverse_proxy::mock::{self, http_client};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use std::{
+        sync::Arc,
+        time::{SystemTime, UNIX_EPOCH},
+    };
+    use tokio::runtime::Runtime;
+
+    // Throwaway key of the mock identity provider
+    const IDP_KEY: &str = include_str!("oidc_test_key.pem");
+    const IDP_MODULUS: &str = "y5maRg3idx-UxD3IvX83I9isLPlmWsUQ8wq8mO8w0Jx35vO7i1PE7gWJN0XygcDeFOVw0kRtm3xTkrBy_SeRibGs_Mqi31-xc8c837q69yD9r8IKa6wPflESgL5_CO3TPdb9NB-mGAuWElxq-38mOD2jwyzYRAyfM_G_vk8O__MBe0mqwqgdXuQgjJV_N7hPNlZ1nJBJbmSBlcJjx2qLeZ7sayLCsnF6-SF_PKI19KMeocRXH-9ov5AaiEpi0KrWIC0J3e5sbjpUPzq1v9ACEjJZ0FzqlxNpc41LdVpd41blXVmdhHkXK82QIOOsxqjxSlyAmaMiUUXdrSb7PWkEKQ";
+
+    #[derive(Default)]
+    struct IdpState {
+        issuer: String,
+        code_challenge: String,
+        nonce: String,
+        audience: String,
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(claims: serde_json::Value) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = Some("test-key".to_string());
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(IDP_KEY.as_bytes()).unwrap(),
+        )
+        .unwrap()
+    }
+
+    async fn idp_response(req: Request<Body>, state: Arc<Mutex<IdpState>>) -> Response<Body> {
+        let path = req.uri().path().to_string();
+        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+        let state = state.lock().unwrap();
+        let issuer = state.issuer.clone();
+
+        let json = match path.as_str() {
+            "/.well-known/openid-configuration" => serde_json::json!({
+                "issuer": issuer,
+                "authorization_endpoint": format!("{}/authorize", issuer),
+                "token_endpoint": format!("{}/token", issuer),
+                "jwks_uri": format!("{}/jwks", issuer),
+            }),
+            "/jwks" => serde_json::json!({
+                "keys": [{
+                    "kty": "RSA", "kid": "test-key", "use": "sig", "alg": "RS256",
+                    "n": IDP_MODULUS, "e": "AQAB",
+                }]
+            }),
+            "/token" => {
+                let params: HashMap<String, String> = serde_urlencoded::from_bytes(&body).unwrap();
+                let verifier = params.get("code_verifier").cloned().unwrap_or_default();
+                if params.get("code").map(|code| code.as_str()) != Some("the-code")
+                    || code_challenge(&verifier) != state.code_challenge
+                {
+                    return Response::builder()
+                        .status(400)
+                        .body(Body::from(r#"{"error":"invalid_grant"}"#))
+                        .unwrap();
+                }
+                serde_json::json!({
+                    "access_token": "unused",
+                    "token_type": "Bearer",
+                    "id_token": sign(serde_json::json!({
+                        "iss": issuer,
+                        "aud": state.audience,
+                        "sub": "0001",
+                        "preferred_username": "alice",
+                        "groups": ["engineering"],
+                        "nonce": state.nonce,
+                        "iat": now(),
+                        "exp": now() + 300,
+                    })),
+                })
+            }
+            _ => return Response::builder().status(404).body(Body::empty()).unwrap(),
+        };
+        Response::new(Body::from(json.to_string()))
+    }
+
+    fn mock_idp() -> Arc<Mutex<IdpState>> {
+        let state = Arc::new(Mutex::new(IdpState::default()));
+        let shared = state.clone();
+        let issuer = mock::serve(move |req| idp_response(req, shared.clone()));
+        state.lock().unwrap().issuer = issuer;
+        state.lock().unwrap().audience = "proxy".to_string();
+
+        state
+    }
+
+    fn oidc_config(issuer: &str) -> OidcConfig {
+        OidcConfig {
+            issuer: issuer.to_string(),
+            client_id: "proxy".to_string(),
+            client_secret: Some("s3cret".to_string()),
+            redirect_uri: "https://proxy.example/__proxy/oidc/callback".to_string(),
+            scopes: "openid profile".to_string(),
+            username_claim: "preferred_username".to_string(),
+            groups_claim: "groups".to_string(),
+            user_prefix: DEFAULT_USER_PREFIX.to_string(),
+        }
+    }
+
+    fn portal_config(oidc: &OidcConfig) -> PortalConfig {
+        PortalConfig {
+            users_file: None,
+            session_ttl: 60,
+            public: Vec::new(),
+            groups: HashMap::from([
+                ("admins".to_string(), vec!["oidc:alice".to_string()]),
+                ("staff".to_string(), vec!["alice".to_string()]),
+            ]),
+            oidc: Some(oidc.clone()),
+            ldap: None,
+        }
+    }
+
+    // Runs the redirect to the provider and returns the authorization parameters
+    async fn authorize(oidc: &OidcConfig, state: &Arc<Mutex<IdpState>>) -> HashMap<String, String> {
+        let res = start_login("/app", true, oidc, &http_client())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::FOUND);
+        let cookie = res.headers()[SET_COOKIE].to_str().unwrap();
+        assert!(cookie.contains("; HttpOnly; Secure; SameSite=Lax"));
+        let location = Url::parse(res.headers()[LOCATION].to_str().unwrap()).unwrap();
+        let params: HashMap<String, String> = location.query_pairs().into_owned().collect();
+
+        let mut idp = state.lock().unwrap();
+        idp.code_challenge = params["code_challenge"].clone();
+        idp.nonce = params["nonce"].clone();
+        params
+    }
+
+    fn callback_request(code: &str, state: &str) -> Request<Body> {
+        browser_callback(code, state, Some(state))
+    }
+
+    fn browser_callback(code: &str, state: &str, browser_state: Option<&str>) -> Request<Body> {
+        let query = serde_urlencoded::to_string([("code", code), ("state", state)]).unwrap();
+        let mut req = Request::builder().uri(format!("{}?{}", OIDC_CALLBACK_PATH, query));
+        if let Some(browser_state) = browser_state {
+            req = req.header(COOKIE, format!("{}={}", STATE_COOKIE, browser_state));
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_login_flow() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let idp = mock_idp();
+            let oidc = oidc_config(&idp.lock().unwrap().issuer);
+            let portal = portal_config(&oidc);
+            let client = http_client();
+
+            let params = authorize(&oidc, &idp).await;
+            assert_eq!(params["response_type"], "code");
+            assert_eq!(params["code_challenge_method"], "S256");
+            assert_eq!(params["redirect_uri"], oidc.redirect_uri);
+
+            let req = callback_request("the-code", &params["state"]);
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::SEE_OTHER);
+            assert_eq!(res.headers()[LOCATION], "/app");
+
+            let cookies: Vec<_> = res.headers().get_all(SET_COOKIE).iter().collect();
+            assert!(cookies[1].to_str().unwrap().contains("Max-Age=0"));
+            let cookie = cookies[0].to_str().unwrap();
+            let token = cookie.split(';').next().unwrap().split('=').nth(1).unwrap();
+            let user = super::super::session_user(token).unwrap();
+            // the groups of the local "alice" stay hers
+            assert_eq!(user.name, "oidc:alice");
+            assert_eq!(user.groups, vec!["admins", "engineering"]);
+
+            // a state value is only good once
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn test_rejected_id_tokens() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let idp = mock_idp();
+            let oidc = oidc_config(&idp.lock().unwrap().issuer);
+            let portal = portal_config(&oidc);
+            let client = http_client();
+
+            // wrong audience
+            let params = authorize(&oidc, &idp).await;
+            idp.lock().unwrap().audience = "someone-else".to_string();
+            let req = callback_request("the-code", &params["state"]);
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            idp.lock().unwrap().audience = "proxy".to_string();
+
+            // replayed nonce
+            let params = authorize(&oidc, &idp).await;
+            idp.lock().unwrap().nonce = "another-nonce".to_string();
+            let req = callback_request("the-code", &params["state"]);
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+            // callback URL handed to another browser
+            let params = authorize(&oidc, &idp).await;
+            let req = browser_callback("the-code", &params["state"], None);
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+            let req = browser_callback("the-code", &params["state"], Some("other-state"));
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+            // code without the matching PKCE verifier
+            let params = authorize(&oidc, &idp).await;
+            idp.lock().unwrap().code_challenge = code_challenge("forged");
+            let req = callback_request("the-code", &params["state"]);
+            let res = callback(&req, &oidc, &portal, &client).await.unwrap();
+            assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn test_pending_logins_bounded() {
+        let pending = |expires_at: Instant| PendingLogin {
+            code_verifier: String::new(),
+            nonce: String::new(),
+            next: "/".to_string(),
+            expires_at,
+        };
+        let now = Instant::now();
+        let mut logins = HashMap::new();
+        for i in 0..MAX_PENDING {
+            assert!(keep_pending(
+                &mut logins,
+                i.to_string(),
+                pending(now + PENDING_TTL)
+            ));
+        }
+        assert!(!keep_pending(
+            &mut logins,
+            "late".to_string(),
+            pending(now + PENDING_TTL)
+        ));
+        assert!(!logins.contains_key("late"));
+
+        // expired logins make room for new ones
+        logins.get_mut("0").unwrap().expires_at = now;
+        assert!(keep_pending(
+            &mut logins,
+            "late".to_string(),
+            pending(now + PENDING_TTL)
+        ));
+        assert!(!logins.contains_key("0"));
+        assert_eq!(logins.len(), MAX_PENDING);
+    }
+
+    #[test]
+    fn test_code_challenge() {
+        // RFC 7636 appendix B
+        assert_eq!(
+            code_challenge("dBjftJeZ4CVP-mB92K27uhbUJU1p1r_wW1gFWFOEjXk"),
+            "E9Melhoa2OwvFrEMTJguCHaoeK1t8URWbuGJSstw-cM"
+        );
+    }
+
+    #[test]
+    fn test_map_claims() {
+        let oidc = oidc_config("https://idp.example");
+        let portal = portal_config(&oidc);
+
+        let claims = serde_json::json!({"sub": "0002", "groups": "ops"});
+        let user = map_claims(&claims, &oidc, &portal).unwrap();
+        assert_eq!(user, ProxyUser::new("oidc:0002", vec!["ops".to_string()]));
+
+        assert_eq!(map_claims(&serde_json::json!({}), &oidc, &portal), None);
+    }
+
+    #[test]
+    fn test_key_algorithm() {
+        let jwk = |value: serde_json::Value| serde_json::from_value::<Jwk>(value).unwrap();
+        let rsa = serde_json::json!({"kty": "RSA", "n": IDP_MODULUS, "e": "AQAB"});
+
+        assert_eq!(key_algorithm(&jwk(rsa.clone())), Some(Algorithm::RS256));
+        let mut ps = rsa.clone();
+        ps["alg"] = "PS384".into();
+        assert_eq!(key_algorithm(&jwk(ps)), Some(Algorithm::PS384));
+        let mut hs = rsa;
+        hs["alg"] = "HS256".into();
+        assert_eq!(key_algorithm(&jwk(hs)), None);
+        assert_eq!(
+            key_algorithm(&jwk(serde_json::json!({"kty": "oct", "k": "c2VjcmV0"}))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_parse_discovery() {
+        let metadata = serde_json::json!({
+            "issuer": "https://idp.example",
+            "authorization_endpoint": "https://idp.example/authorize",
+            "token_endpoint": "https://idp.example/token",
+            "jwks_uri": "https://idp.example/jwks",
+        });
+
+        assert!(parse_discovery("https://idp.example", &metadata).is_ok());
+        assert!(parse_discovery("https://other.example", &metadata).is_err());
+        assert!(parse_discovery(
+            "https://idp.example",
+            &serde_json::json!({"issuer": "https://idp.example"})
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,210 @@
+use argon2::{password_hash::PasswordHash, Argon2, PasswordVerifier};
+use base64::{engine::general_purpose, Engine as _};
+use md5::{Digest, Md5};
+use sha1::Sha1;
+
+use std::fs;
+
+// https://httpd.apache.org/docs/2.4/misc/password_encryptions.html
+
+const APR1_MAGIC: &str = "$apr1$";
+const ITOA64: &[u8; 64] = b"./0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn to64(mut value: u32, n: usize, out: &mut String) {
+    for _ in 0..n {
+        out.push(ITOA64[(value & 0x3f) as usize] as char);
+        value >>= 6;
+    }
+}
+
+// Apache's variant of the FreeBSD MD5-crypt
+fn apr1(password: &[u8], salt: &[u8]) -> String {
+    let salt = &salt[..salt.len().min(8)];
+    let alternate = Md5::new()
+        .chain_update(password)
+        .chain_update(salt)
+        .chain_update(password)
+        .finalize();
+
+    let mut ctx = Md5::new()
+        .chain_update(password)
+        .chain_update(APR1_MAGIC)
+        .chain_update(salt);
+    let mut len = password.len();
+    while len > 0 {
+        ctx.update(&alternate[..len.min(16)]);
+        len = len.saturating_sub(16);
+    }
+    let mut bits = password.len();
+    while bits > 0 {
+        if bits & 1 == 1 {
+            ctx.update([0u8]);
+        } else {
+            ctx.update(&password[..1]);
+        }
+        bits >>= 1;
+    }
+    let mut digest = ctx.finalize();
+
+    for round in 0..1000 {
+        let mut ctx = Md5::new();
+        if round & 1 == 1 {
+            ctx.update(password);
+        } else {
+            ctx.update(digest);
+        }
+        if round % 3 != 0 {
+            ctx.update(salt);
+        }
+        if round % 7 != 0 {
+            ctx.update(password);
+        }
+        if round & 1 == 1 {
+            ctx.update(digest);
+        } else {
+            ctx.update(password);
+        }
+        digest = ctx.finalize();
+    }
+
+    let d = |i: usize| digest[i] as u32;
+    let mut hash = format!("{}{}$", APR1_MAGIC, String::from_utf8_lossy(salt));
+    to64((d(0) << 16) | (d(6) << 8) | d(12), 4, &mut hash);
+    to64((d(1) << 16) | (d(7) << 8) | d(13), 4, &mut hash);
+    to64((d(2) << 16) | (d(8) << 8) | d(14), 4, &mut hash);
+    to64((d(3) << 16) | (d(9) << 8) | d(15), 4, &mut hash);
+    to64((d(4) << 16) | (d(10) << 8) | d(5), 4, &mut hash);
+    to64(d(11), 2, &mut hash);
+    hash
+}
+
+/// Checks a password against an htpasswd (bcrypt, APR1-MD5, SHA-1) or an
+/// Argon2 hash. Plain-text entries are refused.
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    if hash.starts_with("$argon2") {
+        return match PasswordHash::new(hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(password.as_bytes(), &parsed)
+                .is_ok(),
+            Err(err) => {
+                eprintln!("Invalid Argon2 hash: {}", err);
+                false
+            }
+        };
+    }
+    if hash.starts_with("$2y$") || hash.starts_with("$2b$") || hash.starts_with("$2a$") {
+        return bcrypt::verify(password, hash).unwrap_or(false);
+    }
+    if let Some(salted) = hash.strip_prefix(APR1_MAGIC) {
+        let salt = salted.split('$').next().unwrap_or_default();
+        return constant_time_eq(
+            apr1(password.as_bytes(), salt.as_bytes()).as_bytes(),
+            hash.as_bytes(),
+        );
+    }
+    if let Some(encoded) = hash.strip_prefix("{SHA}") {
+        let digest = general_purpose::STANDARD.encode(Sha1::digest(password.as_bytes()));
+        return constant_time_eq(digest.as_bytes(), encoded.as_bytes());
+    }
+
+    eprintln!("Unsupported password hash format, entry ignored");
+    false
+}
+
+fn find_hash(contents: &str, username: &str) -> Option<String> {
+    contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| *name == username)
+        .map(|(_, hash)| hash.to_string())
+}
+
+/// Verifies `username` and `password` against a `user:hash` file.
+pub fn check_credentials(users_file: &str, username: &str, password: &str) -> bool {
+    let contents = match fs::read_to_string(users_file) {
+        Ok(contents) => contents,
+        Err(err) => {
+            eprintln!("Error reading the {} file : {}", users_file, err);
+            return false;
+        }
+    };
+
+    match find_hash(&contents, username) {
+        Some(hash) => verify_password(password, &hash),
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use argon2::password_hash::{PasswordHasher, SaltString};
+
+    #[test]
+    fn test_apr1() {
+        // openssl passwd -apr1 -salt r31nS4lt password
+        assert_eq!(
+            apr1(b"password", b"r31nS4lt"),
+            "$apr1$r31nS4lt$zQODS1FlVpiZWInTNFRuH0"
+        );
+    }
+
+    #[test]
+    fn test_verify_password_htpasswd() {
+        assert!(verify_password(
+            "password",
+            "$apr1$r31nS4lt$zQODS1FlVpiZWInTNFRuH0"
+        ));
+        assert!(!verify_password(
+            "Password",
+            "$apr1$r31nS4lt$zQODS1FlVpiZWInTNFRuH0"
+        ));
+
+        assert!(verify_password(
+            "password",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="
+        ));
+        assert!(!verify_password(
+            "passwore",
+            "{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g="
+        ));
+
+        let bcrypt_hash = bcrypt::hash("password", 4).unwrap();
+        assert!(verify_password("password", &bcrypt_hash));
+        assert!(!verify_password("wrong", &bcrypt_hash));
+
+        assert!(!verify_password("password", "password"));
+    }
+
+    #[test]
+    fn test_verify_password_argon2() {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        let hash = Argon2::default()
+            .hash_password(b"password", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(verify_password("password", &hash));
+        assert!(!verify_password("wrong", &hash));
+        assert!(!verify_password("password", "$argon2id$broken"));
+    }
+
+    #[test]
+    fn test_find_hash() {
+        let contents = "# proxy users\nalice:{SHA}abc\n\nbob:$apr1$salt$hash\n";
+
+        assert_eq!(find_hash(contents, "alice"), Some("{SHA}abc".to_string()));
+        assert_eq!(
+            find_hash(contents, "bob"),
+            Some("$apr1$salt$hash".to_string())
+        );
+        assert_eq!(find_hash(contents, "carol"), None);
+        assert_eq!(find_hash(contents, "# proxy users"), None);
+    }
+}
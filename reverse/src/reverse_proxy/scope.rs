@@ -0,0 +1,191 @@
+use url::Url;
+
+/// Where configured credentials may be sent: one origin, a path prefix
+/// bounded on segments and optionally a single realm.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CredentialScope {
+    scheme: String,
+    host: String,
+    port: u16,
+    path_prefix: String,
+    realm: Option<String>,
+}
+
+pub type ScopedCredentials<T> = Vec<(CredentialScope, T)>;
+
+impl CredentialScope {
+    /// Builds a scope from a configured URL such as `http://host:8080/app`.
+    pub fn parse(key: &str, realm: Option<&str>) -> Option<Self> {
+        let url = match Url::parse(key) {
+            Ok(url) => url,
+            Err(err) => {
+                eprintln!("Invalid credential scope {}: {}", key, err);
+                return None;
+            }
+        };
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => {
+                eprintln!("Credential scope {} doesn't name a host", key);
+                return None;
+            }
+        };
+
+        Some(CredentialScope {
+            scheme: url.scheme().to_string(),
+            host,
+            port: url.port_or_known_default()?,
+            path_prefix: url.path().trim_end_matches('/').to_string(),
+            realm: realm.map(|realm| realm.to_string()),
+        })
+    }
+
+    pub fn matches(&self, url: &Url, realm: Option<&str>) -> bool {
+        let same_origin = url.scheme() == self.scheme
+            && url.host_str().map(|host| host.to_lowercase()) == Some(self.host.clone())
+            && url.port_or_known_default() == Some(self.port);
+        let same_realm = match (&self.realm, realm) {
+            (None, _) => true,
+            (Some(expected), Some(realm)) => expected == realm,
+            (Some(_), None) => false,
+        };
+
+        same_origin && same_realm && path_has_prefix(url.path(), &self.path_prefix)
+    }
+
+    // Deeper prefixes first, then a realm filter over none
+    fn specificity(&self) -> (usize, bool) {
+        let segments = self
+            .path_prefix
+            .split('/')
+            .filter(|segment| !segment.is_empty())
+            .count();
+
+        (segments, self.realm.is_some())
+    }
+}
+
+/// `/app` is a prefix of `/app` and `/app/page`, but not of `/application`.
+pub fn path_has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    match path.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Credentials of the most specific scope covering `uri` (and `realm`, when known).
+pub fn match_scope<'a, T>(
+    scoped: &'a [(CredentialScope, T)],
+    uri: &str,
+    realm: Option<&str>,
+) -> Option<&'a T> {
+    let url = Url::parse(uri).ok()?;
+
+    scoped
+        .iter()
+        .filter(|(scope, _)| scope.matches(&url, realm))
+        .max_by_key(|(scope, _)| scope.specificity())
+        .map(|(_, credentials)| credentials)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_path_has_prefix() {
+        assert!(path_has_prefix("/app", "/app"));
+        assert!(path_has_prefix("/app/", "/app"));
+        assert!(path_has_prefix("/app/page", "/app/"));
+        assert!(path_has_prefix("/anything", ""));
+        assert!(path_has_prefix("/anything", "/"));
+        assert!(!path_has_prefix("/application", "/app"));
+        assert!(!path_has_prefix("/ap", "/app"));
+    }
+
+    #[test]
+    fn test_match_scope_most_specific() {
+        let scoped = vec![
+            (
+                CredentialScope::parse("http://host/", None).unwrap(),
+                "root",
+            ),
+            (
+                CredentialScope::parse("http://host/app", None).unwrap(),
+                "app",
+            ),
+            (
+                CredentialScope::parse("http://host/app/admin", None).unwrap(),
+                "admin",
+            ),
+        ];
+
+        assert_eq!(
+            match_scope(&scoped, "http://host/app/admin/users", None),
+            Some(&"admin")
+        );
+        assert_eq!(
+            match_scope(&scoped, "http://host/app/page", None),
+            Some(&"app")
+        );
+        assert_eq!(
+            match_scope(&scoped, "http://host/application", None),
+            Some(&"root")
+        );
+        assert_eq!(
+            match_scope(&scoped, "http://HOST:80/app", None),
+            Some(&"app")
+        );
+    }
+
+    #[test]
+    fn test_match_scope_origin() {
+        let scoped = vec![(
+            CredentialScope::parse("https://host:8443/app", None).unwrap(),
+            "app",
+        )];
+
+        assert_eq!(
+            match_scope(&scoped, "https://host:8443/app", None),
+            Some(&"app")
+        );
+        assert_eq!(match_scope(&scoped, "http://host:8443/app", None), None);
+        assert_eq!(match_scope(&scoped, "https://host/app", None), None);
+        assert_eq!(
+            match_scope(&scoped, "https://evil.host:8443/app", None),
+            None
+        );
+        assert_eq!(match_scope(&scoped, "/app", None), None);
+    }
+
+    #[test]
+    fn test_match_scope_realm() {
+        let scoped = vec![
+            (
+                CredentialScope::parse("http://host/app", None).unwrap(),
+                "any",
+            ),
+            (
+                CredentialScope::parse("http://host/app", Some("Admin")).unwrap(),
+                "admin",
+            ),
+        ];
+
+        assert_eq!(
+            match_scope(&scoped, "http://host/app", Some("Admin")),
+            Some(&"admin")
+        );
+        assert_eq!(
+            match_scope(&scoped, "http://host/app", Some("Other")),
+            Some(&"any")
+        );
+        assert_eq!(match_scope(&scoped, "http://host/app", None), Some(&"any"));
+    }
+
+    #[test]
+    fn test_parse_without_host() {
+        assert_eq!(CredentialScope::parse("/app", None), None);
+    }
+}
@@ -0,0 +1,53 @@
+use std::{env, fs};
+
+/// Resolves a secret reference from the config.
+///
+/// `env:NAME` reads an environment variable, `file:PATH` reads a file (as
+/// mounted by container secrets) and anything else is taken literally.
+pub fn resolve_secret(reference: &str) -> Option<String> {
+    if let Some(name) = reference.strip_prefix("env:") {
+        return match env::var(name) {
+            Ok(value) => Some(value),
+            Err(err) => {
+                eprintln!("Error reading secret from ${}: {}", name, err);
+                None
+            }
+        };
+    }
+    if let Some(path) = reference.strip_prefix("file:") {
+        return match fs::read_to_string(path) {
+            Ok(value) => Some(value.trim_end_matches(['\r', '\n']).to_string()),
+            Err(err) => {
+                eprintln!("Error reading secret from {}: {}", path, err);
+                None
+            }
+        };
+    }
+    Some(reference.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_secret() {
+        env::set_var("REVERSE_TEST_SECRET", "from-env");
+        let path = env::temp_dir().join("reverse-test-secret");
+        fs::write(&path, "from-file\n").unwrap();
+
+        assert_eq!(
+            resolve_secret("env:REVERSE_TEST_SECRET"),
+            Some("from-env".to_string())
+        );
+        assert_eq!(
+            resolve_secret(&format!("file:{}", path.display())),
+            Some("from-file".to_string())
+        );
+        assert_eq!(resolve_secret("literal"), Some("literal".to_string()));
+        assert_eq!(resolve_secret("env:REVERSE_TEST_SECRET_UNSET"), None);
+        assert_eq!(resolve_secret("file:/nonexistent/secret"), None);
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -22,9 +22,8 @@ pub async fn _https_support(addr: SocketAddr) -> TcpListenerStream {
 
     let cert = native_tls::Identity::from_pkcs12(&cert_buf, "httpproxypoc42").unwrap();
     let tls_connector = TlsConnector::builder().identity(cert).build().unwrap();
-    let other_https = HttpsConnector::new_with_connector(tls_connector);
+    let _other_https = HttpsConnector::new_with_connector(tls_connector);
 
     let listener = TcpListener::bind(&addr).await.unwrap();
-    let incoming = TcpListenerStream::new(listener);
-    incoming
+    TcpListenerStream::new(listener)
 }
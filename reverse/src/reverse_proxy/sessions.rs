@@ -1,6 +1,43 @@
-use hyper::header::{Entry::Occupied, HeaderMap, HeaderName, HeaderValue, COOKIE, SET_COOKIE};
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, COOKIE, SET_COOKIE};
 
-use std::str;
+use std::{str, time::Duration};
+
+use super::{
+    cookie::parse_set_cookie,
+    cookie_replacement::{cookie_replacements, CookieReplacement, CookieReplacements},
+    jar::default_path,
+    portal::new_token,
+};
+
+pub const DEFAULT_JAR_IDLE: Duration = Duration::from_secs(8 * 60 * 60);
+pub const DEFAULT_JAR_MAX: usize = 10_000;
+
+/// How the proxy handles upstream cookies, from [cookies]: with `jar`, it
+/// keeps them per browser session and drops a jar left unused for `jar_idle`,
+/// keeping `jar_max` jars at most. With `replace`, the browser only gets
+/// tokens standing for the values of the HttpOnly ones, and with
+/// `replace_all` for those scripts read too, breaking the scripts that
+/// need their values.
+#[derive(Debug, PartialEq)]
+pub struct CookieSettings {
+    pub jar: bool,
+    pub jar_idle: Duration,
+    pub jar_max: usize,
+    pub replace: bool,
+    pub replace_all: bool,
+}
+
+impl Default for CookieSettings {
+    fn default() -> Self {
+        CookieSettings {
+            jar: false,
+            jar_idle: DEFAULT_JAR_IDLE,
+            jar_max: DEFAULT_JAR_MAX,
+            replace: false,
+            replace_all: false,
+        }
+    }
+}
 
 pub fn process_session(headers: &HeaderMap) -> String {
     let mut final_cookie = String::new();
@@ -51,34 +88,84 @@ pub fn detect_cookies(headers: &HeaderMap) {
     }
 }
 
-struct CookieReplacement {
-    key: Vec<u8>,
-    value: Vec<u8>,
-    replacement: Vec<u8>,
+/// Rewrites the cookies of every `header` through `replacements`, dropping
+/// the headers left without any.
+pub fn header_map_update(
+    map: &mut HeaderMap,
+    header: HeaderName,
+    replacements: &CookieReplacements,
+) {
+    let mut buffer = Vec::new();
+    let values: Vec<HeaderValue> = map.get_all(&header).iter().cloned().collect();
+    map.remove(&header);
+
+    for value in values {
+        let replaced = cookie_replacements(&mut buffer, value.as_bytes(), replacements);
+        // A cookie dropped last leaves its separator behind
+        let end = replaced
+            .iter()
+            .rposition(|byte| !matches!(byte, b';' | b' '))
+            .map_or(0, |last| last + 1);
+        if end == 0 {
+            continue;
+        }
+        if let Ok(replaced) = HeaderValue::from_bytes(&replaced[..end]) {
+            map.append(header.clone(), replaced);
+        }
+    }
 }
 
-fn cookie_replacement(value: &mut HeaderValue, replacements: &[CookieReplacement]) -> Vec<u8> {
-    println!("HeaderValue: {}", value.to_str().unwrap());
-    for cookie_replacement in replacements {
-        println!(
-            "  Replacement ({}): {} -> {}",
-            str::from_utf8(&cookie_replacement.key).unwrap(),
-            str::from_utf8(&cookie_replacement.value).unwrap(),
-            str::from_utf8(&cookie_replacement.replacement).unwrap()
-        );
+/// Swaps the value of every HttpOnly cookie `headers` set for a token,
+/// keeping in `replacements` how to map it back. Cookies scripts read
+/// pass through as they are, unless `all` holds. A cookie replaces the
+/// one of the same name, domain and path, `request_path` giving the
+/// default path.
+pub fn replace_set_cookies(
+    headers: &mut HeaderMap,
+    request_path: &str,
+    all: bool,
+    replacements: &mut CookieReplacements,
+) {
+    let set_cookies: Vec<HeaderValue> = headers.get_all(SET_COOKIE).iter().cloned().collect();
+    if set_cookies.is_empty() {
+        return;
     }
+    headers.remove(SET_COOKIE);
 
-    Vec::new()
-}
+    for set_cookie in set_cookies {
+        let cookie = match parse_set_cookie(set_cookie.as_bytes(), 0) {
+            Some(cookie) => cookie,
+            None => continue,
+        };
+        let domain = cookie.attrs.domain.to_ascii_lowercase();
+        let path = match cookie.attrs.path {
+            [b'/', ..] => cookie.attrs.path.to_vec(),
+            _ => default_path(request_path).into_bytes(),
+        };
+        replacements.retain(|replacement| !replacement.is_cookie(cookie.name, &domain, &path));
+        // Deletions and cookies for scripts go through as they are
+        if !(cookie.attrs.http_only || all)
+            || cookie.value.is_empty()
+            || cookie.attrs.max_age == Some(0)
+        {
+            headers.append(SET_COOKIE, set_cookie.clone());
+            continue;
+        }
+        let token = new_token();
+        let attributes = set_cookie
+            .as_bytes()
+            .iter()
+            .position(|byte| *byte == b';')
+            .map(|attributes| &set_cookie.as_bytes()[attributes..])
+            .unwrap_or_default();
+        let mut hidden = [cookie.name, b"=", token.as_bytes()].concat();
+        hidden.extend_from_slice(attributes);
 
-fn header_map_update(
-    map: &mut HeaderMap,
-    header: HeaderName,
-    replacements: &[CookieReplacement],
-) -> () {
-    if let Occupied(mut entry) = map.entry(header) {
-        for entry in entry.iter_mut() {
-            cookie_replacement(entry, replacements);
+        if let Ok(hidden) = HeaderValue::from_bytes(&hidden) {
+            let replacement = CookieReplacement::new(cookie.name, token.as_bytes(), cookie.value)
+                .scoped(&domain, &path);
+            replacements.insert(replacement.into());
+            headers.append(SET_COOKIE, hidden);
         }
     }
 }
@@ -92,22 +179,90 @@ https://docs.rs/hyper/0.14.27/hyper/header/index.html
 mod tests {
     use super::*;
     use hyper::header::{COOKIE, SET_COOKIE};
+    use std::collections::HashSet;
 
     #[test]
     fn test_cookie_replacement() {
+        let mut replacements = HashSet::new();
         let mut map = HeaderMap::default();
-        map.insert(SET_COOKIE, HeaderValue::from_static("world"));
-        map.append(SET_COOKIE, HeaderValue::from_static("earth"));
-        map.append(COOKIE, HeaderValue::from_static("earth"));
-
-        header_map_update(
-            &mut map,
+        map.append(
             SET_COOKIE,
-            &[CookieReplacement {
-                key: vec![0x42],
-                value: vec![0x44],
-                replacement: vec![0x45],
-            }],
-        )
+            HeaderValue::from_static("sid=secret; Path=/; HttpOnly"),
+        );
+        map.append(SET_COOKIE, HeaderValue::from_static("lang=en; HttpOnly"));
+        map.append(SET_COOKIE, HeaderValue::from_static("old=; Max-Age=0"));
+        map.append(
+            SET_COOKIE,
+            HeaderValue::from_static("XSRF-TOKEN=abc; Path=/"),
+        );
+        replace_set_cookies(&mut map, "/", false, &mut replacements);
+
+        let set_cookies: Vec<&str> = map
+            .get_all(SET_COOKIE)
+            .iter()
+            .map(|set_cookie| set_cookie.to_str().unwrap())
+            .collect();
+        assert_eq!(set_cookies.len(), 4);
+        assert!(set_cookies
+            .iter()
+            .all(|set_cookie| !set_cookie.contains("secret")));
+        assert!(
+            set_cookies[0].starts_with("sid=") && set_cookies[0].ends_with("; Path=/; HttpOnly")
+        );
+        assert_ne!(set_cookies[1], "lang=en; HttpOnly");
+        assert_eq!(set_cookies[2], "old=; Max-Age=0");
+        assert_eq!(set_cookies[3], "XSRF-TOKEN=abc; Path=/");
+        assert_eq!(replacements.len(), 2);
+
+        let sid_token = set_cookies[0].split(';').next().unwrap();
+        let lang_token = set_cookies[1].split(';').next().unwrap();
+        let cookie = format!("{}; theme=dark; {}", sid_token, lang_token);
+        let mut request = HeaderMap::default();
+        request.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        header_map_update(&mut request, COOKIE, &replacements);
+        assert_eq!(request[COOKIE], "sid=secret; theme=dark; lang=en");
+
+        // A forged token doesn't reach the upstream
+        request.insert(COOKIE, HeaderValue::from_static("theme=dark; sid=guess"));
+        header_map_update(&mut request, COOKIE, &replacements);
+        assert_eq!(request[COOKIE], "theme=dark");
+        request.insert(COOKIE, HeaderValue::from_static("sid=guess"));
+        header_map_update(&mut request, COOKIE, &replacements);
+        assert!(!request.contains_key(COOKIE));
+    }
+
+    fn hide(set_cookie: &'static str, all: bool, replacements: &mut CookieReplacements) -> String {
+        let mut map = HeaderMap::default();
+        map.insert(SET_COOKIE, HeaderValue::from_static(set_cookie));
+        replace_set_cookies(&mut map, "/admin/users", all, replacements);
+        let hidden = map[SET_COOKIE].to_str().unwrap();
+        hidden.split(';').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_cookie_replacement_per_path() {
+        let mut replacements = HashSet::new();
+        let root = hide("sid=root; Path=/; HttpOnly", false, &mut replacements);
+        let admin = hide("sid=admin; HttpOnly", false, &mut replacements);
+        assert_eq!(replacements.len(), 2);
+        // The cookie of /admin, the default path, is set again
+        let renewed = hide(
+            "sid=renewed; Path=/admin; HttpOnly",
+            false,
+            &mut replacements,
+        );
+        assert_eq!(replacements.len(), 2);
+
+        let mut request = HeaderMap::default();
+        let cookie = format!("{}; {}", renewed, root);
+        request.insert(COOKIE, HeaderValue::from_str(&cookie).unwrap());
+        header_map_update(&mut request, COOKIE, &replacements);
+        assert_eq!(request[COOKIE], "sid=renewed; sid=root");
+        request.insert(COOKIE, HeaderValue::from_str(&admin).unwrap());
+        header_map_update(&mut request, COOKIE, &replacements);
+        assert!(!request.contains_key(COOKIE));
+
+        assert_eq!(hide("theme=dark", false, &mut replacements), "theme=dark");
+        assert_ne!(hide("theme=dark", true, &mut replacements), "theme=dark");
     }
 }
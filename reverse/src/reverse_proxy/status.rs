@@ -1,5 +1,5 @@
 use hyper::{
-    header::{CONTENT_TYPE, WWW_AUTHENTICATE},
+    header::{CONTENT_TYPE, LOCATION, WWW_AUTHENTICATE},
     Body, Response, StatusCode,
 };
 
@@ -23,6 +23,31 @@ pub fn unauthorized_response() -> Result<Response<Body>, ProxyError> {
         .body(body)?)
 }
 
+pub fn login_required() -> Result<Response<Body>, ProxyError> {
+    let body = Body::from("Error 401 UNAUTHORIZED: Proxy login required\n");
+
+    Ok(Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body)?)
+}
+
+pub fn redirect(location: &str) -> Result<Response<Body>, ProxyError> {
+    Ok(Response::builder()
+        .status(StatusCode::FOUND)
+        .header(LOCATION, location)
+        .body(Body::empty())?)
+}
+
+pub fn forbidden() -> Result<Response<Body>, ProxyError> {
+    let body = Body::from("Error 403 FORBIDDEN: Access denied by the proxy policy\n");
+
+    Ok(Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body)?)
+}
+
 pub fn bad_request() -> Result<Response<Body>, ProxyError> {
     let body = Body::from("Error 400 BAD REQUEST: Proxy config file not properly completed");
 
@@ -32,11 +57,39 @@ pub fn bad_request() -> Result<Response<Body>, ProxyError> {
         .body(body)?)
 }
 
+pub fn payload_too_large(max_body_size: u64) -> Result<Response<Body>, ProxyError> {
+    let body = Body::from(format!(
+        "Error 413 PAYLOAD TOO LARGE: Request body exceeds the proxy limit of {} bytes",
+        max_body_size
+    ));
+
+    Ok(Response::builder()
+        .status(StatusCode::PAYLOAD_TOO_LARGE)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body)?)
+}
+
+pub fn service_unavailable() -> Result<Response<Body>, ProxyError> {
+    let body =
+        Body::from("Error 503 SERVICE UNAVAILABLE: Too many logins in progress, try again later\n");
+
+    Ok(Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(body)?)
+}
+
 pub fn bad_gateway(specification: &str) -> Result<Response<Body>, ProxyError> {
     let mut body = Body::from("Error 502 BAD GATEWAY");
 
     if specification == "config.toml" {
         body = Body::from("Error 502 BAD GATEWAY: Proxy config isn't recognized");
+    } else if specification == "credentials" {
+        body = Body::from("Error 502 BAD GATEWAY: Upstream credentials could not be acquired");
+    } else if specification == "login" {
+        body = Body::from("Error 502 BAD GATEWAY: Automated login to the upstream failed");
+    } else if specification == "identity provider" {
+        body = Body::from("Error 502 BAD GATEWAY: Identity provider unreachable");
     }
 
     Ok(Response::builder()
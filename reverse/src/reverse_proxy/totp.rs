@@ -0,0 +1,219 @@
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use sha1::Sha1;
+use sha2::{Sha256, Sha512};
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+pub const DEFAULT_FIELD: &str = "otp";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// RFC 6238 second factor of an upstream account.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Totp {
+    pub seed: Vec<u8>,
+    pub digits: u32,
+    pub period: u64,
+    pub algorithm: Algorithm,
+    /// Seconds to add to the local clock to match the upstream one. It is
+    /// a fixed offset: only the code of the step it lands on is sent, and
+    /// a clock drifting past the window the upstream accepts needs it
+    /// adjusted by hand.
+    pub skew: i64,
+    /// Login form field the code goes in
+    pub field: String,
+}
+
+// Last time step used per seed, upstreams refuse a code twice
+static USED_STEPS: Lazy<Mutex<HashMap<Vec<u8>, u64>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+impl Algorithm {
+    pub fn parse(algorithm: &str) -> Option<Self> {
+        match algorithm.to_ascii_uppercase().replace('-', "").as_str() {
+            "SHA1" => Some(Algorithm::Sha1),
+            "SHA256" => Some(Algorithm::Sha256),
+            "SHA512" => Some(Algorithm::Sha512),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes an RFC 4648 base32 seed, ignoring case, spaces and padding.
+pub fn decode_base32(seed: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut buffer: u64 = 0;
+    let mut bits = 0;
+
+    for c in seed.chars().filter(|c| !c.is_whitespace() && *c != '=') {
+        let value = match c.to_ascii_uppercase() {
+            c @ 'A'..='Z' => c as u64 - 'A' as u64,
+            c @ '2'..='7' => c as u64 - '2' as u64 + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+            buffer &= (1 << bits) - 1;
+        }
+    }
+    Some(bytes)
+}
+
+fn hmac<M: Mac + hmac::digest::KeyInit>(seed: &[u8], message: &[u8]) -> Vec<u8> {
+    // HMAC takes keys of any length
+    let mut mac = <M as hmac::digest::KeyInit>::new_from_slice(seed).expect("HMAC key");
+    mac.update(message);
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+impl Totp {
+    fn time_step(&self, unix: u64) -> u64 {
+        unix.saturating_add_signed(self.skew) / self.period.max(1)
+    }
+
+    fn code_for_step(&self, step: u64) -> String {
+        let message = step.to_be_bytes();
+        let digest = match self.algorithm {
+            Algorithm::Sha1 => hmac::<Hmac<Sha1>>(&self.seed, &message),
+            Algorithm::Sha256 => hmac::<Hmac<Sha256>>(&self.seed, &message),
+            Algorithm::Sha512 => hmac::<Hmac<Sha512>>(&self.seed, &message),
+        };
+        // RFC 4226 5.3 dynamic truncation
+        let offset = (digest[digest.len() - 1] & 0x0f) as usize;
+        let binary = u32::from_be_bytes([
+            digest[offset] & 0x7f,
+            digest[offset + 1],
+            digest[offset + 2],
+            digest[offset + 3],
+        ]);
+        let code = u64::from(binary) % 10u64.pow(self.digits);
+
+        format!("{:0width$}", code, width = self.digits as usize)
+    }
+
+    /// Code valid at `unix` seconds on the upstream clock.
+    #[cfg(test)]
+    pub fn code_at(&self, unix: u64) -> String {
+        self.code_for_step(self.time_step(unix))
+    }
+
+    /// Current code, waiting for the next period when this one's code was
+    /// already sent.
+    pub async fn fresh_code(&self) -> String {
+        loop {
+            let unix = unix_time();
+            let step = self.time_step(unix);
+            {
+                let mut used = USED_STEPS.lock().unwrap_or_else(PoisonError::into_inner);
+                let last = used.get(&self.seed).copied();
+                if last.is_none_or(|last| last < step) {
+                    used.insert(self.seed.clone(), step);
+                    return self.code_for_step(step);
+                }
+            }
+            let next = (step + 1) * self.period - unix.saturating_add_signed(self.skew);
+            println!("TOTP code already used, waiting {}s for the next one", next);
+            tokio::time::sleep(Duration::from_secs(next.max(1))).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn totp(seed: &[u8], algorithm: Algorithm) -> Totp {
+        Totp {
+            seed: seed.to_vec(),
+            digits: 8,
+            period: 30,
+            algorithm,
+            skew: 0,
+            field: DEFAULT_FIELD.to_string(),
+        }
+    }
+
+    // RFC 6238 appendix B
+    #[test]
+    fn test_rfc6238_vectors() {
+        let sha1 = totp(b"12345678901234567890", Algorithm::Sha1);
+        let sha256 = totp(b"12345678901234567890123456789012", Algorithm::Sha256);
+        let sha512 = totp(
+            b"1234567890123456789012345678901234567890123456789012345678901234",
+            Algorithm::Sha512,
+        );
+
+        for (time, expected) in [
+            (59, ["94287082", "46119246", "90693936"]),
+            (1111111109, ["07081804", "68084774", "25091201"]),
+            (1111111111, ["14050471", "67062674", "99943326"]),
+            (1234567890, ["89005924", "91819424", "93441116"]),
+            (2000000000, ["69279037", "90698825", "38618901"]),
+            (20000000000, ["65353130", "77737706", "47863826"]),
+        ] {
+            assert_eq!(sha1.code_at(time), expected[0], "SHA1 at {}", time);
+            assert_eq!(sha256.code_at(time), expected[1], "SHA256 at {}", time);
+            assert_eq!(sha512.code_at(time), expected[2], "SHA512 at {}", time);
+        }
+    }
+
+    #[test]
+    fn test_skew_and_digits() {
+        let mut sha1 = totp(b"12345678901234567890", Algorithm::Sha1);
+        sha1.digits = 6;
+        assert_eq!(sha1.code_at(59), "287082");
+
+        sha1.skew = 30;
+        assert_eq!(sha1.code_at(29), "287082");
+        sha1.skew = -1000;
+        assert_eq!(sha1.code_at(500), sha1.code_at(0));
+    }
+
+    #[test]
+    fn test_decode_base32() {
+        assert_eq!(
+            decode_base32("GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ"),
+            Some(b"12345678901234567890".to_vec())
+        );
+        assert_eq!(
+            decode_base32("mzxw 6ytb oi======"),
+            Some(b"foobar".to_vec())
+        );
+        assert_eq!(decode_base32("not base32!"), None);
+        assert_eq!(Algorithm::parse("sha-256"), Some(Algorithm::Sha256));
+    }
+
+    #[test]
+    fn test_fresh_code_is_never_reused() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let mut seed = totp(b"fresh-code-test-seed", Algorithm::Sha1);
+        // a long period keeps the test within one time step
+        seed.period = 1 << 40;
+
+        rt.block_on(async {
+            let first = seed.fresh_code().await;
+            assert_eq!(first, seed.code_at(unix_time()));
+
+            let second = tokio::time::timeout(Duration::from_millis(200), seed.fresh_code()).await;
+            assert!(second.is_err());
+        });
+    }
+}
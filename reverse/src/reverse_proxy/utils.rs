@@ -2,7 +2,8 @@ use std::collections::HashMap;
 use toml::Value;
 
 use http::Uri;
-use hyper::header::HeaderMap;
+use hyper::header::{HeaderMap, HeaderValue, LOCATION, REFERER};
+use url::Url;
 
 use super::config::setup_servers;
 
@@ -23,6 +24,22 @@ pub fn clean_url(mut uri: &str) -> String {
     uri.to_string()
 }
 
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 pub fn find_resources_refs(
     referer: &str,
     subpath: &str,
@@ -39,6 +56,49 @@ pub fn find_resources_refs(
     })
 }
 
+/// Whether the browser reached the proxy over HTTPS, directly or through a
+/// TLS terminating front-end.
+pub fn is_https(req_uri: &hyper::Uri, headers: &HeaderMap) -> bool {
+    let forwarded_proto = headers
+        .get("x-forwarded-proto")
+        .and_then(|proto| proto.to_str().ok());
+
+    req_uri.scheme_str() == Some("https")
+        || forwarded_proto.is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+/// Location of a redirection, resolved against the URL it answers
+pub fn absolute_location(url: &str, headers: &HeaderMap) -> Option<HeaderValue> {
+    let location = headers.get(LOCATION)?.to_str().ok()?;
+    let absolute = Url::parse(url).ok()?.join(location).ok()?;
+
+    HeaderValue::from_str(absolute.as_str()).ok()
+}
+
+/// What a request is routed by: its referer when it has one, else its path.
+pub fn route_ref(path: &str, headers: &HeaderMap) -> String {
+    headers
+        .get(REFERER)
+        .and_then(|referer| referer.to_str().ok())
+        .unwrap_or(path)
+        .to_string()
+}
+
+/// Proxy path a request is routed as: its own when it names a route, else
+/// the referer's route followed by the request path, as `determine_target`
+/// resolves it.
+pub fn routed_path(path_ref: &str, req_uri: &hyper::Uri, config: Value) -> Option<String> {
+    let servers = setup_servers(config)?;
+    if servers.contains_key(req_uri.path()) {
+        return Some(req_uri.path().to_string());
+    }
+    let ref_uri = path_ref.parse::<Uri>().ok()?;
+
+    servers
+        .contains_key(ref_uri.path())
+        .then(|| format!("{}{}", clean_url(ref_uri.path()), req_uri.path()))
+}
+
 pub fn determine_target(path_ref: &str, req_uri: &hyper::Uri, config: Value) -> Result<String, ()> {
     let servers = match setup_servers(config) {
         Some(res) => res,
@@ -72,11 +132,3 @@ pub fn _strncmp(s1: &str, s2: &str, n: usize) -> bool {
 
     true
 }
-
-pub fn common_prefix(s1: &str, s2: &str) -> String {
-    s1.chars()
-        .zip(s2.chars())
-        .take_while(|(c1, c2)| c1 == c2)
-        .map(|(c, _)| c)
-        .collect()
-}